@@ -10,7 +10,7 @@ use taffy::Taffy;
 use tao::event::MouseButton;
 
 use dioxus::html::{
-    events::{FocusData, KeyboardData, MouseData, WheelData},
+    events::{DragData, FocusData, KeyboardData, MouseData, WheelData},
     geometry::{euclid::Point2D, ClientPoint, Coordinates, ElementPoint, PagePoint, ScreenPoint},
     input_data::{self, keyboard_types::Modifiers, MouseButtonSet},
 };
@@ -77,6 +77,8 @@ struct EventState {
     modifier_state: Modifiers,
     cursor_state: CursorState,
     focus_state: FocusState,
+    /// Set while files are dragged over the window
+    dragging_files: bool,
 }
 
 impl EventState {
@@ -98,6 +100,7 @@ pub enum EventData {
     Keyboard(KeyboardData),
     Focus(FocusData),
     Wheel(WheelData),
+    Drag(DragData),
 }
 
 impl EventData {
@@ -107,6 +110,7 @@ impl EventData {
             EventData::Keyboard(data) => Rc::new(data),
             EventData::Focus(data) => Rc::new(data),
             EventData::Wheel(data) => Rc::new(data),
+            EventData::Drag(data) => Rc::new(data),
         }
     }
 }
@@ -124,6 +128,7 @@ impl BlitzEventHandler {
                 focus_state,
                 modifier_state: Default::default(),
                 cursor_state: Default::default(),
+                dragging_files: false,
             },
             queued_events: Default::default(),
         }
@@ -146,9 +151,29 @@ impl BlitzEventHandler {
                     tao::event::WindowEvent::Moved(_) => (),
                     tao::event::WindowEvent::CloseRequested => (),
                     tao::event::WindowEvent::Destroyed => (),
-                    tao::event::WindowEvent::DroppedFile(_) => (),
-                    tao::event::WindowEvent::HoveredFile(_) => (),
-                    tao::event::WindowEvent::HoveredFileCancelled => (),
+                    // the paths are left to the application, the events only tell the components
+                    // that a drag is going on. Every file sends its own event, so only the first
+                    // one is passed on
+                    tao::event::WindowEvent::HoveredFile(_) => {
+                        if !self.state.dragging_files {
+                            self.state.dragging_files = true;
+                            self.queue_drag_event("dragenter", rdom);
+                        }
+                    }
+                    tao::event::WindowEvent::HoveredFileCancelled => {
+                        if self.state.dragging_files {
+                            self.state.dragging_files = false;
+                            self.queue_drag_event("dragleave", rdom);
+                        }
+                    }
+                    // every dropped file gets its own event, the hover ends with the first one
+                    tao::event::WindowEvent::DroppedFile(_) => {
+                        if self.state.dragging_files {
+                            self.state.dragging_files = false;
+                            self.queue_drag_event("dragleave", rdom);
+                        }
+                        self.queue_drag_event("drop", rdom);
+                    }
                     tao::event::WindowEvent::ReceivedImeText(_) => (),
                     tao::event::WindowEvent::Focused(_) => (),
                     tao::event::WindowEvent::KeyboardInput {
@@ -471,6 +496,27 @@ impl BlitzEventHandler {
         }
     }
 
+    /// The cursor doesn't move while dragging on every platform, so without a hovered element
+    /// the event goes to the root element
+    fn queue_drag_event(&mut self, name: &'static str, rdom: &RealDom) {
+        // files can be dragged over the window before anything is rendered
+        let Some(element) = self.state.cursor_state.hovered.or_else(|| {
+            rdom.get(rdom.root_id())
+                .and_then(|root| root.child_ids().first().copied())
+        }) else {
+            return;
+        };
+
+        self.queued_events.push(DomEvent {
+            element,
+            name,
+            data: Arc::new(EventData::Drag(DragData {
+                mouse: self.state.cursor_state.get_event_mouse_data(),
+            })),
+            bubbles: true,
+        });
+    }
+
     pub fn drain_events(&mut self) -> Vec<DomEvent> {
         let mut events = Vec::new();
         std::mem::swap(&mut self.queued_events, &mut events);
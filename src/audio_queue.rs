@@ -0,0 +1,258 @@
+use std::time::Duration;
+
+use cpal::{Sample, SampleFormat};
+use ringbuf::{HeapConsumer, HeapProducer, HeapRb};
+
+/// Blocks that fit in the queue, a decoded frame is rarely shorter than a few milliseconds
+const MAX_BLOCKS: usize = 8192;
+
+/// Samples with consecutive timestamps, only the first one is stored
+#[derive(Debug, Clone, Copy)]
+struct AudioBlock {
+    /// Pts in nanoseconds of the first sample frame
+    pts: i64,
+    /// Interleaved samples left in the block
+    len: usize,
+    /// Nanoseconds of media time covered by a sample frame, this changes with the playback rate
+    frame_duration: f64,
+}
+
+/// A queue of interleaved samples with their timestamps kept on the side, that holds up to
+/// twice `duration` so the decoder can stop at `duration` and still push a whole frame. The
+/// samples are stored as the bytes of `sample_format`, the format the output plays
+pub fn audio_queue(
+    channels: usize,
+    sample_rate: u32,
+    sample_format: SampleFormat,
+    duration: Duration,
+) -> (AudioProducer, AudioConsumer) {
+    let sample_size = sample_format.sample_size();
+    let capacity =
+        (duration.as_secs_f64() * 2.0 * sample_rate as f64) as usize * channels * sample_size;
+    let (samples, sample_consumer) = HeapRb::<u8>::new(capacity).split();
+    let (blocks, block_consumer) = HeapRb::<AudioBlock>::new(MAX_BLOCKS).split();
+
+    (
+        AudioProducer {
+            samples,
+            blocks,
+            channels,
+            sample_rate,
+            sample_size,
+        },
+        AudioConsumer {
+            samples: sample_consumer,
+            blocks: block_consumer,
+            current: None,
+            channels,
+            sample_rate,
+            sample_format,
+        },
+    )
+}
+
+pub struct AudioProducer {
+    samples: HeapProducer<u8>,
+    blocks: HeapProducer<AudioBlock>,
+    channels: usize,
+    sample_rate: u32,
+    /// Bytes per sample
+    sample_size: usize,
+}
+
+impl AudioProducer {
+    /// Queues the bytes of interleaved samples of which the first plays at `pts`, returns false
+    /// when they don't fit
+    pub fn push(&mut self, pts: i64, frame_duration: f64, samples: &[u8]) -> bool {
+        if samples.is_empty() {
+            return true;
+        }
+        if self.blocks.is_full() || self.samples.free_len() < samples.len() {
+            return false;
+        }
+
+        // the block goes first, the consumer only looks for one once it popped its samples
+        self.blocks
+            .push(AudioBlock {
+                pts,
+                len: samples.len() / self.sample_size,
+                frame_duration,
+            })
+            .ok();
+        self.samples.push_slice(samples);
+
+        true
+    }
+
+    /// How long it takes to play what is queued
+    pub fn queued_duration(&self) -> Duration {
+        let frames = self.samples.len() / self.sample_size / self.channels;
+        Duration::from_secs_f64(frames as f64 / self.sample_rate as f64)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Whether no more blocks fit, even though there might be room for their samples
+    pub fn is_full(&self) -> bool {
+        self.blocks.is_full()
+    }
+}
+
+/// The end of the queue the audio callback reads from, it never allocates
+pub struct AudioConsumer {
+    samples: HeapConsumer<u8>,
+    blocks: HeapConsumer<AudioBlock>,
+    /// The block the next sample belongs to
+    current: Option<AudioBlock>,
+    channels: usize,
+    sample_rate: u32,
+    sample_format: SampleFormat,
+}
+
+impl AudioConsumer {
+    pub fn sample_format(&self) -> SampleFormat {
+        self.sample_format
+    }
+
+    /// Number of samples waiting to be played
+    pub fn queued(&self) -> usize {
+        self.samples.len() / self.sample_format.sample_size()
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+        self.blocks.clear();
+        self.current = None;
+    }
+
+    /// Fills the start of `data` with queued samples, returns how many and the pts of the sample
+    /// that is heard now when the first one is heard `latency` from now. `T` has to be the
+    /// type of the queue's sample format
+    pub fn pop<T: Sample>(&mut self, data: &mut [T], latency: Duration) -> (usize, Option<i64>) {
+        let sample_size = self.sample_format.sample_size();
+        debug_assert_eq!(std::mem::size_of::<T>(), sample_size);
+        // every bit pattern is a valid sample, so the bytes can be written in place
+        let bytes = unsafe {
+            std::slice::from_raw_parts_mut(
+                data.as_mut_ptr() as *mut u8,
+                std::mem::size_of_val(data),
+            )
+        };
+        let popped = self.samples.pop_slice(bytes) / sample_size;
+
+        let mut clock = None;
+        let mut remaining = popped;
+        while remaining > 0 {
+            if self.current.map_or(true, |block| block.len == 0) {
+                self.current = self.blocks.pop();
+            }
+            let Some(block) = self.current.as_mut() else {
+                break;
+            };
+
+            if clock.is_none() {
+                let latency_frames = latency.as_secs_f64() * self.sample_rate as f64;
+                clock = Some(block.pts - (latency_frames * block.frame_duration) as i64);
+            }
+
+            let taken = remaining.min(block.len);
+            block.len -= taken;
+            block.pts += ((taken / self.channels) as f64 * block.frame_duration) as i64;
+            remaining -= taken;
+        }
+
+        (popped, clock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Nanoseconds of a sample frame at the rate of `queue`
+    const FRAME_DURATION: f64 = 1_000_000.0;
+
+    /// Room for 40 stereo float samples
+    fn queue() -> (AudioProducer, AudioConsumer) {
+        audio_queue(2, 1000, SampleFormat::F32, Duration::from_millis(10))
+    }
+
+    fn samples(values: std::ops::Range<usize>) -> Vec<u8> {
+        values
+            .flat_map(|value| (value as f32).to_ne_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_the_pts_of_blocks_that_wrap_around() {
+        let (mut producer, mut consumer) = queue();
+        let latency = Duration::from_millis(2);
+
+        for block in 0..10 {
+            let pts = block as i64 * 8_000_000;
+            assert!(producer.push(pts, FRAME_DURATION, &samples(block * 16..block * 16 + 16)));
+            assert_eq!(producer.queued_duration(), Duration::from_millis(8));
+
+            let mut data = [0.0f32; 16];
+            // the sample heard now is two frames before the first one popped
+            assert_eq!(
+                consumer.pop(&mut data, latency),
+                (16, Some(pts - 2_000_000))
+            );
+            let expected: Vec<f32> = (block * 16..block * 16 + 16).map(|v| v as f32).collect();
+            assert_eq!(data.to_vec(), expected);
+        }
+        assert!(producer.is_empty());
+    }
+
+    #[test]
+    fn continues_a_block_that_was_partially_read() {
+        let (mut producer, mut consumer) = queue();
+        assert!(producer.push(0, FRAME_DURATION, &samples(0..16)));
+        assert!(producer.push(100_000_000, FRAME_DURATION, &samples(16..24)));
+
+        let mut data = [0.0f32; 6];
+        assert_eq!(consumer.pop(&mut data, Duration::ZERO), (6, Some(0)));
+        assert_eq!(
+            consumer.pop(&mut data, Duration::ZERO),
+            (6, Some(3_000_000))
+        );
+        assert_eq!(data, [6.0, 7.0, 8.0, 9.0, 10.0, 11.0]);
+
+        // the rest of the first block and the start of the second
+        let mut data = [0.0f32; 8];
+        assert_eq!(
+            consumer.pop(&mut data, Duration::ZERO),
+            (8, Some(6_000_000))
+        );
+        assert_eq!(
+            consumer.pop(&mut data, Duration::ZERO),
+            (4, Some(102_000_000))
+        );
+        assert_eq!(data[..4], [20.0, 21.0, 22.0, 23.0]);
+        assert_eq!(consumer.pop(&mut data, Duration::ZERO), (0, None));
+    }
+
+    #[test]
+    fn drops_everything_on_clear() {
+        let (mut producer, mut consumer) = queue();
+        assert!(producer.push(0, FRAME_DURATION, &samples(0..16)));
+        let mut data = [0.0f32; 4];
+        consumer.pop(&mut data, Duration::ZERO);
+
+        consumer.clear();
+        assert_eq!(consumer.queued(), 0);
+        assert!(producer.is_empty());
+        assert_eq!(consumer.pop(&mut data, Duration::ZERO), (0, None));
+
+        // what comes after a flush doesn't pick up the block that was being read
+        assert!(producer.push(50_000_000, FRAME_DURATION, &samples(100..104)));
+        assert_eq!(
+            consumer.pop(&mut data, Duration::ZERO),
+            (4, Some(50_000_000))
+        );
+        assert_eq!(data, [100.0, 101.0, 102.0, 103.0]);
+    }
+}
@@ -0,0 +1,621 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{
+    ChannelCount, FromSample, Sample, SampleFormat, SampleRate, SizedSample, Stream, StreamConfig,
+};
+
+use crate::decoder::{AudioRenderer, MediaDecoderError, Pacing};
+
+/// Where the decoded audio goes
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioOutput {
+    /// An output device by name, `None` is the default device of the system
+    Cpal(Option<String>),
+    /// Throws the samples away, for machines without a sound card
+    Null,
+    /// Writes the samples to a 32-bit float WAV file
+    Wav(PathBuf),
+}
+
+impl Default for AudioOutput {
+    fn default() -> Self {
+        AudioOutput::Cpal(None)
+    }
+}
+
+/// Names of the output devices, to pass to `AudioOutput::Cpal`
+pub fn list_output_devices() -> Vec<String> {
+    match cpal::default_host().output_devices() {
+        Ok(devices) => devices.filter_map(|device| device.name().ok()).collect(),
+        Err(err) => {
+            log::error!("Could not list the audio devices: {}", err);
+            vec![]
+        }
+    }
+}
+
+fn find_output_device(name: Option<&str>) -> Result<cpal::Device, MediaDecoderError> {
+    let host = cpal::default_host();
+    let device = match name {
+        Some(name) => host
+            .output_devices()
+            .map_err(|err| MediaDecoderError::AudioOutput(err.to_string()))?
+            .find(|device| device.name().is_ok_and(|device_name| device_name == name)),
+        None => host.default_output_device(),
+    };
+
+    device.ok_or_else(|| {
+        MediaDecoderError::AudioOutput(match name {
+            Some(name) => format!("no output device named {}", name),
+            None => "no output device available".to_string(),
+        })
+    })
+}
+
+/// What the decoder resamples the audio to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputFormat {
+    pub channels: ChannelCount,
+    pub sample_rate: u32,
+    /// Format of the samples in the audio queue, float or 16-bit signed
+    pub sample_format: SampleFormat,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        Self {
+            channels: 2,
+            sample_rate: 48000,
+            sample_format: SampleFormat::F32,
+        }
+    }
+}
+
+/// Sample formats a sound card can be fed, float is preferred so nothing is converted
+const SAMPLE_FORMATS: [SampleFormat; 3] = [SampleFormat::F32, SampleFormat::I16, SampleFormat::U16];
+
+/// Whether a device taking `device_format` plays a queue of `queue_format`. FFmpeg has no
+/// unsigned 16-bit format, so those devices get float that is converted in the callback
+fn can_play(device_format: SampleFormat, queue_format: SampleFormat) -> bool {
+    device_format == queue_format
+        || (queue_format == SampleFormat::F32 && SAMPLE_FORMATS.contains(&device_format))
+}
+
+impl AudioOutput {
+    /// The format the device plays without converting, the sinks that write samples
+    /// themselves take the default
+    pub fn preferred_format(&self) -> Result<OutputFormat, MediaDecoderError> {
+        match self {
+            AudioOutput::Cpal(name) => {
+                let device = find_output_device(name.as_deref())?;
+                let config = device
+                    .default_output_config()
+                    .map_err(|err| MediaDecoderError::AudioOutput(err.to_string()))?;
+
+                Ok(OutputFormat {
+                    channels: config.channels(),
+                    sample_rate: config.sample_rate().0,
+                    sample_format: match config.sample_format() {
+                        SampleFormat::I16 => SampleFormat::I16,
+                        _ => SampleFormat::F32,
+                    },
+                })
+            }
+            AudioOutput::Null | AudioOutput::Wav(_) => Ok(OutputFormat::default()),
+        }
+    }
+
+    /// A sound card always plays in real time, the other sinks follow `pacing`
+    pub fn create_sink(
+        &self,
+        format: OutputFormat,
+        pacing: Pacing,
+    ) -> Result<Box<dyn AudioSink>, MediaDecoderError> {
+        Ok(match self {
+            AudioOutput::Cpal(name) => Box::new(CpalSink::new(name.as_deref(), format)?),
+            AudioOutput::Null => Box::new(NullSink::new(format, pacing)),
+            AudioOutput::Wav(path) => Box::new(WavSink::new(path, format, pacing)?),
+        })
+    }
+}
+
+/// Plays the samples of an `AudioRenderer`, which moves the audio clock as they're pulled.
+/// Playback stops when the sink is dropped
+pub trait AudioSink {
+    fn start(&mut self, renderer: AudioRenderer) -> Result<(), MediaDecoderError>;
+
+    /// Stops playing and hands back the renderer, so another sink can go on where this one
+    /// stopped
+    fn stop(self: Box<Self>) -> Option<AudioRenderer>;
+
+    /// The device went away and nothing is played anymore
+    fn is_lost(&self) -> bool {
+        false
+    }
+}
+
+pub struct CpalSink {
+    device: cpal::Device,
+    config: StreamConfig,
+    sample_format: SampleFormat,
+    stream: Option<Stream>,
+    /// Shared with the callback so `stop` gets it back
+    renderer: Arc<Mutex<Option<AudioRenderer>>>,
+    lost: Arc<AtomicBool>,
+}
+
+impl CpalSink {
+    /// Opens the device called `name`, or the default one
+    pub fn new(name: Option<&str>, format: OutputFormat) -> Result<Self, MediaDecoderError> {
+        let channels = format.channels;
+        let sample_rate = SampleRate(format.sample_rate);
+        let device = find_output_device(name)?;
+
+        // the default config is what the decoder was set up for, unless the device changed since
+        let default_config = device.default_output_config().ok().filter(|config| {
+            config.channels() == channels
+                && config.sample_rate() == sample_rate
+                && can_play(config.sample_format(), format.sample_format)
+        });
+        let supported_config = match default_config {
+            Some(config) => config,
+            None => device
+                .supported_output_configs()
+                .map_err(|err| MediaDecoderError::AudioOutput(err.to_string()))?
+                .filter(|config| {
+                    config.channels() == channels
+                        && sample_rate >= config.min_sample_rate()
+                        && sample_rate <= config.max_sample_rate()
+                })
+                .filter(|config| can_play(config.sample_format(), format.sample_format))
+                .filter_map(|config| {
+                    // the format of the queue itself needs no conversion
+                    let preference = if config.sample_format() == format.sample_format {
+                        0
+                    } else {
+                        1 + SAMPLE_FORMATS
+                            .iter()
+                            .position(|format| *format == config.sample_format())?
+                    };
+                    Some((preference, config))
+                })
+                .min_by_key(|(preference, _)| *preference)
+                .ok_or_else(|| {
+                    MediaDecoderError::AudioOutput(format!(
+                        "no supported config for {} channels at {} Hz in {}",
+                        channels, sample_rate.0, format.sample_format
+                    ))
+                })?
+                .1
+                .with_sample_rate(sample_rate),
+        };
+        log::debug!("Audio output config {:?}", supported_config);
+
+        Ok(Self {
+            device,
+            sample_format: supported_config.sample_format(),
+            config: supported_config.into(),
+            stream: None,
+            renderer: Arc::new(Mutex::new(None)),
+            lost: Arc::new(AtomicBool::new(false)),
+        })
+    }
+}
+
+impl AudioSink for CpalSink {
+    fn start(&mut self, renderer: AudioRenderer) -> Result<(), MediaDecoderError> {
+        let queue_format = renderer.sample_format();
+        *self.renderer.lock().unwrap() = Some(renderer);
+
+        let error_callback = {
+            let lost = self.lost.clone();
+            move |err| {
+                log::error!("Audio output error: {}", err);
+                if let cpal::StreamError::DeviceNotAvailable = err {
+                    lost.store(true, Ordering::Release);
+                }
+            }
+        };
+        let stream = match (self.sample_format, queue_format) {
+            (SampleFormat::F32, SampleFormat::F32) => build_stream::<f32>(
+                &self.device,
+                &self.config,
+                self.renderer.clone(),
+                error_callback,
+            ),
+            (SampleFormat::I16, SampleFormat::I16) => build_stream::<i16>(
+                &self.device,
+                &self.config,
+                self.renderer.clone(),
+                error_callback,
+            ),
+            (SampleFormat::I16, SampleFormat::F32) => build_converting_stream::<i16>(
+                &self.device,
+                &self.config,
+                self.renderer.clone(),
+                error_callback,
+            ),
+            (SampleFormat::U16, SampleFormat::F32) => build_converting_stream::<u16>(
+                &self.device,
+                &self.config,
+                self.renderer.clone(),
+                error_callback,
+            ),
+            (device_format, queue_format) => {
+                return Err(MediaDecoderError::AudioOutput(format!(
+                    "cannot play {} samples on a {} device",
+                    queue_format, device_format
+                )))
+            }
+        }
+        .map_err(|err| MediaDecoderError::AudioOutput(err.to_string()))?;
+        stream
+            .play()
+            .map_err(|err| MediaDecoderError::AudioOutput(err.to_string()))?;
+        self.stream = Some(stream);
+
+        Ok(())
+    }
+
+    fn stop(mut self: Box<Self>) -> Option<AudioRenderer> {
+        // the callback isn't called anymore once the stream is gone
+        self.stream = None;
+        self.renderer.lock().unwrap().take()
+    }
+
+    fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::Acquire)
+    }
+}
+
+/// Renders into `data` unless the sink is handing the renderer over, which is the only time
+/// the lock is taken outside of the callback. `latency` is how long until `data` is heard
+fn render_shared<T: Sample<Float = f32>>(
+    renderer: &Mutex<Option<AudioRenderer>>,
+    data: &mut [T],
+    latency: Duration,
+) {
+    match renderer.try_lock().as_deref_mut() {
+        Ok(Some(renderer)) => {
+            renderer.render(data, latency);
+        }
+        _ => data.fill(T::EQUILIBRIUM),
+    }
+}
+
+/// How long it takes until the first sample of the buffer is heard
+fn get_latency(info: &cpal::OutputCallbackInfo) -> Duration {
+    let timestamp = info.timestamp();
+    timestamp
+        .playback
+        .duration_since(&timestamp.callback)
+        .unwrap_or_default()
+}
+
+/// A stream for devices that take the samples of the queue as they are
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    renderer: Arc<Mutex<Option<AudioRenderer>>>,
+    error_callback: impl FnMut(cpal::StreamError) + Send + 'static,
+) -> Result<Stream, cpal::BuildStreamError>
+where
+    T: SizedSample<Float = f32>,
+{
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], info: &cpal::OutputCallbackInfo| {
+            render_shared(&renderer, data, get_latency(info));
+        },
+        error_callback,
+        None,
+    )
+}
+
+/// Samples converted at a time, the callback renders them on the stack so it never allocates
+const CONVERSION_CHUNK_LEN: usize = 4096;
+
+/// A stream for integer devices playing a float queue, when FFmpeg can't produce their format
+fn build_converting_stream<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    renderer: Arc<Mutex<Option<AudioRenderer>>>,
+    error_callback: impl FnMut(cpal::StreamError) + Send + 'static,
+) -> Result<Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+    let sample_rate = config.sample_rate.0 as f64;
+    // whole sample frames, so the gain ramp and the clock stay on frame boundaries
+    let chunk_len = (CONVERSION_CHUNK_LEN / channels).max(1) * channels;
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], info: &cpal::OutputCallbackInfo| {
+            let latency = get_latency(info);
+            let mut buffer = [0.0; CONVERSION_CHUNK_LEN];
+            for (index, chunk) in data.chunks_mut(chunk_len).enumerate() {
+                let buffer = &mut buffer[..chunk.len()];
+                // a chunk is heard after the ones before it
+                let frames_before = (index * chunk_len / channels) as f64;
+                render_shared(
+                    &renderer,
+                    buffer,
+                    latency + Duration::from_secs_f64(frames_before / sample_rate),
+                );
+                for (output, sample) in chunk.iter_mut().zip(buffer.iter()) {
+                    *output = T::from_sample(*sample);
+                }
+            }
+        },
+        error_callback,
+        None,
+    )
+}
+
+/// Pulls the samples in real time so the clock runs like it does with a sound card, or as soon
+/// as they're decoded
+pub struct NullSink {
+    channels: ChannelCount,
+    sample_rate: u32,
+    pacing: Pacing,
+    output: Option<ClockedOutput>,
+}
+
+impl NullSink {
+    pub fn new(format: OutputFormat, pacing: Pacing) -> Self {
+        Self {
+            channels: format.channels,
+            sample_rate: format.sample_rate,
+            pacing,
+            output: None,
+        }
+    }
+}
+
+impl AudioSink for NullSink {
+    fn start(&mut self, renderer: AudioRenderer) -> Result<(), MediaDecoderError> {
+        // it takes over from a sound card when that goes away, so it plays any queue
+        self.output = Some(match renderer.sample_format() {
+            SampleFormat::I16 => ClockedOutput::spawn::<i16>(
+                self.channels,
+                self.sample_rate,
+                self.pacing,
+                renderer,
+                |_| (),
+            ),
+            _ => ClockedOutput::spawn::<f32>(
+                self.channels,
+                self.sample_rate,
+                self.pacing,
+                renderer,
+                |_| (),
+            ),
+        });
+
+        Ok(())
+    }
+
+    fn stop(mut self: Box<Self>) -> Option<AudioRenderer> {
+        self.output.take()?.stop()
+    }
+}
+
+/// Pulls the samples like `NullSink` and writes the ones that were decoded to a file, silence
+/// while paused or buffering is left out
+pub struct WavSink {
+    channels: ChannelCount,
+    sample_rate: u32,
+    pacing: Pacing,
+    writer: Option<WavWriter>,
+    output: Option<ClockedOutput>,
+}
+
+impl WavSink {
+    pub fn new(
+        path: &Path,
+        format: OutputFormat,
+        pacing: Pacing,
+    ) -> Result<Self, MediaDecoderError> {
+        if format.sample_format != SampleFormat::F32 {
+            return Err(MediaDecoderError::AudioOutput(format!(
+                "cannot write {} samples to a float WAV file",
+                format.sample_format
+            )));
+        }
+        let OutputFormat {
+            channels,
+            sample_rate,
+            ..
+        } = format;
+        let writer = WavWriter::create(path, channels, sample_rate).map_err(|err| {
+            MediaDecoderError::AudioOutput(format!("could not create {}: {}", path.display(), err))
+        })?;
+        log::info!("Writing audio to {}", path.display());
+
+        Ok(Self {
+            channels,
+            sample_rate,
+            pacing,
+            writer: Some(writer),
+            output: None,
+        })
+    }
+}
+
+impl AudioSink for WavSink {
+    fn start(&mut self, renderer: AudioRenderer) -> Result<(), MediaDecoderError> {
+        let Some(mut writer) = self.writer.take() else {
+            return Err(MediaDecoderError::AudioOutput(
+                "the WAV sink was started twice".to_string(),
+            ));
+        };
+
+        let mut failed = false;
+        self.output = Some(ClockedOutput::spawn::<f32>(
+            self.channels,
+            self.sample_rate,
+            self.pacing,
+            renderer,
+            move |samples| {
+                if failed {
+                    return;
+                }
+                if let Err(err) = writer.write_samples(samples) {
+                    log::error!("Failed to write audio: {}", err);
+                    failed = true;
+                }
+            },
+        ));
+
+        Ok(())
+    }
+
+    /// The WAV file is finished, samples rendered after this aren't written
+    fn stop(mut self: Box<Self>) -> Option<AudioRenderer> {
+        self.output.take()?.stop()
+    }
+}
+
+/// A thread that renders as many samples as a sound card would have played since it started,
+/// or everything that is queued when playing as fast as possible
+struct ClockedOutput {
+    running: Arc<AtomicBool>,
+    /// Gives the renderer back when it exits
+    thread: Option<JoinHandle<AudioRenderer>>,
+}
+
+impl ClockedOutput {
+    /// `write` gets the samples that came from the decoder, it's dropped when the output stops.
+    /// `T` is the type of the renderer's sample format
+    fn spawn<T: Sample<Float = f32> + Send + 'static>(
+        channels: ChannelCount,
+        sample_rate: u32,
+        pacing: Pacing,
+        mut renderer: AudioRenderer,
+        mut write: impl FnMut(&[T]) + Send + 'static,
+    ) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = std::thread::spawn({
+            let running = running.clone();
+            move || {
+                let started = Instant::now();
+                let mut rendered_frames = 0u64;
+                let mut data = vec![];
+
+                while running.load(Ordering::Acquire) {
+                    let frames = match pacing {
+                        Pacing::Realtime => {
+                            let due_frames =
+                                (started.elapsed().as_secs_f64() * sample_rate as f64) as u64;
+                            let frames = (due_frames - rendered_frames) as usize;
+                            rendered_frames = due_frames;
+                            frames
+                        }
+                        Pacing::AsFastAsPossible => renderer.queued() / channels as usize,
+                    };
+                    if frames == 0 {
+                        std::thread::sleep(Duration::from_millis(10));
+                        continue;
+                    }
+
+                    data.resize(frames * channels as usize, T::EQUILIBRIUM);
+                    // the samples count as played once they're rendered
+                    let decoded = renderer.render(&mut data, Duration::ZERO);
+                    write(&data[..decoded]);
+
+                    // nothing is played while paused
+                    if pacing == Pacing::Realtime || decoded == 0 {
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+                }
+
+                renderer
+            }
+        });
+
+        Self {
+            running,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl ClockedOutput {
+    fn stop(mut self) -> Option<AudioRenderer> {
+        self.running.store(false, Ordering::Release);
+        self.thread.take()?.join().ok()
+    }
+}
+
+impl Drop for ClockedOutput {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Writes a WAV file with 32-bit float samples, the sizes in the header are filled in on drop
+struct WavWriter {
+    file: BufWriter<File>,
+    data_len: u32,
+}
+
+impl WavWriter {
+    fn create(path: &Path, channels: ChannelCount, sample_rate: u32) -> std::io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let block_align = channels * 4;
+
+        file.write_all(b"RIFF")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        // WAVE_FORMAT_IEEE_FLOAT
+        file.write_all(&3u16.to_le_bytes())?;
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&32u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self { file, data_len: 0 })
+    }
+
+    fn write_samples(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len = self.data_len.saturating_add((samples.len() * 4) as u32);
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file
+            .write_all(&self.data_len.saturating_add(36).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_len.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish() {
+            log::error!("Failed to finish the WAV file: {}", err);
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+/// Frames later than this are dropped once the frame after them is due
+pub const LATE_FRAME_THRESHOLD: i64 = 20_000_000;
+/// An audio clock further off than this from where we expect it is taken as is, anything closer
+/// is smoothed
+const RESYNC_THRESHOLD: i64 = 100_000_000;
+/// The smoothed clock doesn't run ahead of the last audio update by more than this, in case the
+/// output stalls
+const MAX_EXTRAPOLATION: i64 = 100_000_000;
+/// How much of the error between the smoothed and the audio clock is corrected on an update
+const PHASE_CORRECTION: f64 = 0.125;
+/// How much the speed changes per second of error, small so jitter in the audio callback doesn't
+/// make it wobble
+const DRIFT_CORRECTION: f64 = 0.01;
+/// The sound card and the system clock are never this far apart
+const MAX_DRIFT: f64 = 0.005;
+
+/// Follows the audio clock between the updates of the audio callback and evens out the drift
+/// between the sound card and the system clock
+pub struct ClockSmoother {
+    /// Smoothed clock and the system time it was at, both in nanoseconds
+    anchor: Option<(i64, i64)>,
+    /// System time of the last audio clock update we saw
+    last_update: i64,
+    /// How fast the audio clock runs compared to the system clock, on top of the playback rate
+    speed: f64,
+    /// The smoothed clock never goes back, apart from a resync
+    last: i64,
+}
+
+impl Default for ClockSmoother {
+    fn default() -> Self {
+        Self {
+            anchor: None,
+            last_update: 0,
+            speed: 1.0,
+            last: 0,
+        }
+    }
+}
+
+impl ClockSmoother {
+    /// Starts over from the next update, after a seek or pause. The drift is kept, it's a property
+    /// of the hardware
+    pub fn reset(&mut self) {
+        self.anchor = None;
+        self.last = 0;
+    }
+
+    /// The clock at system time `now`, `audio_clock` is what the audio callback stored at system
+    /// time `updated`
+    pub fn tick(&mut self, audio_clock: i64, updated: i64, now: i64, rate: f32) -> i64 {
+        let rate = rate as f64 * self.speed;
+        let (pts, at) = *self.anchor.get_or_insert((audio_clock, updated));
+
+        if updated != self.last_update {
+            self.last_update = updated;
+
+            let expected = pts + ((updated - at) as f64 * rate) as i64;
+            let error = audio_clock - expected;
+            if error.abs() > RESYNC_THRESHOLD {
+                log::debug!("Audio clock is {} ms off, resyncing", error / 1_000_000);
+                self.anchor = Some((audio_clock, updated));
+                self.last = 0;
+            } else {
+                self.anchor = Some((expected + (error as f64 * PHASE_CORRECTION) as i64, updated));
+                self.speed = (self.speed + error as f64 / 1_000_000_000.0 * DRIFT_CORRECTION)
+                    .clamp(1.0 - MAX_DRIFT, 1.0 + MAX_DRIFT);
+            }
+        }
+
+        let (pts, at) = self.anchor.unwrap();
+        let clock = (pts + ((now - at) as f64 * rate) as i64)
+            .min(audio_clock + MAX_EXTRAPOLATION)
+            .max(self.last);
+        self.last = clock;
+
+        clock
+    }
+}
+
+/// Counters of the presentation thread
+#[derive(Default)]
+pub struct SyncCounters {
+    dropped: AtomicU64,
+    duplicated: AtomicU64,
+    av_offset: AtomicI64,
+}
+
+/// How well video keeps up with the clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SyncStats {
+    /// Frames that were thrown away because a later one was already due
+    pub dropped: u64,
+    /// Frame intervals the frame on screen was kept because the next one wasn't decoded yet
+    pub duplicated: u64,
+    /// How late the last frame was shown in nanoseconds
+    pub av_offset: i64,
+}
+
+impl SyncCounters {
+    pub fn frame_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn frames_duplicated(&self, frames: u64) {
+        self.duplicated.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    pub fn set_av_offset(&self, offset: i64) {
+        self.av_offset.store(offset, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> SyncStats {
+        SyncStats {
+            dropped: self.dropped.load(Ordering::Relaxed),
+            duplicated: self.duplicated.load(Ordering::Relaxed),
+            av_offset: self.av_offset.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MILLISECOND: i64 = 1_000_000;
+
+    /// Ticks halfway between audio updates that come every 10 ms, from a sound card that runs
+    /// `drift` faster than the system clock and reports with up to 2 ms of jitter. Returns how far
+    /// off the smoothed clock is from the real audio clock after each tick
+    fn follow(smoother: &mut ClockSmoother, start: i64, updates: i64, drift: f64) -> Vec<i64> {
+        (0..updates)
+            .map(|update| {
+                let updated = update * 10 * MILLISECOND;
+                let jitter = if update % 2 == 0 { 2 } else { -2 } * MILLISECOND;
+                let audio_clock = start + (updated as f64 * (1.0 + drift)) as i64 + jitter;
+                let now = updated + 5 * MILLISECOND;
+                let clock = smoother.tick(audio_clock, updated, now, 1.0);
+                clock - (start + (now as f64 * (1.0 + drift)) as i64)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn converges_on_a_drifting_audio_clock() {
+        let mut smoother = ClockSmoother::default();
+        let errors = follow(&mut smoother, 0, 3000, 0.002);
+
+        // the jitter is averaged out and the drift learned
+        for error in &errors[2000..] {
+            assert!(error.abs() < MILLISECOND, "{} ns off", error);
+        }
+        assert!((smoother.speed - 1.002).abs() < 0.0005);
+    }
+
+    #[test]
+    fn never_goes_back_between_updates() {
+        let mut smoother = ClockSmoother::default();
+        let mut last = 0;
+        for update in 0..100 {
+            let updated = update * 10 * MILLISECOND;
+            let jitter = if update % 2 == 0 { 3 } else { -3 } * MILLISECOND;
+            let clock = smoother.tick(updated + jitter, updated, updated, 1.0);
+            assert!(clock >= last);
+            last = clock;
+        }
+    }
+
+    #[test]
+    fn starts_over_from_the_audio_clock_after_a_reset() {
+        let mut smoother = ClockSmoother::default();
+        follow(&mut smoother, 0, 500, 0.0);
+
+        // a seek a little back is within the resync threshold, only a reset follows it
+        let target = 4_950 * MILLISECOND;
+        let updated = 5_000 * MILLISECOND;
+        smoother.reset();
+        assert_eq!(smoother.tick(target, updated, updated, 1.0), target);
+        // and runs on from there at the speed it learned
+        let clock = smoother.tick(target, updated, updated + 10 * MILLISECOND, 1.0);
+        assert!((clock - (target + 10 * MILLISECOND)).abs() < MILLISECOND / 10);
+    }
+
+    #[test]
+    fn resyncs_on_a_jump_without_a_reset() {
+        let mut smoother = ClockSmoother::default();
+        follow(&mut smoother, 0, 500, 0.0);
+
+        let updated = 5_000 * MILLISECOND;
+        let clock = smoother.tick(60_000 * MILLISECOND, updated, updated, 1.0);
+        assert_eq!(clock, 60_000 * MILLISECOND);
+    }
+}
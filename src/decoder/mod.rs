@@ -0,0 +1,3510 @@
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32};
+
+use cpal::{ChannelCount, Sample, SampleFormat};
+use ringbuf::{HeapProducer, HeapRb};
+use stainless_ffmpeg::prelude::FormatContext;
+use stainless_ffmpeg::prelude::*;
+use stainless_ffmpeg::probe::Probe;
+use std::sync::{Arc, Mutex};
+
+use crate::audio_queue::{audio_queue, AudioConsumer, AudioProducer};
+use crate::audio_sink::{AudioOutput, AudioSink, OutputFormat};
+use crate::av_sync::{SyncCounters, SyncStats};
+use crate::frame_pool::{FrameBuffer, FramePool};
+use crate::playlist::{Playlist, RepeatMode};
+use crate::subtitle_file::{find_sidecar_file, parse_subtitle_file, strip_ass_tags};
+
+use self::presentation::Presenter;
+
+mod presentation;
+
+const ONE_NANOSECOND: i64 = 1000000000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekMode {
+    /// Jump to the closest keyframe before the target, fast but imprecise
+    Keyframe,
+    /// Decode and discard everything up to the exact target pts
+    Accurate,
+}
+
+#[derive(Debug)]
+pub enum MediaCommands {
+    Play,
+    Pause,
+    /// Seek to an absolute position in nanoseconds
+    Seek(i64, SeekMode),
+    /// Seek relative to the current position in nanoseconds
+    SeekRelative(i64, SeekMode),
+    /// Volume between 0.0 and 1.0
+    SetVolume(f32),
+    SetMuted(bool),
+    /// Playback speed between 0.25 and 4.0, audio keeps its pitch
+    SetRate(f32),
+    /// Show the next video frame, only while paused
+    StepForward,
+    /// Show the previous video frame, only while paused
+    StepBackward,
+    /// Switch to the audio stream with this index
+    SelectAudioTrack(usize),
+    /// Switch to the video stream with this index
+    SelectVideoTrack(usize),
+    /// Show the subtitle stream with this index, `None` turns subtitles off
+    SelectSubtitleTrack(Option<usize>),
+    /// Show the cues of an SRT, ASS or WebVTT file instead of the embedded subtitles
+    LoadSubtitleFile(PathBuf),
+    /// Skip to the next playlist item
+    Next,
+    /// Restart the current item, or go to the previous one when it only just started
+    Previous,
+    SetShuffle(bool),
+    SetRepeat(RepeatMode),
+    /// Replace whatever is open with these paths or URLs, playlist files open all their entries
+    Open(Vec<String>),
+    /// Add paths or URLs to the end of the playlist
+    Enqueue(Vec<String>),
+    /// Stop playing and let go of the file and the audio device
+    Close,
+    /// Play through the output device with this name, `None` is the default device
+    SetAudioDevice(Option<String>),
+    SetLoop(LoopMode),
+}
+
+/// What plays again once playback gets to its end
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoopMode {
+    #[default]
+    Off,
+    /// The whole item, it stays open instead of being opened again like repeating a playlist
+    /// item does
+    File,
+    /// From `start` to `end` in nanoseconds, A and B of the player
+    Range { start: i64, end: i64 },
+}
+
+impl LoopMode {
+    /// Whether the loop fits in an item of `duration` nanoseconds, any range that ends after it
+    /// starts does when the length isn't known
+    pub fn is_valid(&self, duration: Option<i64>) -> bool {
+        match *self {
+            LoopMode::Range { start, end } => {
+                start >= 0 && end > start && !duration.is_some_and(|duration| end > duration)
+            }
+            _ => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum MediaDecoderError {
+    Probe(String),
+    OpenInput(String),
+    NoStreams,
+    AudioDecoder(String),
+    VideoDecoder(String),
+    SubtitleDecoder(String),
+    SubtitleFile(String),
+    FilterGraph(String),
+    AudioOutput(String),
+    FrameOutput(String),
+    /// Seeking to the given pts in nanoseconds failed
+    Seek(i64),
+    /// The stream index doesn't exist or isn't of the requested type
+    InvalidTrack(usize),
+}
+
+impl std::fmt::Display for MediaDecoderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MediaDecoderError::Probe(err) => write!(f, "could not probe file: {err}"),
+            MediaDecoderError::OpenInput(err) => write!(f, "could not open file: {err}"),
+            MediaDecoderError::NoStreams => write!(f, "no audio or video stream found"),
+            MediaDecoderError::AudioDecoder(err) => {
+                write!(f, "could not create audio decoder: {err}")
+            }
+            MediaDecoderError::VideoDecoder(err) => {
+                write!(f, "could not create video decoder: {err}")
+            }
+            MediaDecoderError::SubtitleDecoder(err) => {
+                write!(f, "could not create subtitle decoder: {err}")
+            }
+            MediaDecoderError::SubtitleFile(err) => {
+                write!(f, "could not load subtitle file: {err}")
+            }
+            MediaDecoderError::FilterGraph(err) => write!(f, "filter graph error: {err}"),
+            MediaDecoderError::AudioOutput(err) => write!(f, "audio output error: {err}"),
+            MediaDecoderError::FrameOutput(err) => write!(f, "frame output error: {err}"),
+            MediaDecoderError::Seek(pts) => write!(f, "could not seek to {pts}"),
+            MediaDecoderError::InvalidTrack(index) => write!(f, "no such track: {index}"),
+        }
+    }
+}
+
+impl std::error::Error for MediaDecoderError {}
+
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    pub index: usize,
+    pub media_type: AVMediaType,
+    pub codec: String,
+    /// ISO 639 code from the stream metadata
+    pub language: Option<String>,
+    pub title: Option<String>,
+    /// Cover art stored as a video stream
+    pub attached_picture: bool,
+    /// Average frames per second as a fraction, for video streams
+    pub frame_rate: Option<(i32, i32)>,
+    /// PQ or HLG video that is tone mapped for the screen
+    pub hdr: bool,
+}
+
+/// Events emitted by the decoder so the UI can follow playback
+#[derive(Debug, Clone)]
+pub enum MediaEvents {
+    /// Duration in nanoseconds, `None` for live streams
+    Loaded {
+        duration: Option<i64>,
+        streams: Vec<StreamInfo>,
+    },
+    /// Current playback position in nanoseconds
+    Position(i64),
+    Paused,
+    Playing,
+    /// The video frame queue, or the audio queue for audio-only media, ran dry while playing
+    Buffering,
+    EndOfStream,
+    /// The last item of the playlist played to the end
+    Finished,
+    Volume {
+        volume: f32,
+        muted: bool,
+    },
+    Rate(f32),
+    /// The stream with this index is now used for its media type
+    TrackSelected {
+        media_type: AVMediaType,
+        index: usize,
+    },
+    /// The subtitle stream that is shown, `None` when subtitles are off
+    SubtitleTrackSelected(Option<usize>),
+    /// The cues of this file are shown instead of an embedded subtitle stream
+    SubtitleFileLoaded(PathBuf),
+    /// The subtitles on screen changed, empty when nothing is shown
+    Subtitles(Vec<Subtitle>),
+    /// The video size changed, the renderer has to be set up for the new size
+    VideoSize {
+        width: u32,
+        height: u32,
+        /// Width of a pixel relative to its height, 1:1 when the stream doesn't say
+        sample_aspect_ratio: (i32, i32),
+        /// Degrees the picture is turned clockwise to stand upright, 0, 90, 180 or 270
+        rotation: u32,
+    },
+    /// The item has neither video nor cover art
+    AudioOnly,
+    /// The playlist, its order or the item that is playing changed
+    Playlist {
+        items: Vec<String>,
+        /// Index in `items` of the item that is playing
+        current: usize,
+        shuffle: bool,
+        repeat: RepeatMode,
+    },
+    /// The decoder shut down, nothing is open until the next `Loaded`
+    Closed,
+    /// A single frame was shown while paused
+    Stepped {
+        /// Pts in nanoseconds
+        pts: i64,
+        /// `None` if the stream doesn't have a frame rate
+        frame_number: Option<i64>,
+    },
+    /// Audio plays through the output device with this name now, `None` being the default one
+    AudioDevice(Option<String>),
+    Loop(LoopMode),
+    /// How well video keeps up with the clock, sent along with the position
+    Sync(SyncStats),
+    Error(MediaDecoderError),
+}
+
+struct MediaState {
+    paused: AtomicBool,
+    /// Master clock in nanoseconds, driven by the audio output or by a system clock when there is no audio
+    audio_clock: AtomicI64,
+    /// When the audio output last moved `audio_clock`, in nanoseconds since `epoch`
+    audio_clock_updated: AtomicI64,
+    epoch: std::time::Instant,
+    end_of_stream: AtomicBool,
+    /// The audio callback couldn't fill its buffer
+    audio_starved: AtomicBool,
+    /// Gain applied to every sample, the bits of an f32 combining volume and mute
+    gain: AtomicU32,
+    /// ReplayGain of the current audio track, the bits of an f32
+    replay_gain: AtomicU32,
+    /// Playback speed, the bits of an f32
+    rate: AtomicU32,
+    /// Pts in nanoseconds of the last frame handed to the frame callback
+    displayed_pts: AtomicI64,
+    /// Frames the presentation thread still has to show while paused
+    pending_steps: AtomicU32,
+    /// Frames were stepped through, so audio has to be resynced before playing again
+    stepped: AtomicBool,
+    /// Set by the decoder when the subtitle source changes, cleared by the presentation thread
+    flush_subtitles: AtomicBool,
+    /// Frame rate of the current video track, used to number stepped frames
+    frame_rate: Mutex<Option<AVRational>>,
+    /// The item that is playing has audio, otherwise a system clock drives playback
+    has_audio: AtomicBool,
+    /// The item that is playing has real video, not just cover art
+    continuous_video: AtomicBool,
+    /// Where the item that is playing has its position 0 on the clock, subtracted for the
+    /// positions we report
+    item_offset: AtomicI64,
+    /// Set by the decoder when it moved on to the next playlist item, taken by the presentation
+    /// thread once playback gets there
+    pending_item: Mutex<Option<PendingItem>>,
+    /// Set when the decoder shuts down, its threads exit once they see it
+    closed: AtomicBool,
+    // set by the decoder after a seek, cleared by the consumer once its queue is emptied
+    flush_video: AtomicBool,
+    flush_audio: AtomicBool,
+    sync: SyncCounters,
+}
+
+impl MediaState {
+    fn rate(&self) -> f32 {
+        f32::from_bits(self.rate.load(std::sync::atomic::Ordering::Acquire))
+    }
+
+    fn item_offset(&self) -> i64 {
+        self.item_offset.load(std::sync::atomic::Ordering::Acquire)
+    }
+
+    /// Nanoseconds since `epoch`
+    fn now(&self) -> i64 {
+        self.epoch.elapsed().as_nanos() as i64
+    }
+}
+
+/// Playlist item the decoder moved on to, while the queues still hold the end of the previous one
+struct PendingItem {
+    /// Where the item starts on the clock, in nanoseconds
+    start: i64,
+    /// Subtracted from the clock for positions in the item, see `MediaSource::position_offset`
+    offset: i64,
+    replay_gain: f32,
+    frame_rate: Option<AVRational>,
+    /// Sent once playback reaches the item
+    events: Vec<MediaEvents>,
+}
+
+struct AudioTrack {
+    stream_index: isize,
+    decoder: AudioDecoder,
+    graph: FilterGraph,
+    /// Nanoseconds of media time sent into `graph` that the resampler and the tempo filters
+    /// still hold on to
+    buffered: i64,
+}
+
+struct VideoTrack {
+    stream_index: isize,
+    decoder: VideoDecoder,
+    graph: FilterGraph,
+    hw_accel: bool,
+    /// Cover art of audio files, a single picture shown for the whole duration
+    attached_picture: bool,
+    /// Encoders don't repeat the HDR metadata on every frame, the last one seen is kept
+    hdr_metadata: Option<HdrMetadata>,
+}
+
+impl VideoTrack {
+    fn get_decoded_frame(&mut self, frame: Frame) -> Result<Vec<Frame>, MediaDecoderError> {
+        let frame = unsafe {
+            if self.hw_accel {
+                let mut sw_frame = av_frame_alloc();
+                if av_hwframe_transfer_data(sw_frame, frame.frame, 0) < 0 {
+                    av_frame_free(&mut sw_frame);
+                    return Err(MediaDecoderError::VideoDecoder(
+                        "could not copy the frame from the GPU".to_string(),
+                    ));
+                }
+
+                Frame {
+                    frame: sw_frame,
+                    index: self.stream_index as usize,
+                    name: None,
+                }
+            } else {
+                frame
+            }
+        };
+
+        let (_, frames) = self
+            .graph
+            .process(&[], &[frame])
+            .map_err(MediaDecoderError::FilterGraph)?;
+
+        Ok(frames)
+    }
+}
+
+/// There is no subtitle decoder in stainless_ffmpeg, so this one uses the codec api directly
+struct SubtitleTrack {
+    stream_index: isize,
+    codec_context: *mut AVCodecContext,
+}
+
+impl SubtitleTrack {
+    fn new(format_context: &FormatContext, stream_index: isize) -> Result<Self, MediaDecoderError> {
+        unsafe {
+            let stream = format_context.get_stream(stream_index);
+            let codec = avcodec_find_decoder((*(*stream).codecpar).codec_id);
+            if codec.is_null() {
+                return Err(MediaDecoderError::SubtitleDecoder(
+                    "unsupported codec".to_string(),
+                ));
+            }
+
+            let codec_context = avcodec_alloc_context3(codec);
+            if codec_context.is_null() {
+                return Err(MediaDecoderError::SubtitleDecoder(
+                    "could not allocate codec context".to_string(),
+                ));
+            }
+
+            let mut track = Self {
+                stream_index,
+                codec_context,
+            };
+            if avcodec_parameters_to_context(track.codec_context, (*stream).codecpar) < 0 {
+                return Err(MediaDecoderError::SubtitleDecoder(
+                    "could not copy codec parameters".to_string(),
+                ));
+            }
+            // lets the decoder work out the display time from the packet duration
+            (*track.codec_context).pkt_timebase = (*stream).time_base;
+            if avcodec_open2(track.codec_context, codec, std::ptr::null_mut()) < 0 {
+                return Err(MediaDecoderError::SubtitleDecoder(
+                    "could not open codec".to_string(),
+                ));
+            }
+
+            Ok(track)
+        }
+    }
+}
+
+impl Drop for SubtitleTrack {
+    fn drop(&mut self) {
+        unsafe { avcodec_free_context(&mut self.codec_context) };
+    }
+}
+
+/// Cues of a subtitle file, fed to the subtitle queue as it has room
+struct ExternalSubtitles {
+    path: PathBuf,
+    cues: Vec<Subtitle>,
+    /// Index of the first cue that isn't queued yet
+    next: usize,
+}
+
+impl ExternalSubtitles {
+    fn load(path: &Path) -> Result<Self, MediaDecoderError> {
+        let cues = parse_subtitle_file(path).map_err(MediaDecoderError::SubtitleFile)?;
+        log::info!("Loaded {} subtitles from {}", cues.len(), path.display());
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            cues,
+            next: 0,
+        })
+    }
+
+    /// Continues from the first cue that is still on screen at `position`, cue times count from
+    /// the start of the item like positions do
+    fn rewind(&mut self, position: i64) {
+        self.next = self
+            .cues
+            .iter()
+            .position(|cue| cue.end.map_or(cue.start >= position, |end| end > position))
+            .unwrap_or(self.cues.len());
+    }
+
+    /// The next cue that isn't queued yet, on the clock of an item that has its position 0 at
+    /// `position_offset`
+    fn peek(&self, position_offset: i64) -> Option<Subtitle> {
+        let cue = self.cues.get(self.next)?;
+
+        Some(Subtitle {
+            start: cue.start + position_offset,
+            end: cue.end.map(|end| end + position_offset),
+            ..cue.clone()
+        })
+    }
+}
+
+/// Everything that belongs to a single playlist item, the queues and the audio output outlive it
+struct MediaSource {
+    path: String,
+    format_context: FormatContext,
+    audio: Option<AudioTrack>,
+    video: Option<VideoTrack>,
+    subtitles: Option<SubtitleTrack>,
+    external_subtitles: Option<ExternalSubtitles>,
+    /// ReplayGain of the audio track, applied once the item is playing
+    replay_gain: f32,
+    /// Pts of the first frame in nanoseconds
+    start_time: i64,
+    /// Added to every pts so playlist items follow each other on the clock, in nanoseconds
+    offset: i64,
+    /// Where the last decoded frame or sample ends on the clock, in nanoseconds
+    end: i64,
+}
+
+impl MediaSource {
+    fn open(
+        path_or_url: &str,
+        subtitle_file: Option<&Path>,
+        options: &MediaDecoderOptions,
+        output_format: OutputFormat,
+        rate: f32,
+        event_callback: &dyn Fn(MediaEvents),
+    ) -> Result<Self, MediaDecoderError> {
+        let mut probe = Probe::new(path_or_url);
+        probe
+            .process(log::LevelFilter::Off)
+            .map_err(MediaDecoderError::Probe)?;
+        if let Some(format) = probe.format.as_ref() {
+            log::debug!("{}", format);
+        }
+
+        let mut format_context =
+            FormatContext::new(path_or_url).map_err(MediaDecoderError::OpenInput)?;
+        format_context
+            .open_input()
+            .map_err(MediaDecoderError::OpenInput)?;
+
+        let mut first_audio_stream = None;
+        let mut first_video_stream = None;
+        let mut first_attached_picture_stream = None;
+        let mut default_subtitle_stream = None;
+        for i in 0..format_context.get_nb_streams() {
+            let stream_type = format_context.get_stream_type(i as isize);
+            log::debug!("Stream {}: {:?}", i, stream_type);
+
+            if stream_type == AVMediaType::AVMEDIA_TYPE_AUDIO {
+                first_audio_stream.get_or_insert(i as isize);
+            }
+            if stream_type == AVMediaType::AVMEDIA_TYPE_VIDEO {
+                let disposition = unsafe { (*format_context.get_stream(i as isize)).disposition };
+                if disposition & AV_DISPOSITION_ATTACHED_PIC as i32 != 0 {
+                    first_attached_picture_stream.get_or_insert(i as isize);
+                } else {
+                    first_video_stream.get_or_insert(i as isize);
+                }
+            }
+            // subtitles stay off unless the file asks for them
+            if stream_type == AVMediaType::AVMEDIA_TYPE_SUBTITLE {
+                let disposition = unsafe { (*format_context.get_stream(i as isize)).disposition };
+                if disposition & (AV_DISPOSITION_DEFAULT | AV_DISPOSITION_FORCED) as i32 != 0 {
+                    default_subtitle_stream.get_or_insert(i as isize);
+                }
+            }
+        }
+
+        // fall back to the cover art when there is no real video
+        let video_stream = first_video_stream
+            .map(|index| (index, false))
+            .or(first_attached_picture_stream.map(|index| (index, true)));
+
+        if first_audio_stream.is_none() && video_stream.is_none() {
+            return Err(MediaDecoderError::NoStreams);
+        }
+
+        let audio = match first_audio_stream {
+            Some(stream_index) => {
+                let decoder =
+                    AudioDecoder::new("audio_decoder".to_string(), &format_context, stream_index)
+                        .map_err(MediaDecoderError::AudioDecoder)?;
+                let graph = create_audio_graph(&decoder, output_format, options.downmix, rate)?;
+
+                Some(AudioTrack {
+                    stream_index,
+                    decoder,
+                    graph,
+                    buffered: 0,
+                })
+            }
+            None => None,
+        };
+        let replay_gain = audio.as_ref().map_or(1.0, |audio| {
+            get_replay_gain(&format_context, audio.stream_index, options.replay_gain)
+        });
+
+        let video = match video_stream {
+            Some((stream_index, attached_picture)) => {
+                // cover art is a single jpeg or png, not worth a hardware decoder
+                let hw_accel = options.use_hw_accel && !attached_picture;
+                let decoder = VideoDecoder::new(
+                    "video_decoder".to_string(),
+                    &format_context,
+                    stream_index,
+                    hw_accel,
+                )
+                .map_err(MediaDecoderError::VideoDecoder)?;
+                let graph = create_video_graph(&decoder)?;
+
+                Some(VideoTrack {
+                    stream_index,
+                    decoder,
+                    graph,
+                    hw_accel,
+                    attached_picture,
+                    hdr_metadata: None,
+                })
+            }
+            None => None,
+        };
+
+        // a broken subtitle file or stream shouldn't keep the file from playing
+        let external_subtitles = match subtitle_file {
+            Some(path) => ExternalSubtitles::load(path)
+                .map_err(|err| event_callback(MediaEvents::Error(err)))
+                .ok(),
+            None => find_sidecar_file(Path::new(path_or_url)).and_then(|path| {
+                ExternalSubtitles::load(&path)
+                    .map_err(|err| log::error!("{}", err))
+                    .ok()
+            }),
+        };
+        // a subtitle file next to the media wins over the embedded default
+        let subtitles = default_subtitle_stream
+            .filter(|_| external_subtitles.is_none())
+            .and_then(|stream_index| {
+                SubtitleTrack::new(&format_context, stream_index)
+                    .map_err(|err| log::error!("{}", err))
+                    .ok()
+            });
+
+        let start_time = unsafe { (*format_context.format_context).start_time };
+        let start_time = if start_time == AV_NOPTS_VALUE {
+            0
+        } else {
+            unsafe {
+                av_rescale_q(
+                    start_time,
+                    av_make_q(1, AV_TIME_BASE as i32),
+                    av_make_q(1, ONE_NANOSECOND as i32),
+                )
+            }
+        };
+
+        Ok(Self {
+            path: path_or_url.to_string(),
+            format_context,
+            audio,
+            video,
+            subtitles,
+            external_subtitles,
+            replay_gain,
+            start_time,
+            offset: 0,
+            end: 0,
+        })
+    }
+
+    /// Where the item has its position 0 on the clock. Positions start at 0 even when the first
+    /// pts doesn't, as for MPEG-TS
+    fn position_offset(&self) -> i64 {
+        self.offset + self.start_time
+    }
+
+    /// Size of the video, `None` for audio-only media without cover art
+    fn get_video_size(&self) -> Option<(u32, u32)> {
+        let video = self.video.as_ref()?;
+        let width = video.decoder.get_width() as u32;
+        let height = video.decoder.get_height() as u32;
+
+        Some((width, height))
+    }
+
+    /// The size with the aspect ratio and rotation it's shown with, `None` without a video track
+    fn get_video_size_event(&self) -> Option<MediaEvents> {
+        let video = self.video.as_ref()?;
+        let (width, height) = self.get_video_size()?;
+
+        Some(MediaEvents::VideoSize {
+            width,
+            height,
+            sample_aspect_ratio: get_sample_aspect_ratio(&self.format_context, video.stream_index),
+            rotation: get_rotation(&self.format_context, video.stream_index),
+        })
+    }
+
+    /// Total duration in nanoseconds, `None` if the container doesn't know it
+    fn get_duration(&self) -> Option<i64> {
+        let duration = unsafe { (*self.format_context.format_context).duration };
+        if duration == AV_NOPTS_VALUE {
+            return None;
+        }
+
+        Some(unsafe {
+            av_rescale_q(
+                duration,
+                av_make_q(1, AV_TIME_BASE as i32),
+                av_make_q(1, ONE_NANOSECOND as i32),
+            )
+        })
+    }
+
+    fn get_streams(&self) -> Vec<StreamInfo> {
+        (0..self.format_context.get_nb_streams())
+            .map(|i| {
+                let (codec, disposition, transfer) = unsafe {
+                    let stream = self.format_context.get_stream(i as isize);
+                    let codec = CStr::from_ptr(avcodec_get_name((*(*stream).codecpar).codec_id))
+                        .to_string_lossy()
+                        .into_owned();
+                    (
+                        codec,
+                        (*stream).disposition,
+                        (*(*stream).codecpar).color_trc,
+                    )
+                };
+
+                StreamInfo {
+                    index: i as usize,
+                    media_type: self.format_context.get_stream_type(i as isize),
+                    codec,
+                    language: get_stream_tag(&self.format_context, i as isize, "language"),
+                    title: get_stream_tag(&self.format_context, i as isize, "title"),
+                    attached_picture: disposition & AV_DISPOSITION_ATTACHED_PIC as i32 != 0,
+                    frame_rate: get_frame_rate(&self.format_context, i as isize)
+                        .map(|rate| (rate.num, rate.den)),
+                    hdr: is_hdr_transfer(transfer),
+                }
+            })
+            .collect()
+    }
+
+    /// Checks that `index` is a stream of `media_type` we can switch to
+    fn get_track_stream(&self, index: usize, media_type: AVMediaType) -> Option<isize> {
+        if index >= self.format_context.get_nb_streams() as usize {
+            return None;
+        }
+        let stream_index = index as isize;
+        if self.format_context.get_stream_type(stream_index) != media_type {
+            return None;
+        }
+        // cover art is only used when there is no real video
+        let disposition = unsafe { (*self.format_context.get_stream(stream_index)).disposition };
+        if disposition & AV_DISPOSITION_ATTACHED_PIC as i32 != 0 {
+            return None;
+        }
+
+        Some(stream_index)
+    }
+
+    /// Stream index of the track that is playing for every media type
+    fn get_selected_tracks(&self) -> Vec<MediaEvents> {
+        let mut events = vec![];
+        if let Some(audio) = self.audio.as_ref() {
+            events.push(MediaEvents::TrackSelected {
+                media_type: AVMediaType::AVMEDIA_TYPE_AUDIO,
+                index: audio.stream_index as usize,
+            });
+        }
+        if let Some(video) = self.video.as_ref() {
+            events.push(MediaEvents::TrackSelected {
+                media_type: AVMediaType::AVMEDIA_TYPE_VIDEO,
+                index: video.stream_index as usize,
+            });
+        }
+        events.push(MediaEvents::SubtitleTrackSelected(
+            self.subtitles
+                .as_ref()
+                .map(|subtitles| subtitles.stream_index as usize),
+        ));
+        if let Some(external_subtitles) = self.external_subtitles.as_ref() {
+            events.push(MediaEvents::SubtitleFileLoaded(
+                external_subtitles.path.clone(),
+            ));
+        }
+
+        events
+    }
+
+    fn get_frame_rate(&self) -> Option<AVRational> {
+        let video = self.video.as_ref()?;
+        get_frame_rate(&self.format_context, video.stream_index)
+    }
+
+    /// Real video rather than cover art, which doesn't tell us anything about buffering
+    fn has_continuous_video(&self) -> bool {
+        self.video
+            .as_ref()
+            .is_some_and(|video| !video.attached_picture)
+    }
+
+    /// Items with the same kind of streams can share the clock, so one follows the other without a gap
+    fn is_compatible(&self, other: &MediaSource) -> bool {
+        self.audio.is_some() == other.audio.is_some()
+            && self.has_continuous_video() == other.has_continuous_video()
+    }
+}
+
+pub struct MediaDecoder {
+    video_producer: HeapProducer<DecodedFrame>,
+    subtitle_producer: HeapProducer<Subtitle>,
+    /// Opened with the first item that has audio and kept for the items after it
+    audio_producer: Option<AudioProducer>,
+    audio_output: Option<Box<dyn AudioSink>>,
+    source: MediaSource,
+    /// The item after the current one with its playlist position, opened ahead of time
+    next_source: Option<(usize, MediaSource)>,
+    /// Whether `next_source` was opened, or failed to open, for the current item
+    next_prepared: bool,
+    /// Set once `Finished` was sent, until playback moves away from the end
+    finished: bool,
+    /// The item that is still playing from the queues after the decoder moved on, with its
+    /// playlist position
+    previous_source: Option<(usize, MediaSource)>,
+    playlist: Playlist,
+    output_format: OutputFormat,
+    pub command_sender: crossbeam_channel::Sender<MediaCommands>,
+    decoder_command_receiver: crossbeam_channel::Receiver<MediaCommands>,
+    state: Arc<MediaState>,
+    event_callback: Arc<dyn Fn(MediaEvents) + Send + Sync>,
+    /// frames before this pts are decoded but dropped, used for accurate seeking
+    seek_target: Option<i64>,
+    loop_mode: LoopMode,
+    /// Stream pts where decoding got to the end of the loop, the decoder goes back to its start once
+    /// playback reached the start of the previous round
+    loop_end_reached: Option<i64>,
+    options: MediaDecoderOptions,
+    /// Buffers of the frames in the video queue, they come back once a frame was shown
+    frame_pool: FramePool,
+}
+
+#[derive(Debug)]
+pub struct DecodedFrame {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    pub color: ColorInfo,
+    /// Static metadata of HDR video, `None` when the stream doesn't have any
+    pub hdr_metadata: Option<HdrMetadata>,
+    /// The planes back to back, every plane keeps the row padding of `linesizes`
+    pub data: FrameBuffer,
+    pub linesizes: Vec<i32>,
+    pub pts: i64,
+}
+
+/// Color metadata of a frame, values the stream leaves unspecified are filled in with what it most
+/// likely is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColorInfo {
+    pub space: AVColorSpace,
+    pub range: AVColorRange,
+    pub primaries: AVColorPrimaries,
+    pub transfer: AVColorTransferCharacteristic,
+    /// Where the chroma samples sit relative to the luma samples
+    pub chroma_location: AVChromaLocation,
+}
+
+impl ColorInfo {
+    fn from_frame(frame: &AVFrame, format: &PixelFormat) -> Self {
+        // anything bigger than PAL is assumed to be HD
+        let hd = frame.width > 1024 || frame.height > 576;
+        let pal = frame.height == 576;
+
+        let space = match frame.colorspace {
+            AVColorSpace::AVCOL_SPC_UNSPECIFIED | AVColorSpace::AVCOL_SPC_RESERVED => {
+                if hd {
+                    AVColorSpace::AVCOL_SPC_BT709
+                } else if pal {
+                    AVColorSpace::AVCOL_SPC_BT470BG
+                } else {
+                    AVColorSpace::AVCOL_SPC_SMPTE170M
+                }
+            }
+            space => space,
+        };
+
+        let range = match frame.color_range {
+            AVColorRange::AVCOL_RANGE_UNSPECIFIED if format.rgb => AVColorRange::AVCOL_RANGE_JPEG,
+            AVColorRange::AVCOL_RANGE_UNSPECIFIED => AVColorRange::AVCOL_RANGE_MPEG,
+            range => range,
+        };
+
+        let primaries = match frame.color_primaries {
+            AVColorPrimaries::AVCOL_PRI_UNSPECIFIED
+            | AVColorPrimaries::AVCOL_PRI_RESERVED
+            | AVColorPrimaries::AVCOL_PRI_RESERVED0 => match space {
+                AVColorSpace::AVCOL_SPC_BT2020_NCL | AVColorSpace::AVCOL_SPC_BT2020_CL => {
+                    AVColorPrimaries::AVCOL_PRI_BT2020
+                }
+                AVColorSpace::AVCOL_SPC_BT470BG => AVColorPrimaries::AVCOL_PRI_BT470BG,
+                AVColorSpace::AVCOL_SPC_SMPTE170M => AVColorPrimaries::AVCOL_PRI_SMPTE170M,
+                _ => AVColorPrimaries::AVCOL_PRI_BT709,
+            },
+            primaries => primaries,
+        };
+
+        let transfer = match frame.color_trc {
+            AVColorTransferCharacteristic::AVCOL_TRC_UNSPECIFIED
+            | AVColorTransferCharacteristic::AVCOL_TRC_RESERVED
+            | AVColorTransferCharacteristic::AVCOL_TRC_RESERVED0 => {
+                AVColorTransferCharacteristic::AVCOL_TRC_BT709
+            }
+            transfer => transfer,
+        };
+
+        // MPEG-2 and H.264 put 4:2:0 chroma on the left edge unless they say otherwise
+        let chroma_location = match frame.chroma_location {
+            AVChromaLocation::AVCHROMA_LOC_UNSPECIFIED => AVChromaLocation::AVCHROMA_LOC_LEFT,
+            location => location,
+        };
+
+        Self {
+            space,
+            range,
+            primaries,
+            transfer,
+            chroma_location,
+        }
+    }
+
+    pub fn is_hdr(&self) -> bool {
+        is_hdr_transfer(self.transfer)
+    }
+
+    pub fn is_full_range(&self) -> bool {
+        self.range == AVColorRange::AVCOL_RANGE_JPEG
+    }
+
+    /// Kr and Kb of the matrix that turns R'G'B' into Y'CbCr, BT.709 for spaces that aren't a
+    /// weighted sum like YCgCo
+    pub fn luma_coefficients(&self) -> (f32, f32) {
+        match self.space {
+            AVColorSpace::AVCOL_SPC_BT470BG | AVColorSpace::AVCOL_SPC_SMPTE170M => (0.299, 0.114),
+            AVColorSpace::AVCOL_SPC_FCC => (0.30, 0.11),
+            AVColorSpace::AVCOL_SPC_SMPTE240M => (0.212, 0.087),
+            AVColorSpace::AVCOL_SPC_BT2020_NCL | AVColorSpace::AVCOL_SPC_BT2020_CL => {
+                (0.2627, 0.0593)
+            }
+            _ => (0.2126, 0.0722),
+        }
+    }
+
+    /// Position of a chroma sample between the two luma samples it covers horizontally and
+    /// vertically, 0.0 is on the first one and 0.5 halfway
+    pub fn chroma_position(&self) -> (f32, f32) {
+        match self.chroma_location {
+            AVChromaLocation::AVCHROMA_LOC_CENTER => (0.5, 0.5),
+            AVChromaLocation::AVCHROMA_LOC_TOPLEFT => (0.0, 0.0),
+            AVChromaLocation::AVCHROMA_LOC_TOP => (0.5, 0.0),
+            AVChromaLocation::AVCHROMA_LOC_BOTTOMLEFT => (0.0, 1.0),
+            AVChromaLocation::AVCHROMA_LOC_BOTTOM => (0.5, 1.0),
+            _ => (0.0, 0.5),
+        }
+    }
+}
+
+fn is_hdr_transfer(transfer: AVColorTransferCharacteristic) -> bool {
+    matches!(
+        transfer,
+        AVColorTransferCharacteristic::AVCOL_TRC_SMPTE2084
+            | AVColorTransferCharacteristic::AVCOL_TRC_ARIB_STD_B67
+    )
+}
+
+/// Static HDR metadata from the frame side data, luminance in nits
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct HdrMetadata {
+    /// Peak luminance of the display the video was mastered on
+    pub mastering_max_luminance: Option<f32>,
+    pub mastering_min_luminance: Option<f32>,
+    /// Brightest pixel of the whole video
+    pub max_cll: Option<f32>,
+    /// Brightest frame average of the whole video
+    pub max_fall: Option<f32>,
+}
+
+impl HdrMetadata {
+    /// `None` when the frame has neither mastering display nor content light level side data
+    unsafe fn from_frame(frame: *const AVFrame) -> Option<Self> {
+        let mut metadata = None;
+
+        let side_data = av_frame_get_side_data(
+            frame,
+            AVFrameSideDataType::AV_FRAME_DATA_MASTERING_DISPLAY_METADATA,
+        );
+        if !side_data.is_null() {
+            let mastering = &*((*side_data).data as *const AVMasteringDisplayMetadata);
+            if mastering.has_luminance != 0 {
+                let metadata = metadata.get_or_insert(Self::default());
+                metadata.mastering_max_luminance = Some(av_q2d(mastering.max_luminance) as f32);
+                metadata.mastering_min_luminance = Some(av_q2d(mastering.min_luminance) as f32);
+            }
+        }
+
+        let side_data = av_frame_get_side_data(
+            frame,
+            AVFrameSideDataType::AV_FRAME_DATA_CONTENT_LIGHT_LEVEL,
+        );
+        if !side_data.is_null() {
+            let light_level = &*((*side_data).data as *const AVContentLightMetadata);
+            let metadata = metadata.get_or_insert(Self::default());
+            // zero means unknown
+            metadata.max_cll = (light_level.MaxCLL > 0).then_some(light_level.MaxCLL as f32);
+            metadata.max_fall = (light_level.MaxFALL > 0).then_some(light_level.MaxFALL as f32);
+        }
+
+        metadata
+    }
+
+    /// Brightest the video gets, the content light level is more accurate than the mastering
+    /// display when it's there
+    pub fn peak_luminance(&self) -> Option<f32> {
+        self.max_cll.or(self.mastering_max_luminance)
+    }
+}
+
+/// Pixel formats the renderer uploads as they are, the filter graph converts anything else to
+/// the closest one of these
+pub const SUPPORTED_PIXEL_FORMATS: &[AVPixelFormat] = &[
+    AVPixelFormat::AV_PIX_FMT_YUV420P,
+    AVPixelFormat::AV_PIX_FMT_NV12,
+    AVPixelFormat::AV_PIX_FMT_P010LE,
+    AVPixelFormat::AV_PIX_FMT_P016LE,
+    AVPixelFormat::AV_PIX_FMT_YUV420P10LE,
+    AVPixelFormat::AV_PIX_FMT_YUV422P,
+    AVPixelFormat::AV_PIX_FMT_YUV422P10LE,
+    AVPixelFormat::AV_PIX_FMT_YUV444P,
+    AVPixelFormat::AV_PIX_FMT_YUV444P10LE,
+    AVPixelFormat::AV_PIX_FMT_RGBA,
+    AVPixelFormat::AV_PIX_FMT_BGRA,
+];
+
+/// How the samples of a `DecodedFrame` are laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelFormat {
+    pub format: AVPixelFormat,
+    /// Planes in the data, semi-planar formats like NV12 interleave the chroma in the second one
+    pub planes: usize,
+    /// log2 of how much smaller the chroma planes are than the luma plane horizontally and
+    /// vertically, 4:2:0 is `(1, 1)`
+    pub chroma_shift: (u32, u32),
+    /// Significant bits of a sample, deeper than 8 bits is stored in 16 bits with the value in
+    /// the high bits like P010
+    pub bit_depth: u32,
+    /// Packed RGB with 4 bytes per pixel
+    pub rgb: bool,
+}
+
+impl PixelFormat {
+    pub fn from_av(format: AVPixelFormat) -> Option<Self> {
+        unsafe {
+            let descriptor = av_pix_fmt_desc_get(format);
+            if descriptor.is_null() {
+                return None;
+            }
+            let descriptor = &*descriptor;
+
+            Some(Self {
+                format,
+                planes: av_pix_fmt_count_planes(format).max(1) as usize,
+                chroma_shift: (
+                    descriptor.log2_chroma_w as u32,
+                    descriptor.log2_chroma_h as u32,
+                ),
+                bit_depth: descriptor.comp[0].depth as u32,
+                rgb: descriptor.flags & AV_PIX_FMT_FLAG_RGB as u64 != 0,
+            })
+        }
+    }
+
+    pub fn name(&self) -> String {
+        unsafe {
+            let name = av_get_pix_fmt_name(self.format);
+            if name.is_null() {
+                return format!("{:?}", self.format);
+            }
+            CStr::from_ptr(name).to_string_lossy().into_owned()
+        }
+    }
+
+    pub fn is_semi_planar(&self) -> bool {
+        !self.rgb && self.planes == 2
+    }
+
+    pub fn bytes_per_sample(&self) -> usize {
+        if self.bit_depth > 8 {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Bytes of a single pixel in `plane`
+    pub fn pixel_size(&self, plane: usize) -> usize {
+        if self.rgb {
+            4
+        } else if plane > 0 && self.is_semi_planar() {
+            self.bytes_per_sample() * 2
+        } else {
+            self.bytes_per_sample()
+        }
+    }
+
+    /// Width and height of `plane` in pixels for a frame of `width` by `height`, odd sizes round
+    /// the chroma up
+    pub fn plane_size(&self, plane: usize, width: u32, height: u32) -> (u32, u32) {
+        if plane == 0 || self.rgb {
+            (width, height)
+        } else {
+            let (shift_x, shift_y) = self.chroma_shift;
+            (
+                (width + (1 << shift_x) - 1) >> shift_x,
+                (height + (1 << shift_y) - 1) >> shift_y,
+            )
+        }
+    }
+
+    /// Planar formats keep deep samples in the low bits, they're moved up by this much
+    fn alignment_shift(&self) -> u32 {
+        if self.rgb || self.is_semi_planar() || self.bit_depth <= 8 {
+            0
+        } else {
+            16 - self.bit_depth
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Subtitle {
+    /// Start in nanoseconds
+    pub start: i64,
+    /// End in nanoseconds, `None` when it stays until the next subtitle
+    pub end: Option<i64>,
+    /// Lines of text without any styling
+    pub text: Vec<String>,
+    pub bitmaps: Vec<SubtitleBitmap>,
+    /// Size of the picture the bitmaps are positioned in
+    pub canvas: (u32, u32),
+}
+
+#[derive(Debug, Clone)]
+pub struct SubtitleBitmap {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// RGBA pixels
+    pub data: Vec<u8>,
+}
+
+pub struct MediaDecoderOptions {
+    pub use_hw_accel: bool,
+    pub replay_gain: ReplayGainMode,
+    /// When `None` a subtitle file with the same name as the media is used, if there is one
+    pub subtitle_file: Option<PathBuf>,
+    /// Volume between 0.0 and 1.0 to start with, so it carries over to the next file
+    pub volume: f32,
+    pub muted: bool,
+    pub audio_output: AudioOutput,
+    pub pacing: Pacing,
+    pub downmix: DownmixMode,
+}
+
+/// How fast the decoded media is played
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Pacing {
+    /// Frames are shown when the clock gets to them
+    #[default]
+    Realtime,
+    /// Every frame is passed on as soon as it's decoded, for writing to files. Audio only keeps
+    /// up when the sink isn't a sound card
+    AsFastAsPossible,
+}
+
+/// Which ReplayGain tags from the container metadata are used to normalize loudness
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+}
+
+/// How sources with more channels than the output are mixed down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DownmixMode {
+    /// ITU-R BS.775, center and surrounds at -3 dB and the LFE left out
+    #[default]
+    Itu,
+    /// Center at full level and quieter surrounds, so speech is easier to follow
+    Dialogue,
+    /// Dolby Surround matrix encoding, for receivers that decode it back to surround
+    Dolby,
+    /// Dolby Pro Logic II matrix encoding
+    ProLogicII,
+}
+
+impl DownmixMode {
+    pub const ALL: [DownmixMode; 4] = [
+        DownmixMode::Itu,
+        DownmixMode::Dialogue,
+        DownmixMode::Dolby,
+        DownmixMode::ProLogicII,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DownmixMode::Itu => "itu",
+            DownmixMode::Dialogue => "dialogue",
+            DownmixMode::Dolby => "dolby",
+            DownmixMode::ProLogicII => "dplii",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|mode| mode.name() == name)
+    }
+
+    /// Options of the resampler that does the mixing
+    fn resampler_options(&self) -> Vec<(&'static str, &'static str)> {
+        match self {
+            DownmixMode::Itu => vec![],
+            DownmixMode::Dialogue => {
+                vec![("center_mix_level", "1.0"), ("surround_mix_level", "0.5")]
+            }
+            DownmixMode::Dolby => vec![("matrix_encoding", "dolby")],
+            DownmixMode::ProLogicII => vec![("matrix_encoding", "dplii")],
+        }
+    }
+}
+
+/// The layout FFmpeg mixes to for the channels of the output device, devices with more channels
+/// than we know get stereo. The device only tells us how many channels it has, not which
+/// speakers, so we go with FFmpeg's default layout for the count: 3 channels are 2.1 (left,
+/// right and LFE) rather than 3.0, and 7 are 6.1 with a back center
+fn get_channel_layout(channels: ChannelCount) -> Option<&'static str> {
+    Some(match channels {
+        1 => "mono",
+        2 => "stereo",
+        3 => "2.1",
+        4 => "quad",
+        5 => "5.0",
+        6 => "5.1",
+        7 => "6.1",
+        8 => "7.1",
+        _ => return None,
+    })
+}
+
+/// The format the audio is resampled to for `output`, devices with more channels than we have
+/// layouts for get stereo
+fn get_output_format(output: &AudioOutput) -> Result<OutputFormat, MediaDecoderError> {
+    let format = output.preferred_format()?;
+
+    Ok(OutputFormat {
+        channels: match get_channel_layout(format.channels) {
+            Some(_) => format.channels,
+            None => 2,
+        },
+        ..format
+    })
+}
+
+const VIDEO_FRAME_QUEUE_SIZE: usize = 100;
+const SUBTITLE_QUEUE_SIZE: usize = 256;
+/// How far ahead we decode audio, this bounds the decoder for audio-only media
+const AUDIO_QUEUE_DURATION: std::time::Duration = std::time::Duration::from_secs(5);
+/// How long the decoder waits for the presentation thread and the audio output to empty their
+/// queues, they only take longer when they're stuck
+const FLUSH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+/// Going to the previous item restarts the current one when it played longer than this
+const RESTART_THRESHOLD: i64 = 3 * ONE_NANOSECOND;
+
+impl MediaDecoder {
+    pub fn new<F, E>(
+        mut playlist: Playlist,
+        options: MediaDecoderOptions,
+        new_frame_callback: F,
+        event_callback: E,
+    ) -> Result<Self, MediaDecoderError>
+    where
+        F: Fn(DecodedFrame) + Send + Sync + 'static,
+        E: Fn(MediaEvents) + Send + Sync + 'static,
+    {
+        let event_callback: Arc<dyn Fn(MediaEvents) + Send + Sync> = Arc::new(event_callback);
+
+        // the audio is resampled to what the device plays, so it doesn't convert it again
+        let output_format = get_output_format(&options.audio_output).unwrap_or_else(|err| {
+            log::warn!("Could not query the audio output: {}", err);
+            OutputFormat::default()
+        });
+        log::debug!(
+            "Resampling audio to {} channels at {} Hz in {}",
+            output_format.channels,
+            output_format.sample_rate,
+            output_format.sample_format
+        );
+
+        // items that fail to open are skipped
+        let mut source = Err(MediaDecoderError::NoStreams);
+        for position in 0..playlist.len() {
+            playlist.set_position(position);
+            let Some(path_or_url) = playlist.current() else {
+                break;
+            };
+            // the subtitle file from the options belongs to the first item
+            let subtitle_file = options.subtitle_file.as_deref().filter(|_| position == 0);
+
+            source = MediaSource::open(
+                path_or_url,
+                subtitle_file,
+                &options,
+                output_format,
+                1.0,
+                &*event_callback,
+            );
+            match source.as_ref() {
+                Ok(_) => break,
+                Err(err) => log::error!("Failed to open {}: {}", path_or_url, err),
+            }
+        }
+        let source = source?;
+
+        let (video_producer, video_consumer) =
+            HeapRb::<DecodedFrame>::new(VIDEO_FRAME_QUEUE_SIZE).split();
+        let (subtitle_producer, subtitle_consumer) =
+            HeapRb::<Subtitle>::new(SUBTITLE_QUEUE_SIZE).split();
+        let state = Arc::new(MediaState {
+            paused: AtomicBool::new(true),
+            audio_clock: AtomicI64::new(0),
+            audio_clock_updated: AtomicI64::new(0),
+            epoch: std::time::Instant::now(),
+            end_of_stream: AtomicBool::new(false),
+            audio_starved: AtomicBool::new(false),
+            gain: AtomicU32::new(1.0f32.to_bits()),
+            replay_gain: AtomicU32::new(source.replay_gain.to_bits()),
+            rate: AtomicU32::new(1.0f32.to_bits()),
+            displayed_pts: AtomicI64::new(0),
+            pending_steps: AtomicU32::new(0),
+            stepped: AtomicBool::new(false),
+            flush_subtitles: AtomicBool::new(false),
+            frame_rate: Mutex::new(source.get_frame_rate()),
+            has_audio: AtomicBool::new(source.audio.is_some()),
+            continuous_video: AtomicBool::new(source.has_continuous_video()),
+            item_offset: AtomicI64::new(source.position_offset()),
+            pending_item: Mutex::new(None),
+            closed: AtomicBool::new(false),
+            flush_video: AtomicBool::new(false),
+            flush_audio: AtomicBool::new(false),
+            sync: SyncCounters::default(),
+        });
+
+        let volume = options.volume.clamp(0.0, 1.0);
+        let muted = options.muted;
+        update_gain(&state, volume, muted);
+
+        let (audio_producer, audio_output) = if source.audio.is_some() {
+            let (producer, output) = create_audio_output(
+                &options.audio_output,
+                options.pacing,
+                output_format,
+                state.clone(),
+            )?;
+            (Some(producer), Some(output))
+        } else {
+            (None, None)
+        };
+
+        Presenter::new(
+            state.clone(),
+            video_consumer,
+            subtitle_consumer,
+            new_frame_callback,
+            event_callback.clone(),
+            options.pacing,
+        )
+        .spawn();
+
+        let (command_sender, command_receiver) = crossbeam_channel::bounded::<MediaCommands>(1);
+        // commands that touch the demuxer or decoders are forwarded to the decode loop
+        let (decoder_command_sender, decoder_command_receiver) =
+            crossbeam_channel::unbounded::<MediaCommands>();
+        std::thread::spawn({
+            let state = state.clone();
+            let event_callback = event_callback.clone();
+            move || {
+                run_commands(
+                    command_receiver,
+                    decoder_command_sender,
+                    &state,
+                    &*event_callback,
+                    volume,
+                    muted,
+                )
+            }
+        });
+
+        Ok(Self {
+            video_producer,
+            subtitle_producer,
+            audio_producer,
+            audio_output,
+            source,
+            next_source: None,
+            next_prepared: false,
+            finished: false,
+            previous_source: None,
+            playlist,
+            output_format,
+            command_sender,
+            decoder_command_receiver,
+            state,
+            event_callback,
+            seek_target: None,
+            loop_mode: LoopMode::Off,
+            loop_end_reached: None,
+            options,
+            frame_pool: FramePool::new(),
+        })
+    }
+
+    pub fn frame_pool(&self) -> &FramePool {
+        &self.frame_pool
+    }
+
+    pub fn sync_stats(&self) -> SyncStats {
+        self.state.sync.stats()
+    }
+
+    /// Moves the audio to another output device, what is queued carries over so audio and video
+    /// stay in sync. When the device can't be opened we go on without sound
+    fn set_audio_device(&mut self, device: Option<String>) -> Result<(), MediaDecoderError> {
+        let AudioOutput::Cpal(current) = &mut self.options.audio_output else {
+            log::warn!("Only a sound card output can switch devices");
+            return Ok(());
+        };
+        *current = device.clone();
+        (self.event_callback)(MediaEvents::AudioDevice(device));
+
+        if self.reopen_audio_output()? && self.source.audio.is_some() {
+            self.seek(self.get_position(), SeekMode::Accurate)?;
+        }
+
+        Ok(())
+    }
+
+    /// Hands the renderer to a sink for the output in the options. When the device can't play
+    /// what is queued the audio is set up for its format instead and true is returned, the
+    /// samples then have to be decoded again
+    fn reopen_audio_output(&mut self) -> Result<bool, MediaDecoderError> {
+        // the output is opened with the first item that has audio
+        let Some(renderer) = self.audio_output.take().and_then(|output| output.stop()) else {
+            self.audio_producer = None;
+            return Ok(false);
+        };
+
+        let output = self.options.audio_output.clone();
+        let pacing = self.options.pacing;
+        let err = match output.create_sink(self.output_format, pacing) {
+            Ok(mut sink) => {
+                sink.start(renderer)?;
+                self.audio_output = Some(sink);
+                return Ok(false);
+            }
+            Err(err) => err,
+        };
+
+        match get_output_format(&output) {
+            Ok(format) if format != self.output_format => {
+                log::info!(
+                    "Resampling audio to {} channels at {} Hz in {} for the new device",
+                    format.channels,
+                    format.sample_rate,
+                    format.sample_format
+                );
+                self.output_format = format;
+                let (producer, output) =
+                    create_audio_output(&output, pacing, format, self.state.clone())?;
+                self.audio_producer = Some(producer);
+                self.audio_output = Some(output);
+
+                // the next item's audio graph still has the old format
+                self.next_source = None;
+                self.next_prepared = false;
+                Ok(true)
+            }
+            result => {
+                self.play_without_sound(renderer, result.err().unwrap_or(err))?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Keeps the clock going on a null sink when there is no device to play on
+    fn play_without_sound(
+        &mut self,
+        renderer: AudioRenderer,
+        err: MediaDecoderError,
+    ) -> Result<(), MediaDecoderError> {
+        log::error!("Playing without sound: {}", err);
+        (self.event_callback)(MediaEvents::Error(err));
+
+        let mut sink = AudioOutput::Null.create_sink(self.output_format, Pacing::Realtime)?;
+        sink.start(renderer)?;
+        self.audio_output = Some(sink);
+
+        Ok(())
+    }
+
+    /// Seeks the demuxer to the position `pts` (in nanoseconds), flushes the decoders, filter
+    /// graphs and frame queues
+    fn seek(&mut self, pts: i64, mode: SeekMode) -> Result<(), MediaDecoderError> {
+        log::debug!("Seeking to {} ({:?})", pts, mode);
+
+        if !self.rewind_source(pts + self.source.start_time)? {
+            return Ok(());
+        }
+
+        self.flush_queues()?;
+        // the round of the loop that hasn't started playing yet is dropped with the queues
+        self.loop_end_reached = None;
+        if self.previous_source.is_none() {
+            if let Some(item) = self.state.pending_item.lock().unwrap().take() {
+                self.state
+                    .item_offset
+                    .store(item.offset, std::sync::atomic::Ordering::Release);
+            }
+        }
+        self.source.end = pts + self.source.position_offset();
+        self.state
+            .audio_clock
+            .store(0, std::sync::atomic::Ordering::Release);
+        self.state
+            .end_of_stream
+            .store(false, std::sync::atomic::Ordering::Release);
+        (self.event_callback)(MediaEvents::Position(pts));
+
+        self.seek_target = match mode {
+            SeekMode::Keyframe => None,
+            SeekMode::Accurate => Some(pts + self.source.position_offset()),
+        };
+
+        Ok(())
+    }
+
+    /// Seeks the demuxer to the keyframe before the stream pts `pts` and flushes the decoders and filter graphs,
+    /// returns false when the demuxer couldn't seek
+    fn rewind_source(&mut self, pts: i64) -> Result<bool, MediaDecoderError> {
+        unsafe {
+            // without a stream index the timestamp is expected in AV_TIME_BASE (microseconds)
+            let timestamp = av_rescale_q(
+                pts,
+                av_make_q(1, ONE_NANOSECOND as i32),
+                av_make_q(1, AV_TIME_BASE as i32),
+            );
+            let ret = av_seek_frame(
+                self.source.format_context.format_context,
+                -1,
+                timestamp,
+                AVSEEK_FLAG_BACKWARD as i32,
+            );
+            if ret < 0 {
+                log::error!("Failed to seek to {}: {}", pts, ret);
+                (self.event_callback)(MediaEvents::Error(MediaDecoderError::Seek(pts)));
+                return Ok(false);
+            }
+        }
+
+        // filter graphs hold on to buffered frames, so we rebuild them
+        if let Some(audio) = self.source.audio.as_ref() {
+            unsafe { avcodec_flush_buffers(audio.decoder.codec_context) };
+        }
+        self.rebuild_audio_graph()?;
+        if let Some(video) = self.source.video.as_mut() {
+            unsafe { avcodec_flush_buffers(video.decoder.codec_context) };
+            video.graph = create_video_graph(&video.decoder)?;
+        }
+        if let Some(subtitles) = self.source.subtitles.as_ref() {
+            unsafe { avcodec_flush_buffers(subtitles.codec_context) };
+        }
+        let position = pts - self.source.start_time;
+        if let Some(external_subtitles) = self.source.external_subtitles.as_mut() {
+            external_subtitles.rewind(position);
+        }
+
+        Ok(true)
+    }
+
+    fn set_loop(&mut self, mode: LoopMode) -> Result<(), MediaDecoderError> {
+        if !mode.is_valid(self.source.get_duration()) {
+            log::warn!("The loop has to end after its start and within the item");
+            return Ok(());
+        }
+        self.loop_mode = mode;
+        self.loop_end_reached = None;
+        (self.event_callback)(MediaEvents::Loop(mode));
+
+        // what is queued past the end would play before going back, so decoding starts over
+        // from where we are
+        if let LoopMode::Range { start, end } = mode {
+            if self.source.end - self.source.position_offset() > end {
+                let position = self.get_position();
+                let position = if (start..end).contains(&position) {
+                    position
+                } else {
+                    start
+                };
+                self.seek(position, SeekMode::Accurate)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// End of the loop on the clock, for the round that is being decoded
+    fn get_loop_end(&self) -> Option<i64> {
+        match self.loop_mode {
+            LoopMode::Range { end, .. } => Some(end + self.source.position_offset()),
+            _ => None,
+        }
+    }
+
+    /// Goes back to the start of the loop after decoding got to the stream pts `end`. The queues
+    /// and the clock are left alone, the start is stamped to follow `end` so it plays right after it
+    fn loop_back(&mut self, end: i64) -> Result<(), MediaDecoderError> {
+        let start = match self.loop_mode {
+            LoopMode::Off => return Ok(()),
+            LoopMode::File => self.source.start_time,
+            LoopMode::Range { start, .. } => start + self.source.start_time,
+        };
+        log::debug!("Looping back from {} to {}", end, start);
+
+        if !self.rewind_source(start)? {
+            return Ok(());
+        }
+
+        let loop_start = self.source.offset + end;
+        self.source.offset = loop_start - start;
+        self.source.end = loop_start;
+        self.seek_target = Some(start + self.source.offset);
+        // the positions we report jump back once playback gets there
+        *self.state.pending_item.lock().unwrap() = Some(PendingItem {
+            start: loop_start,
+            offset: self.source.position_offset(),
+            replay_gain: self.source.replay_gain,
+            frame_rate: self.source.get_frame_rate(),
+            events: vec![],
+        });
+
+        Ok(())
+    }
+
+    /// Pts of `packet` in nanoseconds, without the offset of the item
+    fn get_packet_pts(&self, packet: &Packet) -> Option<i64> {
+        unsafe {
+            let pts = if (*packet.packet).pts != AV_NOPTS_VALUE {
+                (*packet.packet).pts
+            } else {
+                (*packet.packet).dts
+            };
+            if pts == AV_NOPTS_VALUE {
+                return None;
+            }
+            let time_base = (*self
+                .source
+                .format_context
+                .get_stream(packet.get_stream_index()))
+            .time_base;
+
+            Some(av_rescale_q(
+                pts,
+                time_base,
+                av_make_q(1, ONE_NANOSECOND as i32),
+            ))
+        }
+    }
+
+    /// Sets up a new audio graph for the output format and the playback rate, which drops the
+    /// samples buffered in the old one
+    fn rebuild_audio_graph(&mut self) -> Result<(), MediaDecoderError> {
+        if let Some(audio) = self.source.audio.as_mut() {
+            audio.graph = create_audio_graph(
+                &audio.decoder,
+                self.output_format,
+                self.options.downmix,
+                self.state.rate(),
+            )?;
+            audio.buffered = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Asks the video presentation thread and the audio callback to drop their queued data and
+    /// waits until they did, or until `FLUSH_TIMEOUT` passed
+    fn flush_queues(&mut self) -> Result<(), MediaDecoderError> {
+        // a device that went away doesn't call back anymore, so it would never empty its queue
+        if self
+            .audio_output
+            .as_ref()
+            .is_some_and(|output| output.is_lost())
+        {
+            log::warn!("The audio device went away, switching to the default one");
+            if let AudioOutput::Cpal(device) = &mut self.options.audio_output {
+                *device = None;
+            }
+            (self.event_callback)(MediaEvents::AudioDevice(None));
+            // we're seeking or switching items already, only the graph has to follow the format
+            if self.reopen_audio_output()? {
+                self.rebuild_audio_graph()?;
+            }
+        }
+
+        self.state
+            .flush_video
+            .store(true, std::sync::atomic::Ordering::Release);
+        if self.audio_producer.is_some() {
+            self.state
+                .flush_audio
+                .store(true, std::sync::atomic::Ordering::Release);
+        }
+
+        if self.wait_for_flush(&[&self.state.flush_video, &self.state.flush_audio]) {
+            return Ok(());
+        }
+        if self
+            .state
+            .flush_audio
+            .load(std::sync::atomic::Ordering::Acquire)
+        {
+            self.flush_stalled_audio_output()?;
+        }
+        if self
+            .state
+            .flush_video
+            .load(std::sync::atomic::Ordering::Acquire)
+        {
+            log::warn!("The frame queue wasn't flushed in time, stale frames might still play");
+        }
+
+        Ok(())
+    }
+
+    /// Takes the renderer back from a sound card that stopped calling back and empties its queue
+    /// ourselves, then plays it on a new stream
+    fn flush_stalled_audio_output(&mut self) -> Result<(), MediaDecoderError> {
+        // the other sinks render from their own thread, and a WAV file can't be opened again
+        if !matches!(self.options.audio_output, AudioOutput::Cpal(_)) {
+            log::warn!("The audio queue wasn't flushed in time");
+            return Ok(());
+        }
+
+        log::warn!("The audio output stopped playing, restarting it");
+        let Some(mut renderer) = self.audio_output.take().and_then(|output| output.stop()) else {
+            self.audio_producer = None;
+            return Ok(());
+        };
+        renderer.flush();
+
+        match self
+            .options
+            .audio_output
+            .create_sink(self.output_format, self.options.pacing)
+        {
+            Ok(mut sink) => {
+                sink.start(renderer)?;
+                self.audio_output = Some(sink);
+                Ok(())
+            }
+            Err(err) => self.play_without_sound(renderer, err),
+        }
+    }
+
+    /// Waits until the threads that own the queues cleared `flags`, returns false when they
+    /// didn't get to it in time. Once the decoder is closed there is no one left to wait for
+    fn wait_for_flush(&self, flags: &[&AtomicBool]) -> bool {
+        let started = std::time::Instant::now();
+        while flags
+            .iter()
+            .any(|flag| flag.load(std::sync::atomic::Ordering::Acquire))
+        {
+            if self.state.closed.load(std::sync::atomic::Ordering::Acquire) {
+                return true;
+            }
+            if started.elapsed() >= FLUSH_TIMEOUT {
+                return false;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        true
+    }
+
+    /// Shows the cues of an SRT, ASS or WebVTT file instead of the embedded subtitles
+    pub fn add_subtitle_file(&mut self, path: impl AsRef<Path>) -> Result<(), MediaDecoderError> {
+        let mut external_subtitles = ExternalSubtitles::load(path.as_ref())?;
+
+        // the cues are all in memory, so unlike a stream there is no need to seek back
+        self.source.subtitles = None;
+        self.flush_subtitle_queue();
+        external_subtitles.rewind(self.get_position());
+        self.source.external_subtitles = Some(external_subtitles);
+
+        (self.event_callback)(MediaEvents::SubtitleTrackSelected(None));
+        (self.event_callback)(MediaEvents::SubtitleFileLoaded(path.as_ref().to_path_buf()));
+
+        Ok(())
+    }
+
+    /// Asks the presentation thread to drop the queued and shown subtitles and waits until it did
+    fn flush_subtitle_queue(&self) {
+        self.state
+            .flush_subtitles
+            .store(true, std::sync::atomic::Ordering::Release);
+        if !self.wait_for_flush(&[&self.state.flush_subtitles]) {
+            log::warn!("The subtitle queue wasn't flushed in time");
+        }
+    }
+
+    /// Queues as many cues of the subtitle file as there is room for
+    fn queue_external_subtitles(&mut self) {
+        let position_offset = self.source.position_offset();
+        let Some(external_subtitles) = self.source.external_subtitles.as_mut() else {
+            return;
+        };
+
+        while let Some(subtitle) = external_subtitles.peek(position_offset) {
+            if self.subtitle_producer.push(subtitle).is_err() {
+                break;
+            }
+            external_subtitles.next += 1;
+        }
+    }
+
+    /// Replaces the audio decoder and continues from the current position
+    fn select_audio_track(&mut self, index: usize) -> Result<(), MediaDecoderError> {
+        let Some(stream_index) = self.source.audio.as_ref().and(
+            self.source
+                .get_track_stream(index, AVMediaType::AVMEDIA_TYPE_AUDIO),
+        ) else {
+            (self.event_callback)(MediaEvents::Error(MediaDecoderError::InvalidTrack(index)));
+            return Ok(());
+        };
+        if self
+            .source
+            .audio
+            .as_ref()
+            .is_some_and(|audio| audio.stream_index == stream_index)
+        {
+            return Ok(());
+        }
+
+        let decoder = match AudioDecoder::new(
+            "audio_decoder".to_string(),
+            &self.source.format_context,
+            stream_index,
+        ) {
+            Ok(decoder) => decoder,
+            Err(err) => {
+                (self.event_callback)(MediaEvents::Error(MediaDecoderError::AudioDecoder(err)));
+                return Ok(());
+            }
+        };
+
+        let replay_gain = get_replay_gain(
+            &self.source.format_context,
+            stream_index,
+            self.options.replay_gain,
+        );
+        self.state
+            .replay_gain
+            .store(replay_gain.to_bits(), std::sync::atomic::Ordering::Release);
+        if let Some(audio) = self.source.audio.as_mut() {
+            audio.decoder = decoder;
+            audio.stream_index = stream_index;
+        }
+
+        // seeking rebuilds the filter graph and drops the samples of the old track
+        self.seek(self.get_position(), SeekMode::Accurate)?;
+        (self.event_callback)(MediaEvents::TrackSelected {
+            media_type: AVMediaType::AVMEDIA_TYPE_AUDIO,
+            index,
+        });
+
+        Ok(())
+    }
+
+    /// Replaces the subtitle decoder, the subtitles around the current position were already
+    /// demuxed so we seek back to get them again
+    fn select_subtitle_track(&mut self, index: Option<usize>) -> Result<(), MediaDecoderError> {
+        let subtitles = match index {
+            Some(index) => {
+                let Some(stream_index) = self
+                    .source
+                    .get_track_stream(index, AVMediaType::AVMEDIA_TYPE_SUBTITLE)
+                else {
+                    (self.event_callback)(MediaEvents::Error(MediaDecoderError::InvalidTrack(
+                        index,
+                    )));
+                    return Ok(());
+                };
+                match SubtitleTrack::new(&self.source.format_context, stream_index) {
+                    Ok(subtitles) => Some(subtitles),
+                    Err(err) => {
+                        (self.event_callback)(MediaEvents::Error(err));
+                        return Ok(());
+                    }
+                }
+            }
+            None => None,
+        };
+        self.source.subtitles = subtitles;
+        self.source.external_subtitles = None;
+
+        self.seek(self.get_position(), SeekMode::Accurate)?;
+        (self.event_callback)(MediaEvents::SubtitleTrackSelected(index));
+
+        Ok(())
+    }
+
+    /// Replaces the video decoder and continues from the current position
+    fn select_video_track(&mut self, index: usize) -> Result<(), MediaDecoderError> {
+        let Some(stream_index) = self
+            .source
+            .video
+            .as_ref()
+            .filter(|video| !video.attached_picture)
+            .and(
+                self.source
+                    .get_track_stream(index, AVMediaType::AVMEDIA_TYPE_VIDEO),
+            )
+        else {
+            (self.event_callback)(MediaEvents::Error(MediaDecoderError::InvalidTrack(index)));
+            return Ok(());
+        };
+        if self
+            .source
+            .video
+            .as_ref()
+            .is_some_and(|video| video.stream_index == stream_index)
+        {
+            return Ok(());
+        }
+
+        let decoder = match VideoDecoder::new(
+            "video_decoder".to_string(),
+            &self.source.format_context,
+            stream_index,
+            self.options.use_hw_accel,
+        ) {
+            Ok(decoder) => decoder,
+            Err(err) => {
+                (self.event_callback)(MediaEvents::Error(MediaDecoderError::VideoDecoder(err)));
+                return Ok(());
+            }
+        };
+
+        *self.state.frame_rate.lock().unwrap() =
+            get_frame_rate(&self.source.format_context, stream_index);
+        if let Some(video) = self.source.video.as_mut() {
+            video.decoder = decoder;
+            video.stream_index = stream_index;
+        }
+
+        self.seek(self.get_position(), SeekMode::Accurate)?;
+
+        // the frame queue is empty now, so the renderer gets this before any frame of the new track
+        if let Some(event) = self.source.get_video_size_event() {
+            (self.event_callback)(event);
+        }
+        (self.event_callback)(MediaEvents::TrackSelected {
+            media_type: AVMediaType::AVMEDIA_TYPE_VIDEO,
+            index,
+        });
+
+        Ok(())
+    }
+
+    /// Position in the current item in nanoseconds
+    fn get_position(&self) -> i64 {
+        let clock = self
+            .state
+            .audio_clock
+            .load(std::sync::atomic::Ordering::Acquire);
+
+        // the offset of the item moves ahead when looping, before playback gets there
+        (clock - self.state.item_offset()).max(0)
+    }
+
+    /// Whether everything that was decoded has been played
+    fn is_drained(&self) -> bool {
+        self.video_producer.is_empty()
+            && self
+                .audio_producer
+                .as_ref()
+                .map_or(true, |producer| producer.is_empty())
+    }
+
+    fn get_playlist_event(&self) -> MediaEvents {
+        MediaEvents::Playlist {
+            items: self.playlist.items().to_vec(),
+            current: self.playlist.current_index().unwrap_or(0),
+            shuffle: self.playlist.shuffle(),
+            repeat: self.playlist.repeat(),
+        }
+    }
+
+    /// Everything the UI and the renderer need to know about the current item
+    fn get_item_events(&self) -> Vec<MediaEvents> {
+        let mut events = vec![MediaEvents::Loaded {
+            duration: self.source.get_duration(),
+            streams: self.source.get_streams(),
+        }];
+        events.extend(self.source.get_selected_tracks());
+        events.push(
+            self.source
+                .get_video_size_event()
+                .unwrap_or(MediaEvents::AudioOnly),
+        );
+        events.push(self.get_playlist_event());
+
+        events
+    }
+
+    fn open_source(&self, position: usize) -> Result<MediaSource, MediaDecoderError> {
+        let path_or_url = self.playlist.get(position).unwrap_or_default();
+
+        MediaSource::open(
+            path_or_url,
+            None,
+            &self.options,
+            self.output_format,
+            self.state.rate(),
+            &*self.event_callback,
+        )
+        .map_err(|err| {
+            log::error!("Failed to open {}: {}", path_or_url, err);
+            err
+        })
+    }
+
+    /// Opens the item that plays after the current one ahead of time, so it can follow without
+    /// waiting on the disk or network
+    fn prepare_next_source(&mut self) {
+        if self.next_prepared {
+            return;
+        }
+        self.next_prepared = true;
+
+        // items that fail to open are skipped
+        let mut position = self.playlist.position();
+        for _ in 0..self.playlist.len() {
+            let Some(next) = self.playlist.following(position) else {
+                return;
+            };
+            if let Ok(source) = self.open_source(next) {
+                self.next_source = Some((next, source));
+                return;
+            }
+            position = next;
+        }
+    }
+
+    /// Jumps to the playlist item at `position` right away
+    fn play_position(&mut self, position: Option<usize>) -> Result<(), MediaDecoderError> {
+        let Some(position) = position else {
+            return Ok(());
+        };
+
+        let source = match self.next_source.take() {
+            Some((next_position, source)) if next_position == position => source,
+            _ => match self.open_source(position) {
+                Ok(source) => source,
+                Err(err) => {
+                    (self.event_callback)(MediaEvents::Error(err));
+                    return Ok(());
+                }
+            },
+        };
+
+        self.switch_source(position, source, false)
+    }
+
+    /// Makes `source` the item that is decoded. A gapless switch continues the clock after the
+    /// current item and leaves its end in the queues, otherwise the queues are flushed and the
+    /// clock starts over.
+    fn switch_source(
+        &mut self,
+        position: usize,
+        mut source: MediaSource,
+        gapless: bool,
+    ) -> Result<(), MediaDecoderError> {
+        log::info!("Playing {}", source.path);
+        let previous_position = self.playlist.position();
+        self.playlist.set_position(position);
+        self.next_source = None;
+        self.next_prepared = false;
+        self.seek_target = None;
+        self.loop_end_reached = None;
+        // A and B belong to the item that was playing
+        if let LoopMode::Range { .. } = self.loop_mode {
+            self.loop_mode = LoopMode::Off;
+            (self.event_callback)(MediaEvents::Loop(LoopMode::Off));
+        }
+
+        if gapless {
+            let start = self.source.end;
+            source.offset = start - source.start_time;
+            source.end = start;
+            let previous = std::mem::replace(&mut self.source, source);
+            self.previous_source = Some((previous_position, previous));
+
+            *self.state.pending_item.lock().unwrap() = Some(PendingItem {
+                start,
+                offset: self.source.position_offset(),
+                replay_gain: self.source.replay_gain,
+                frame_rate: self.source.get_frame_rate(),
+                events: self.get_item_events(),
+            });
+        } else {
+            self.previous_source = None;
+            *self.state.pending_item.lock().unwrap() = None;
+            self.source = source;
+
+            if self.source.audio.is_some() && self.audio_producer.is_none() {
+                let (producer, output) = create_audio_output(
+                    &self.options.audio_output,
+                    self.options.pacing,
+                    self.output_format,
+                    self.state.clone(),
+                )?;
+                self.audio_producer = Some(producer);
+                self.audio_output = Some(output);
+            }
+            self.state.has_audio.store(
+                self.source.audio.is_some(),
+                std::sync::atomic::Ordering::Release,
+            );
+            self.state.continuous_video.store(
+                self.source.has_continuous_video(),
+                std::sync::atomic::Ordering::Release,
+            );
+            self.flush_queues()?;
+            self.state
+                .audio_clock
+                .store(0, std::sync::atomic::Ordering::Release);
+
+            enter_item(
+                &self.state,
+                &*self.event_callback,
+                PendingItem {
+                    start: 0,
+                    offset: self.source.position_offset(),
+                    replay_gain: self.source.replay_gain,
+                    frame_rate: self.source.get_frame_rate(),
+                    events: self.get_item_events(),
+                },
+            );
+        }
+
+        self.state
+            .end_of_stream
+            .store(false, std::sync::atomic::Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Goes back to the item that is still playing from the queues when the decoder already moved
+    /// on to the next one, seeking drops what was queued of that next item
+    fn restore_previous_source(&mut self) -> Result<(), MediaDecoderError> {
+        let Some((position, previous)) = self.previous_source.take() else {
+            return Ok(());
+        };
+        // playback might have just gotten to the next item
+        if self.state.pending_item.lock().unwrap().take().is_none() {
+            return Ok(());
+        }
+
+        self.source = previous;
+        self.playlist.set_position(position);
+        self.next_source = None;
+        self.next_prepared = false;
+        self.seek(self.get_position(), SeekMode::Accurate)
+    }
+
+    /// Decodes until the decoder is closed or replaced, returns the paths or URLs that replace it
+    pub fn start(&mut self) -> Result<Option<Vec<String>>, MediaDecoderError> {
+        let result = self.run();
+
+        self.state
+            .closed
+            .store(true, std::sync::atomic::Ordering::Release);
+        (self.event_callback)(MediaEvents::Closed);
+
+        result
+    }
+
+    fn run(&mut self) -> Result<Option<Vec<String>>, MediaDecoderError> {
+        for event in self.get_item_events() {
+            (self.event_callback)(event);
+        }
+        if let AudioOutput::Cpal(device) = &self.options.audio_output {
+            (self.event_callback)(MediaEvents::AudioDevice(device.clone()));
+        }
+
+        loop {
+            while let Ok(command) = self.decoder_command_receiver.try_recv() {
+                // commands are about the item that is playing, not the one we already moved on to
+                self.restore_previous_source()?;
+
+                // a command that fails leaves the item playing as it was, the error is only reported
+                let result = match command {
+                    MediaCommands::Seek(pts, mode) => self.seek(pts, mode),
+                    // the queued samples keep the old tempo and their own durations, so the clock
+                    // stays right and the new tempo follows them without a seek
+                    MediaCommands::SetRate(_) => {
+                        // the next item's audio graph still has the old tempo
+                        self.next_source = None;
+                        self.next_prepared = false;
+                        self.rebuild_audio_graph()
+                    }
+                    MediaCommands::StepBackward => self.step_backward(),
+                    MediaCommands::SelectAudioTrack(index) => self.select_audio_track(index),
+                    MediaCommands::SelectVideoTrack(index) => self.select_video_track(index),
+                    MediaCommands::SelectSubtitleTrack(index) => self.select_subtitle_track(index),
+                    MediaCommands::LoadSubtitleFile(path) => self.add_subtitle_file(&path),
+                    MediaCommands::Next => self.play_position(self.playlist.next_position()),
+                    MediaCommands::Previous => {
+                        if self.get_position() > RESTART_THRESHOLD {
+                            self.seek(0, SeekMode::Keyframe)
+                        } else {
+                            self.play_position(self.playlist.previous_position())
+                        }
+                    }
+                    MediaCommands::SetShuffle(shuffle) => {
+                        self.playlist.set_shuffle(shuffle);
+                        self.next_source = None;
+                        self.next_prepared = false;
+                        (self.event_callback)(self.get_playlist_event());
+                        Ok(())
+                    }
+                    MediaCommands::SetRepeat(repeat) => {
+                        self.playlist.set_repeat(repeat);
+                        self.next_source = None;
+                        self.next_prepared = false;
+                        (self.event_callback)(self.get_playlist_event());
+                        Ok(())
+                    }
+                    MediaCommands::Enqueue(paths) => {
+                        self.playlist.append(paths);
+                        // the playlist might not have ended after all
+                        if self.next_source.is_none() {
+                            self.next_prepared = false;
+                        }
+                        (self.event_callback)(self.get_playlist_event());
+                        Ok(())
+                    }
+                    MediaCommands::SetAudioDevice(device) => self.set_audio_device(device),
+                    MediaCommands::SetLoop(mode) => self.set_loop(mode),
+                    MediaCommands::Open(paths) => return Ok(Some(paths)),
+                    MediaCommands::Close => return Ok(None),
+                    _ => Ok(()),
+                };
+                if let Err(err) = result {
+                    log::error!("Command failed: {}", err);
+                    (self.event_callback)(MediaEvents::Error(err));
+                }
+            }
+            if self
+                .audio_output
+                .as_ref()
+                .is_some_and(|output| output.is_lost())
+            {
+                log::warn!("The audio device went away, switching to the default one");
+                self.set_audio_device(None)?;
+            }
+            if let Some(end) = self.loop_end_reached {
+                // a round shorter than the queues has to wait for the one before it to start
+                if self.state.pending_item.lock().unwrap().is_some() {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    continue;
+                }
+                self.loop_end_reached = None;
+                self.loop_back(end)?;
+            }
+            self.queue_external_subtitles();
+
+            // playback got to the next item, so the previous one is done
+            if self.previous_source.is_some() && self.state.pending_item.lock().unwrap().is_none() {
+                self.previous_source = None;
+            }
+            self.prepare_next_source();
+
+            // keep serving commands after the end so we can still seek back
+            if self
+                .state
+                .end_of_stream
+                .load(std::sync::atomic::Ordering::Acquire)
+            {
+                if let Some((_, next_source)) = self.next_source.as_ref() {
+                    // items that can't share the clock wait until the current one finished playing
+                    let gapless =
+                        self.previous_source.is_none() && self.source.is_compatible(next_source);
+                    let drained = self.is_drained();
+                    let paused = self.state.paused.load(std::sync::atomic::Ordering::Acquire);
+                    if gapless || (drained && !paused) {
+                        let (position, source) = self.next_source.take().unwrap();
+                        self.switch_source(position, source, gapless)?;
+                        continue;
+                    }
+                } else if self.next_prepared && !self.finished && self.is_drained() {
+                    self.finished = true;
+                    (self.event_callback)(MediaEvents::Finished);
+                }
+
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                continue;
+            }
+            self.finished = false;
+
+            // while paused we only decode to serve a frame step, audio gets resynced on play
+            let stepping = self
+                .state
+                .pending_steps
+                .load(std::sync::atomic::Ordering::Acquire)
+                > 0
+                && self.video_producer.is_empty();
+            let paused = self.state.paused.load(std::sync::atomic::Ordering::Acquire);
+            if paused && !stepping {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                continue;
+            }
+
+            if self.video_producer.len() >= VIDEO_FRAME_QUEUE_SIZE
+                || (!paused
+                    && self.audio_producer.as_ref().is_some_and(|producer| {
+                        producer.queued_duration() >= AUDIO_QUEUE_DURATION || producer.is_full()
+                    }))
+            {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                continue;
+            }
+
+            let Ok(packet) = self.source.format_context.next_packet() else {
+                // a loop that ends after the item goes back from wherever it ended
+                if self.loop_mode != LoopMode::Off {
+                    self.loop_end_reached = Some(self.source.end - self.source.offset);
+                    continue;
+                }
+                log::debug!("Reached end of stream");
+                self.state
+                    .end_of_stream
+                    .store(true, std::sync::atomic::Ordering::Release);
+                continue;
+            };
+
+            let stream_index = packet.get_stream_index();
+            // audio decides when the end is reached, it can't skip a frame like video can
+            let clock_stream = match (self.source.audio.as_ref(), self.source.video.as_ref()) {
+                (Some(audio), _) => Some(audio.stream_index),
+                (None, Some(video)) => Some(video.stream_index),
+                (None, None) => None,
+            };
+            if let LoopMode::Range { end, .. } = self.loop_mode {
+                if clock_stream == Some(stream_index)
+                    && self
+                        .get_packet_pts(&packet)
+                        .is_some_and(|pts| pts >= end + self.source.start_time)
+                {
+                    self.loop_end_reached = Some(end + self.source.start_time);
+                    continue;
+                }
+            }
+            if self
+                .source
+                .video
+                .as_ref()
+                .is_some_and(|video| video.stream_index == stream_index)
+            {
+                self.decode_video_packet(&packet)?;
+            }
+            if !paused
+                && self
+                    .source
+                    .audio
+                    .as_ref()
+                    .is_some_and(|audio| audio.stream_index == stream_index)
+            {
+                self.decode_audio_packet(&packet)?;
+            }
+            if self
+                .source
+                .subtitles
+                .as_ref()
+                .is_some_and(|subtitles| subtitles.stream_index == stream_index)
+            {
+                self.decode_subtitle_packet(&packet);
+            }
+        }
+    }
+
+    /// Shows the frame before the one on screen by decoding from the keyframe before it
+    fn step_backward(&mut self) -> Result<(), MediaDecoderError> {
+        let Some(stream_index) = self
+            .source
+            .video
+            .as_ref()
+            .filter(|video| !video.attached_picture)
+            .map(|video| video.stream_index)
+        else {
+            return Ok(());
+        };
+
+        let current = self
+            .state
+            .displayed_pts
+            .load(std::sync::atomic::Ordering::Acquire);
+        // a microsecond earlier, so a frame on a keyframe lands us on the keyframe before it
+        self.seek(
+            current - self.source.position_offset() - 1000,
+            SeekMode::Keyframe,
+        )?;
+
+        let mut previous = None;
+        let mut following = vec![];
+        while following.is_empty() {
+            let Ok(packet) = self.source.format_context.next_packet() else {
+                break;
+            };
+            if packet.get_stream_index() != stream_index {
+                continue;
+            }
+
+            match self.decode_video_frame(&packet)? {
+                Some(frame) if frame.pts < current => previous = Some(frame),
+                Some(frame) => following.push(frame),
+                None => (),
+            }
+        }
+
+        // already at the first frame, show it again
+        for frame in previous.into_iter().chain(following) {
+            if self.video_producer.push(frame).is_err() {
+                log::warn!("The frame queue is full, dropping a frame");
+            }
+        }
+        self.state
+            .pending_steps
+            .store(1, std::sync::atomic::Ordering::Release);
+
+        Ok(())
+    }
+
+    fn decode_video_packet(&mut self, packet: &Packet) -> Result<(), MediaDecoderError> {
+        if let Some(frame) = self.decode_video_frame(packet)? {
+            // the start of the loop comes in place of what is past its end
+            if self.get_loop_end().is_some_and(|end| frame.pts >= end) {
+                return Ok(());
+            }
+            if self.video_producer.push(frame).is_err() {
+                log::warn!("The frame queue is full, dropping a frame");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn decode_video_frame(
+        &mut self,
+        packet: &Packet,
+    ) -> Result<Option<DecodedFrame>, MediaDecoderError> {
+        let Some(video) = self.source.video.as_mut() else {
+            return Ok(None);
+        };
+        let Ok(frame) = video.decoder.decode(packet) else {
+            return Ok(None);
+        };
+
+        unsafe {
+            // bet gets lost in the process
+            let bet: i64 = (*frame.frame).best_effort_timestamp;
+
+            let frames = video.get_decoded_frame(frame)?;
+            let Some(frame) = frames.first() else {
+                return Ok(None);
+            };
+
+            let pts_nano = if video.attached_picture {
+                // shown as soon as the item starts, whatever the clock says after that
+                self.source.position_offset()
+            } else {
+                let nano = av_make_q(1, ONE_NANOSECOND as i32);
+                let stream = (*self.source.format_context.get_stream(video.stream_index)).time_base;
+                let pts_nano = av_rescale_q(bet, stream, nano) + self.source.offset;
+
+                if self.seek_target.is_some_and(|target| pts_nano < target) {
+                    return Ok(None);
+                }
+
+                // the next playlist item starts where the last frame ends
+                let frame_duration =
+                    get_frame_rate(&self.source.format_context, video.stream_index)
+                        .map_or(0, |rate| {
+                            av_rescale_q(1, av_make_q(rate.den, rate.num), nano)
+                        });
+                self.source.end = self.source.end.max(pts_nano + frame_duration);
+
+                pts_nano
+            };
+
+            if let Some(metadata) = HdrMetadata::from_frame(frame.frame) {
+                video.hdr_metadata = Some(metadata);
+            }
+            let hdr_metadata = video.hdr_metadata;
+
+            let frame = *frame.frame;
+
+            log::debug!("linesize cells {:?}", frame.linesize);
+            log::debug!("data cells {:?}", frame.data);
+
+            let Some(format) =
+                PixelFormat::from_av(std::mem::transmute::<i32, AVPixelFormat>(frame.format))
+            else {
+                log::warn!(
+                    "Skipping a frame with an unknown pixel format {}",
+                    frame.format
+                );
+                return Ok(None);
+            };
+
+            let linesizes: Vec<i32> = frame.linesize[..format.planes].to_vec();
+
+            let color = ColorInfo::from_frame(&frame, &format);
+            let data = self.combine_yuv_to_vec(frame, &format);
+            log::debug!("color_data {:?}", data.len());
+
+            Ok(Some(DecodedFrame {
+                width: frame.width as u32,
+                height: frame.height as u32,
+                format,
+                color,
+                hdr_metadata,
+                data,
+                linesizes,
+                pts: pts_nano,
+            }))
+        }
+    }
+
+    fn decode_subtitle_packet(&mut self, packet: &Packet) {
+        let Some(subtitles) = self.source.subtitles.as_ref() else {
+            return;
+        };
+
+        unsafe {
+            let mut subtitle: AVSubtitle = std::mem::zeroed();
+            let mut got_subtitle = 0;
+            let ret = avcodec_decode_subtitle2(
+                subtitles.codec_context,
+                &mut subtitle,
+                &mut got_subtitle,
+                packet.packet,
+            );
+            if ret < 0 || got_subtitle == 0 {
+                return;
+            }
+
+            let nano = av_make_q(1, ONE_NANOSECOND as i32);
+            let time_base = (*self
+                .source
+                .format_context
+                .get_stream(subtitles.stream_index))
+            .time_base;
+            let pts = if (*packet.packet).pts != AV_NOPTS_VALUE {
+                av_rescale_q((*packet.packet).pts, time_base, nano)
+            } else {
+                av_rescale_q(subtitle.pts, av_make_q(1, AV_TIME_BASE as i32), nano)
+            } + self.source.offset;
+            // bitmap formats like PGS only end when the next, often empty, subtitle comes in
+            let end = (subtitle.end_display_time != u32::MAX
+                && subtitle.end_display_time > subtitle.start_display_time)
+                .then(|| pts + subtitle.end_display_time as i64 * 1_000_000);
+
+            // bitmaps are positioned in the size the subtitles were authored for
+            let canvas = match (
+                (*subtitles.codec_context).width,
+                (*subtitles.codec_context).height,
+            ) {
+                (width, height) if width > 0 && height > 0 => (width as u32, height as u32),
+                _ => self.source.get_video_size().unwrap_or((0, 0)),
+            };
+
+            let mut text = vec![];
+            let mut bitmaps = vec![];
+            let rects = if subtitle.rects.is_null() {
+                &[][..]
+            } else {
+                slice::from_raw_parts(subtitle.rects, subtitle.num_rects as usize)
+            };
+            for rect in rects.iter().map(|rect| &**rect) {
+                match rect.type_ {
+                    AVSubtitleType::SUBTITLE_BITMAP => bitmaps.push(get_subtitle_bitmap(rect)),
+                    AVSubtitleType::SUBTITLE_TEXT if !rect.text.is_null() => {
+                        text.extend(
+                            CStr::from_ptr(rect.text)
+                                .to_string_lossy()
+                                .lines()
+                                .map(str::to_string),
+                        );
+                    }
+                    AVSubtitleType::SUBTITLE_ASS if !rect.ass.is_null() => {
+                        text.extend(
+                            strip_ass_dialogue(&CStr::from_ptr(rect.ass).to_string_lossy())
+                                .lines()
+                                .map(str::to_string),
+                        );
+                    }
+                    _ => (),
+                }
+            }
+            let start = pts + subtitle.start_display_time as i64 * 1_000_000;
+            avsubtitle_free(&mut subtitle);
+
+            let subtitle = Subtitle {
+                start,
+                end,
+                text,
+                bitmaps,
+                canvas,
+            };
+            if self.subtitle_producer.push(subtitle).is_err() {
+                log::warn!("Subtitle queue is full, dropping subtitle");
+            }
+        }
+    }
+
+    fn decode_audio_packet(&mut self, packet: &Packet) -> Result<(), MediaDecoderError> {
+        let Some(audio) = self.source.audio.as_mut() else {
+            return Ok(());
+        };
+        let Ok(frame) = audio.decoder.decode(packet) else {
+            return Ok(());
+        };
+
+        // the tempo filter restamps its output, so we take the pts from the decoded frame
+        let (pts_nano, frame_end) = unsafe {
+            let stream = (*self.source.format_context.get_stream(audio.stream_index)).time_base;
+            let pts = av_rescale_q(
+                (*frame.frame).best_effort_timestamp,
+                stream,
+                av_make_q(1, ONE_NANOSECOND as i32),
+            ) + self.source.offset;
+            let duration = av_rescale_q(
+                (*frame.frame).nb_samples as i64,
+                av_make_q(1, (*frame.frame).sample_rate),
+                av_make_q(1, ONE_NANOSECOND as i32),
+            );
+            (pts, pts + duration)
+        };
+
+        let (frames, _) = audio
+            .graph
+            .process(&[frame], &[])
+            .map_err(MediaDecoderError::FilterGraph)?;
+
+        // nanoseconds of media time covered by a single output sample
+        let sample_duration = self.state.rate() as f64 * ONE_NANOSECOND as f64
+            / self.output_format.sample_rate as f64;
+        let sample_size = self.output_format.sample_format.sample_size();
+
+        // the graph returns what it held back first, so the output starts that much before the
+        // frame we just sent in
+        let output_samples: usize = frames
+            .iter()
+            .map(|frame| unsafe { (*frame.frame).nb_samples as usize })
+            .sum();
+        audio.buffered += frame_end - pts_nano;
+        let output_start = frame_end - audio.buffered;
+        audio.buffered = (audio.buffered - (output_samples as f64 * sample_duration) as i64).max(0);
+        let mut samples_before = 0;
+
+        for frame in frames.iter() {
+            unsafe {
+                let frame = frame.frame;
+                let channels = (*frame).channels as usize;
+                let size = channels * (*frame).nb_samples as usize;
+                // interleaved samples in the format of the queue, as `aformat` asked for
+                let data: &[u8] = slice::from_raw_parts((*frame).data[0], size * sample_size);
+
+                let frame_pts = output_start + (samples_before as f64 * sample_duration) as i64;
+                samples_before += (*frame).nb_samples as usize;
+                self.source.end = self
+                    .source
+                    .end
+                    .max(output_start + (samples_before as f64 * sample_duration) as i64);
+
+                // when seeking accurately, drop the samples that come before the target
+                let mut skip = 0;
+                if let Some(target) = self.seek_target {
+                    if frame_pts < target {
+                        let samples =
+                            ((target - frame_pts) as f64 / sample_duration) as usize * channels;
+                        if samples >= size {
+                            continue;
+                        }
+                        skip = samples;
+                    }
+                }
+
+                let mut samples = &data[skip * sample_size..];
+                let first_pts = frame_pts + ((skip / channels) as f64 * sample_duration) as i64;
+                if let Some(end) = self.get_loop_end() {
+                    let frames_left = ((end - first_pts) as f64 / sample_duration).max(0.0);
+                    samples = &samples[..samples
+                        .len()
+                        .min(frames_left as usize * channels * sample_size)];
+                }
+
+                if let Some(producer) = self.audio_producer.as_mut() {
+                    if !producer.push(first_pts, sample_duration, samples) {
+                        log::warn!(
+                            "Audio queue is full, dropped {} samples",
+                            samples.len() / sample_size
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub unsafe fn combine_yuv_to_vec(&self, frame: AVFrame, format: &PixelFormat) -> FrameBuffer {
+        let plane_sizes: Vec<usize> = (0..format.planes)
+            .map(|plane| {
+                let (_, rows) = format.plane_size(plane, frame.width as u32, frame.height as u32);
+                frame.linesize[plane] as usize * rows as usize
+            })
+            .collect();
+
+        log::debug!("plane sizes {:?}", plane_sizes);
+
+        let mut vec = self.frame_pool.get(plane_sizes.iter().sum());
+        for (plane, size) in plane_sizes.iter().enumerate() {
+            vec.extend_from_slice(slice::from_raw_parts(frame.data[plane], *size));
+        }
+
+        let shift = format.alignment_shift();
+        if shift > 0 {
+            for sample in vec.chunks_exact_mut(2) {
+                let value = u16::from_le_bytes([sample[0], sample[1]]) << shift;
+                sample.copy_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        vec
+    }
+}
+
+impl Drop for MediaDecoder {
+    /// Stops the presentation thread, the command thread stops once every sender is dropped
+    /// and the audio sink when `audio_output` is
+    fn drop(&mut self) {
+        self.state
+            .closed
+            .store(true, std::sync::atomic::Ordering::Release);
+    }
+}
+
+/// How long it takes the gain to go from silent to full volume
+const GAIN_RAMP_DURATION: f32 = 0.01;
+
+/// Converts the palette based bitmap of PGS, DVB and VobSub subtitles to RGBA
+unsafe fn get_subtitle_bitmap(rect: &AVSubtitleRect) -> SubtitleBitmap {
+    let (width, height) = (rect.w.max(0) as usize, rect.h.max(0) as usize);
+    let palette = slice::from_raw_parts(rect.data[1] as *const u32, 256);
+    let mut data = Vec::with_capacity(width * height * 4);
+    for y in 0..height {
+        let row = slice::from_raw_parts(rect.data[0].add(y * rect.linesize[0] as usize), width);
+        for index in row {
+            // stored as 0xAARRGGBB
+            let color = palette[*index as usize];
+            data.extend_from_slice(&[
+                (color >> 16) as u8,
+                (color >> 8) as u8,
+                color as u8,
+                (color >> 24) as u8,
+            ]);
+        }
+    }
+
+    SubtitleBitmap {
+        x: rect.x.max(0) as u32,
+        y: rect.y.max(0) as u32,
+        width: width as u32,
+        height: height as u32,
+        data,
+    }
+}
+
+/// Takes the text of an ASS dialogue line, dropping the override tags like `{\i1}`
+fn strip_ass_dialogue(line: &str) -> String {
+    // "ReadOrder,Layer,Style,Name,MarginL,MarginR,MarginV,Effect,Text", older ffmpeg versions
+    // still hand us the full "Dialogue: Layer,Start,End,..." line
+    let text = match line.strip_prefix("Dialogue:") {
+        Some(line) => line.splitn(10, ',').nth(9),
+        None => line.splitn(9, ',').nth(8),
+    }
+    .unwrap_or_default();
+
+    strip_ass_tags(text)
+}
+
+/// Makes `item` the one that is playing, the positions we report are relative to it from here on
+fn enter_item(state: &MediaState, event_callback: &dyn Fn(MediaEvents), item: PendingItem) {
+    state
+        .item_offset
+        .store(item.offset, std::sync::atomic::Ordering::Release);
+    state.replay_gain.store(
+        item.replay_gain.to_bits(),
+        std::sync::atomic::Ordering::Release,
+    );
+    *state.frame_rate.lock().unwrap() = item.frame_rate;
+
+    for event in item.events {
+        event_callback(event);
+    }
+}
+
+/// Handles the commands that only change the shared state, the ones that need the demuxer or the
+/// decoders are forwarded to the decode loop
+fn run_commands(
+    command_receiver: crossbeam_channel::Receiver<MediaCommands>,
+    decoder_command_sender: crossbeam_channel::Sender<MediaCommands>,
+    state: &MediaState,
+    event_callback: &dyn Fn(MediaEvents),
+    mut volume: f32,
+    mut muted: bool,
+) {
+    event_callback(MediaEvents::Volume { volume, muted });
+    event_callback(MediaEvents::Rate(state.rate()));
+
+    while let Ok(command) = command_receiver.recv() {
+        // the decode loop is gone, commands sent before the UI noticed are dropped
+        if state.closed.load(std::sync::atomic::Ordering::Acquire) {
+            continue;
+        }
+
+        let command = match command {
+            MediaCommands::Pause => {
+                state
+                    .paused
+                    .store(true, std::sync::atomic::Ordering::Release);
+                event_callback(MediaEvents::Paused);
+                None
+            }
+            MediaCommands::Play => {
+                // the audio queue is still where we paused, so continue from the frame on screen
+                let resync = state
+                    .stepped
+                    .swap(false, std::sync::atomic::Ordering::AcqRel)
+                    .then(|| {
+                        state
+                            .pending_steps
+                            .store(0, std::sync::atomic::Ordering::Release);
+                        let current = state
+                            .displayed_pts
+                            .load(std::sync::atomic::Ordering::Acquire)
+                            - state.item_offset();
+                        MediaCommands::Seek(current, SeekMode::Accurate)
+                    });
+                state
+                    .paused
+                    .store(false, std::sync::atomic::Ordering::Release);
+                event_callback(MediaEvents::Playing);
+                resync
+            }
+            MediaCommands::StepForward => {
+                if state.paused.load(std::sync::atomic::Ordering::Acquire) {
+                    state
+                        .stepped
+                        .store(true, std::sync::atomic::Ordering::Release);
+                    state
+                        .pending_steps
+                        .fetch_add(1, std::sync::atomic::Ordering::AcqRel);
+                }
+                None
+            }
+            MediaCommands::StepBackward => {
+                let paused = state.paused.load(std::sync::atomic::Ordering::Acquire);
+                if paused {
+                    state
+                        .stepped
+                        .store(true, std::sync::atomic::Ordering::Release);
+                }
+                paused.then_some(MediaCommands::StepBackward)
+            }
+            MediaCommands::SeekRelative(offset, mode) => {
+                let current = state.audio_clock.load(std::sync::atomic::Ordering::Acquire)
+                    - state.item_offset();
+                Some(MediaCommands::Seek((current + offset).max(0), mode))
+            }
+            MediaCommands::SetVolume(new_volume) => {
+                volume = new_volume.clamp(0.0, 1.0);
+                update_gain(state, volume, muted);
+                event_callback(MediaEvents::Volume { volume, muted });
+                None
+            }
+            MediaCommands::SetMuted(new_muted) => {
+                muted = new_muted;
+                update_gain(state, volume, muted);
+                event_callback(MediaEvents::Volume { volume, muted });
+                None
+            }
+            MediaCommands::SetRate(rate) => {
+                let rate = rate.clamp(MIN_RATE, MAX_RATE);
+                state
+                    .rate
+                    .store(rate.to_bits(), std::sync::atomic::Ordering::Release);
+                event_callback(MediaEvents::Rate(rate));
+                Some(MediaCommands::SetRate(rate))
+            }
+            // Open and Close set `closed` once the decode loop gets to them, a seek queued before
+            // them still needs the presentation thread to flush
+            command => Some(command),
+        };
+
+        // the decode loop only drops its end when the decoder is gone
+        if let Some(command) = command {
+            if decoder_command_sender.send(command).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn update_gain(state: &MediaState, volume: f32, muted: bool) {
+    let gain = if muted { 0.0 } else { volume };
+    state
+        .gain
+        .store(gain.to_bits(), std::sync::atomic::Ordering::Release);
+}
+
+/// Reads a metadata tag from the audio stream, falling back to the container
+fn get_metadata_tag(
+    format_context: &FormatContext,
+    stream_index: isize,
+    key: &str,
+) -> Option<String> {
+    get_stream_tag(format_context, stream_index, key)
+        .or_else(|| unsafe { get_dictionary_value((*format_context.format_context).metadata, key) })
+}
+
+/// Reads a metadata tag from the stream only, container tags like the title don't describe a track
+fn get_stream_tag(
+    format_context: &FormatContext,
+    stream_index: isize,
+    key: &str,
+) -> Option<String> {
+    unsafe { get_dictionary_value((*format_context.get_stream(stream_index)).metadata, key) }
+}
+
+unsafe fn get_dictionary_value(dictionary: *mut AVDictionary, key: &str) -> Option<String> {
+    let key = CString::new(key).ok()?;
+    let entry = av_dict_get(dictionary, key.as_ptr(), std::ptr::null(), 0);
+    if entry.is_null() {
+        return None;
+    }
+
+    Some(
+        CStr::from_ptr((*entry).value)
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+/// `None` when the container doesn't know the frame rate
+fn get_frame_rate(format_context: &FormatContext, stream_index: isize) -> Option<AVRational> {
+    let frame_rate = unsafe { (*format_context.get_stream(stream_index)).avg_frame_rate };
+    (frame_rate.num > 0 && frame_rate.den > 0).then_some(frame_rate)
+}
+
+/// The container's sample aspect ratio, or the codec's when the container doesn't have one
+fn get_sample_aspect_ratio(format_context: &FormatContext, stream_index: isize) -> (i32, i32) {
+    let ratio = unsafe {
+        av_guess_sample_aspect_ratio(
+            format_context.format_context,
+            format_context.get_stream(stream_index),
+            std::ptr::null_mut(),
+        )
+    };
+
+    if ratio.num > 0 && ratio.den > 0 {
+        (ratio.num, ratio.den)
+    } else {
+        (1, 1)
+    }
+}
+
+/// Clockwise rotation from the display matrix, phones store portrait video as landscape with
+/// one of these. Mirroring isn't supported
+fn get_rotation(format_context: &FormatContext, stream_index: isize) -> u32 {
+    unsafe {
+        let matrix = av_stream_get_side_data(
+            format_context.get_stream(stream_index),
+            AVPacketSideDataType::AV_PKT_DATA_DISPLAYMATRIX,
+            std::ptr::null_mut(),
+        );
+        if matrix.is_null() {
+            return 0;
+        }
+
+        // the matrix rotates counterclockwise
+        let degrees = -av_display_rotation_get(matrix as *const i32);
+        if degrees.is_nan() {
+            return 0;
+        }
+        ((degrees / 90.0).round() as i32).rem_euclid(4) as u32 * 90
+    }
+}
+
+/// Linear gain from the ReplayGain tags, limited by the peak so normalizing never clips
+fn get_replay_gain(
+    format_context: &FormatContext,
+    stream_index: isize,
+    mode: ReplayGainMode,
+) -> f32 {
+    let (gain_key, peak_key) = match mode {
+        ReplayGainMode::Off => return 1.0,
+        ReplayGainMode::Track => ("REPLAYGAIN_TRACK_GAIN", "REPLAYGAIN_TRACK_PEAK"),
+        ReplayGainMode::Album => ("REPLAYGAIN_ALBUM_GAIN", "REPLAYGAIN_ALBUM_PEAK"),
+    };
+
+    // formatted like "-6.20 dB"
+    let Some(gain_db) = get_metadata_tag(format_context, stream_index, gain_key)
+        .and_then(|tag| tag.trim().trim_end_matches("dB").trim().parse::<f32>().ok())
+    else {
+        return 1.0;
+    };
+
+    let gain = 10f32.powf(gain_db / 20.0);
+    let peak = get_metadata_tag(format_context, stream_index, peak_key)
+        .and_then(|tag| tag.trim().parse::<f32>().ok())
+        .filter(|peak| *peak > 0.0);
+
+    log::debug!("ReplayGain {} dB (peak {:?})", gain_db, peak);
+
+    match peak {
+        Some(peak) => gain.min(1.0 / peak),
+        None => gain,
+    }
+}
+
+/// The queue the decoder pushes samples into and the sink that plays them
+fn create_audio_output(
+    output: &AudioOutput,
+    pacing: Pacing,
+    format: OutputFormat,
+    state: Arc<MediaState>,
+) -> Result<(AudioProducer, Box<dyn AudioSink>), MediaDecoderError> {
+    let (producer, consumer) = audio_queue(
+        format.channels as usize,
+        format.sample_rate,
+        format.sample_format,
+        AUDIO_QUEUE_DURATION,
+    );
+    let mut sink = output.create_sink(format, pacing)?;
+    sink.start(AudioRenderer::new(
+        consumer,
+        format.channels,
+        format.sample_rate,
+        state,
+    ))?;
+
+    Ok((producer, sink))
+}
+
+/// Fills the buffers of an `AudioSink` with the decoded samples and moves the audio clock along
+pub struct AudioRenderer {
+    audio_consumer: AudioConsumer,
+    channels: usize,
+    state: Arc<MediaState>,
+    gain_step: f32,
+    current_gain: f32,
+}
+
+impl AudioRenderer {
+    fn new(
+        audio_consumer: AudioConsumer,
+        channels: ChannelCount,
+        sample_rate: u32,
+        state: Arc<MediaState>,
+    ) -> Self {
+        Self {
+            audio_consumer,
+            channels: channels as usize,
+            // the gain moves towards its target in small steps so volume changes don't click
+            gain_step: 1.0 / (GAIN_RAMP_DURATION * sample_rate as f32),
+            // a new output starts at the volume that is set instead of ramping up to it
+            current_gain: get_target_gain(&state),
+            state,
+        }
+    }
+
+    /// The format `render` has to be called with
+    pub fn sample_format(&self) -> SampleFormat {
+        self.audio_consumer.sample_format()
+    }
+
+    /// Number of samples waiting to be played
+    pub fn queued(&self) -> usize {
+        self.audio_consumer.queued()
+    }
+
+    /// Drops the queued samples after a seek
+    fn flush(&mut self) {
+        self.audio_consumer.clear();
+        self.state
+            .flush_audio
+            .store(false, std::sync::atomic::Ordering::Release);
+    }
+
+    /// Fills `data` with interleaved samples, returns how many came from the decoder, the rest
+    /// is silence. `latency` is how long it takes until the first sample is heard, the clock
+    /// follows what comes out of the speakers. `T` is the type of `sample_format`
+    pub fn render<T: Sample<Float = f32>>(
+        &mut self,
+        data: &mut [T],
+        latency: std::time::Duration,
+    ) -> usize {
+        if self
+            .state
+            .flush_audio
+            .load(std::sync::atomic::Ordering::Acquire)
+        {
+            self.flush();
+        }
+        let state = &self.state;
+        if state.paused.load(std::sync::atomic::Ordering::Acquire) {
+            data.fill(T::EQUILIBRIUM);
+            return 0;
+        }
+        let (popped, clock) = self.audio_consumer.pop(data, latency);
+        data[popped..].fill(T::EQUILIBRIUM);
+        state
+            .audio_starved
+            .store(popped < data.len(), std::sync::atomic::Ordering::Release);
+        let target_gain = get_target_gain(state);
+        for frame in data[..popped].chunks_mut(self.channels) {
+            self.current_gain +=
+                (target_gain - self.current_gain).clamp(-self.gain_step, self.gain_step);
+            for sample in frame {
+                *sample = sample.mul_amp(self.current_gain);
+            }
+        }
+
+        // zero means the clock hasn't started since the last seek
+        if let Some(clock) = clock.filter(|clock| *clock > 0) {
+            state
+                .audio_clock_updated
+                .store(state.now(), std::sync::atomic::Ordering::Release);
+            state
+                .audio_clock
+                .store(clock, std::sync::atomic::Ordering::Release);
+        }
+
+        popped
+    }
+}
+
+/// Volume, or zero when muted, times the ReplayGain of the item that is playing
+fn get_target_gain(state: &MediaState) -> f32 {
+    f32::from_bits(state.gain.load(std::sync::atomic::Ordering::Acquire))
+        * f32::from_bits(state.replay_gain.load(std::sync::atomic::Ordering::Acquire))
+}
+
+fn create_video_graph(video_decoder: &VideoDecoder) -> Result<FilterGraph, MediaDecoderError> {
+    let mut video_graph = FilterGraph::new().map_err(MediaDecoderError::FilterGraph)?;
+    video_graph
+        .add_input_from_video_decoder("source_video", video_decoder)
+        .map_err(MediaDecoderError::FilterGraph)?;
+
+    let format_filter = {
+        let mut parameters = HashMap::new();
+
+        // the decoder's own format is kept when the renderer supports it
+        let pix_fmts = SUPPORTED_PIXEL_FORMATS
+            .iter()
+            .filter_map(|format| PixelFormat::from_av(*format))
+            .map(|format| format.name())
+            .collect::<Vec<_>>()
+            .join("|");
+        log::debug!(
+            "Decoding {} into one of {}",
+            video_decoder.get_pix_fmt_name(),
+            pix_fmts
+        );
+        parameters.insert("pix_fmts".to_string(), ParameterValue::String(pix_fmts));
+
+        let filter = Filter {
+            name: "format".to_string(),
+            label: Some("Format video".to_string()),
+            parameters,
+            inputs: None,
+            outputs: None,
+        };
+
+        video_graph
+            .add_filter(&filter)
+            .map_err(MediaDecoderError::FilterGraph)?
+    };
+
+    video_graph
+        .add_video_output("main_video")
+        .map_err(MediaDecoderError::FilterGraph)?;
+
+    video_graph
+        .connect_input("source_video", 0, &format_filter, 0)
+        .map_err(MediaDecoderError::FilterGraph)?;
+    video_graph
+        .connect_output(&format_filter, 0, "main_video", 0)
+        .map_err(MediaDecoderError::FilterGraph)?;
+    video_graph
+        .validate()
+        .map_err(MediaDecoderError::FilterGraph)?;
+
+    Ok(video_graph)
+}
+
+const MIN_RATE: f32 = 0.25;
+const MAX_RATE: f32 = 4.0;
+
+/// Splits a playback rate into `atempo` factors, a single instance only handles 0.5 to 2.0
+fn get_tempo_factors(rate: f32) -> Vec<f32> {
+    let mut factors = vec![];
+    let mut remaining = rate;
+    while remaining > 2.0 {
+        factors.push(2.0);
+        remaining /= 2.0;
+    }
+    while remaining < 0.5 {
+        factors.push(0.5);
+        remaining /= 0.5;
+    }
+    if remaining != 1.0 {
+        factors.push(remaining);
+    }
+
+    factors
+}
+
+fn create_audio_graph(
+    audio_decoder: &AudioDecoder,
+    format: OutputFormat,
+    downmix: DownmixMode,
+    rate: f32,
+) -> Result<FilterGraph, MediaDecoderError> {
+    let mut audio_graph = FilterGraph::new().map_err(MediaDecoderError::FilterGraph)?;
+    audio_graph
+        .add_input_from_audio_decoder("source_audio", audio_decoder)
+        .map_err(MediaDecoderError::FilterGraph)?;
+
+    // the resampler converts to whatever `aformat` asks for, with our mixing options
+    let mut parameters = HashMap::new();
+    for (key, value) in downmix.resampler_options() {
+        parameters.insert(key.to_string(), ParameterValue::String(value.to_string()));
+    }
+    let resample_filter = audio_graph
+        .add_filter(&Filter {
+            name: "aresample".to_string(),
+            label: Some("Resample audio".to_string()),
+            parameters,
+            inputs: None,
+            outputs: None,
+        })
+        .map_err(MediaDecoderError::FilterGraph)?;
+
+    let mut parameters = HashMap::new();
+    parameters.insert(
+        "sample_rates".to_string(),
+        ParameterValue::String(format.sample_rate.to_string()),
+    );
+    parameters.insert(
+        "channel_layouts".to_string(),
+        ParameterValue::String(
+            get_channel_layout(format.channels)
+                .unwrap_or("stereo")
+                .to_string(),
+        ),
+    );
+    // interleaved samples the device plays as they are, see `OutputFormat`
+    let sample_format = match format.sample_format {
+        SampleFormat::I16 => "s16",
+        _ => "flt",
+    };
+    parameters.insert(
+        "sample_fmts".to_string(),
+        ParameterValue::String(sample_format.to_string()),
+    );
+
+    let filter = Filter {
+        name: "aformat".to_string(),
+        label: Some("Format audio samples".to_string()),
+        parameters,
+        inputs: None,
+        outputs: None,
+    };
+
+    let filter = audio_graph
+        .add_filter(&filter)
+        .map_err(MediaDecoderError::FilterGraph)?;
+    audio_graph
+        .add_audio_output("main_audio")
+        .map_err(MediaDecoderError::FilterGraph)?;
+
+    audio_graph
+        .connect_input("source_audio", 0, &resample_filter, 0)
+        .map_err(MediaDecoderError::FilterGraph)?;
+    audio_graph
+        .connect(&resample_filter, 0, &filter, 0)
+        .map_err(MediaDecoderError::FilterGraph)?;
+
+    let mut last_filter = filter;
+    for (i, factor) in get_tempo_factors(rate).into_iter().enumerate() {
+        let mut parameters = HashMap::new();
+        parameters.insert(
+            "tempo".to_string(),
+            ParameterValue::String(factor.to_string()),
+        );
+
+        let tempo_filter = audio_graph
+            .add_filter(&Filter {
+                name: "atempo".to_string(),
+                label: Some(format!("Change tempo {i}")),
+                parameters,
+                inputs: None,
+                outputs: None,
+            })
+            .map_err(MediaDecoderError::FilterGraph)?;
+        audio_graph
+            .connect(&last_filter, 0, &tempo_filter, 0)
+            .map_err(MediaDecoderError::FilterGraph)?;
+        last_filter = tempo_filter;
+    }
+
+    audio_graph
+        .connect_output(&last_filter, 0, "main_audio", 0)
+        .map_err(MediaDecoderError::FilterGraph)?;
+    audio_graph
+        .validate()
+        .map_err(MediaDecoderError::FilterGraph)?;
+
+    Ok(audio_graph)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cue(start: i64, end: i64) -> Subtitle {
+        Subtitle {
+            start,
+            end: Some(end),
+            text: vec![format!("{start}")],
+            bitmaps: vec![],
+            canvas: (0, 0),
+        }
+    }
+
+    fn external_subtitles(cues: Vec<Subtitle>) -> ExternalSubtitles {
+        ExternalSubtitles {
+            path: PathBuf::from("movie.srt"),
+            cues,
+            next: 0,
+        }
+    }
+
+    #[test]
+    fn maps_cues_past_the_start_time_of_the_stream() {
+        // an MPEG-TS file whose first pts is at 1.4s, in the second item of a gapless playlist
+        let start_time = 1_400_000_000;
+        let offset = 60_000_000_000 - start_time;
+        let mut subtitles = external_subtitles(vec![
+            cue(1_000_000_000, 2_000_000_000),
+            cue(3_000_000_000, 4_000_000_000),
+        ]);
+
+        let subtitle = subtitles.peek(offset + start_time).unwrap();
+        assert_eq!(subtitle.start, 61_000_000_000);
+        assert_eq!(subtitle.end, Some(62_000_000_000));
+
+        // rewinding takes the position, the first cue is over 2.5s into the item
+        subtitles.rewind(2_500_000_000);
+        assert_eq!(subtitles.next, 1);
+        assert_eq!(
+            subtitles.peek(offset + start_time).unwrap().start,
+            63_000_000_000
+        );
+    }
+
+    #[test]
+    fn rewinds_to_the_cue_on_screen() {
+        let mut subtitles = external_subtitles(vec![
+            cue(1_000_000_000, 2_000_000_000),
+            cue(3_000_000_000, 4_000_000_000),
+        ]);
+
+        subtitles.rewind(3_500_000_000);
+        assert_eq!(subtitles.next, 1);
+        subtitles.rewind(5_000_000_000);
+        assert_eq!(subtitles.next, 2);
+        assert!(subtitles.peek(0).is_none());
+        subtitles.rewind(0);
+        assert_eq!(subtitles.next, 0);
+    }
+
+    #[test]
+    fn splits_rates_into_factors_atempo_handles() {
+        for rate in [MIN_RATE, 0.5, 1.0, 3.0, MAX_RATE] {
+            let factors = get_tempo_factors(rate);
+            assert!(
+                (factors.iter().product::<f32>() - rate).abs() < 1e-6,
+                "{rate}"
+            );
+            for factor in factors {
+                assert!(
+                    (0.5..=2.0).contains(&factor),
+                    "{rate} has a factor of {factor}"
+                );
+            }
+        }
+        assert!(get_tempo_factors(1.0).is_empty());
+        assert_eq!(get_tempo_factors(3.0), vec![2.0, 1.5]);
+    }
+
+    #[test]
+    fn picks_the_default_layout_for_the_channel_count() {
+        let layouts: Vec<_> = (0..10).map(get_channel_layout).collect();
+        assert_eq!(
+            layouts,
+            vec![
+                None,
+                Some("mono"),
+                Some("stereo"),
+                Some("2.1"),
+                Some("quad"),
+                Some("5.0"),
+                Some("5.1"),
+                Some("6.1"),
+                Some("7.1"),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_downmix_modes_by_name() {
+        for mode in DownmixMode::ALL {
+            assert_eq!(DownmixMode::from_name(mode.name()), Some(mode));
+        }
+        assert_eq!(
+            DownmixMode::from_name("dplii"),
+            Some(DownmixMode::ProLogicII)
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_downmix_names() {
+        assert_eq!(DownmixMode::from_name("surround"), None);
+        assert_eq!(DownmixMode::from_name("ITU"), None);
+        assert_eq!(DownmixMode::from_name(""), None);
+    }
+
+    #[test]
+    fn sizes_the_planes_of_each_format() {
+        use AVPixelFormat::*;
+
+        // format, size of the frame, then size and bytes per pixel of every plane
+        let cases = [
+            (
+                AV_PIX_FMT_YUV420P,
+                (1920, 1080),
+                vec![((1920, 1080), 1), ((960, 540), 1), ((960, 540), 1)],
+            ),
+            (
+                AV_PIX_FMT_YUV420P,
+                (1921, 1081),
+                vec![((1921, 1081), 1), ((961, 541), 1), ((961, 541), 1)],
+            ),
+            (
+                AV_PIX_FMT_YUV420P10LE,
+                (1280, 720),
+                vec![((1280, 720), 2), ((640, 360), 2), ((640, 360), 2)],
+            ),
+            (
+                AV_PIX_FMT_YUV420P10LE,
+                (5, 3),
+                vec![((5, 3), 2), ((3, 2), 2), ((3, 2), 2)],
+            ),
+            (
+                AV_PIX_FMT_P010LE,
+                (3840, 2160),
+                vec![((3840, 2160), 2), ((1920, 1080), 4)],
+            ),
+            (
+                AV_PIX_FMT_NV12,
+                (1920, 1080),
+                vec![((1920, 1080), 1), ((960, 540), 2)],
+            ),
+            (AV_PIX_FMT_NV12, (5, 3), vec![((5, 3), 1), ((3, 2), 2)]),
+        ];
+        for (format, (width, height), planes) in cases {
+            let format = PixelFormat::from_av(format).unwrap();
+            assert_eq!(format.planes, planes.len(), "{}", format.name());
+            for (plane, (size, pixel_size)) in planes.into_iter().enumerate() {
+                assert_eq!(
+                    format.plane_size(plane, width, height),
+                    size,
+                    "{} plane {plane}",
+                    format.name()
+                );
+                assert_eq!(
+                    format.pixel_size(plane),
+                    pixel_size,
+                    "{} plane {plane}",
+                    format.name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn moves_only_planar_deep_samples_up() {
+        use AVPixelFormat::*;
+
+        let cases = [
+            (AV_PIX_FMT_YUV420P, 0),
+            (AV_PIX_FMT_YUV420P10LE, 6),
+            (AV_PIX_FMT_YUV420P12LE, 4),
+            // P010 already has the value in the high bits
+            (AV_PIX_FMT_P010LE, 0),
+            (AV_PIX_FMT_NV12, 0),
+        ];
+        for (format, shift) in cases {
+            let format = PixelFormat::from_av(format).unwrap();
+            assert_eq!(format.alignment_shift(), shift, "{}", format.name());
+        }
+    }
+}
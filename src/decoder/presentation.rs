@@ -0,0 +1,617 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ringbuf::HeapConsumer;
+use stainless_ffmpeg::prelude::*;
+
+use crate::av_sync::{ClockSmoother, LATE_FRAME_THRESHOLD};
+
+use super::{
+    enter_item, DecodedFrame, MediaEvents, MediaState, Pacing, PendingItem, Subtitle,
+    ONE_NANOSECOND,
+};
+
+/// How often the presentation thread reports the playback position
+const POSITION_EVENT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Monotonic clock that drives playback when there is no audio to sync against
+#[derive(Default)]
+struct SystemClock {
+    anchor: Option<(Instant, i64, f32)>,
+}
+
+impl SystemClock {
+    fn pause(&mut self) {
+        self.anchor = None;
+    }
+
+    /// Current time in nanoseconds, a stopped clock starts counting from `start`
+    fn tick(&mut self, start: i64, rate: f32) -> i64 {
+        let (instant, pts, anchor_rate) = *self
+            .anchor
+            .get_or_insert_with(|| (Instant::now(), start, rate));
+        let now = pts + (instant.elapsed().as_nanos() as f64 * anchor_rate as f64) as i64;
+
+        // restart from the current time so the new rate only applies from here on
+        if anchor_rate != rate {
+            self.anchor = Some((Instant::now(), now, rate));
+        }
+
+        now
+    }
+}
+
+/// Hands the decoded frames to the frame callback once the clock gets to them and keeps the UI
+/// up to date with the position and the subtitles on screen. Runs on its own thread until the
+/// decoder is closed
+pub struct Presenter<F> {
+    state: Arc<MediaState>,
+    video_consumer: HeapConsumer<DecodedFrame>,
+    subtitle_consumer: HeapConsumer<Subtitle>,
+    new_frame_callback: F,
+    event_callback: Arc<dyn Fn(MediaEvents) + Send + Sync>,
+    /// Frames are shown as soon as they're decoded instead of waiting for the clock
+    free_running: bool,
+    system_clock: SystemClock,
+    clock_smoother: ClockSmoother,
+    /// Pts of the frame on screen
+    last_shown: Option<i64>,
+    /// How long the frame before the one on screen was shown
+    frame_duration: i64,
+    /// Frame intervals the frame on screen was kept up for because the next one was missing
+    held_frames: u64,
+    last_position_event: Instant,
+    last_position: i64,
+    /// The queue ran dry and `Buffering` or `EndOfStream` was sent
+    starved: bool,
+    active_subtitles: Vec<Subtitle>,
+}
+
+impl<F> Presenter<F>
+where
+    F: Fn(DecodedFrame) + Send + Sync + 'static,
+{
+    pub fn new(
+        state: Arc<MediaState>,
+        video_consumer: HeapConsumer<DecodedFrame>,
+        subtitle_consumer: HeapConsumer<Subtitle>,
+        new_frame_callback: F,
+        event_callback: Arc<dyn Fn(MediaEvents) + Send + Sync>,
+        pacing: Pacing,
+    ) -> Self {
+        Self {
+            state,
+            video_consumer,
+            subtitle_consumer,
+            new_frame_callback,
+            event_callback,
+            free_running: pacing == Pacing::AsFastAsPossible,
+            system_clock: SystemClock::default(),
+            clock_smoother: ClockSmoother::default(),
+            last_shown: None,
+            frame_duration: 0,
+            held_frames: 0,
+            last_position_event: Instant::now(),
+            last_position: 0,
+            starved: false,
+            active_subtitles: vec![],
+        }
+    }
+
+    pub fn spawn(self) {
+        std::thread::spawn(move || self.run());
+    }
+
+    fn run(mut self) {
+        loop {
+            if self.state.closed.load(Ordering::Acquire) {
+                self.clear_subtitles();
+                return;
+            }
+            if self.state.flush_subtitles.load(Ordering::Acquire) {
+                self.subtitle_consumer.clear();
+                self.clear_subtitles();
+                self.state.flush_subtitles.store(false, Ordering::Release);
+            }
+            if self.state.flush_video.load(Ordering::Acquire) {
+                self.flush();
+                continue;
+            }
+
+            let shown = if self.state.paused.load(Ordering::Acquire) {
+                self.step()
+            } else {
+                self.present()
+            };
+            if !shown {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+
+    /// Drops the queued frames and subtitles after a seek, the clock starts over from the first
+    /// frame that comes in
+    fn flush(&mut self) {
+        self.video_consumer.clear();
+        self.subtitle_consumer.clear();
+        self.clear_subtitles();
+        self.system_clock.pause();
+        self.clock_smoother.reset();
+        self.last_shown = None;
+        if !self.state.has_audio.load(Ordering::Acquire) {
+            self.state.audio_clock.store(0, Ordering::Release);
+        }
+        self.state.flush_video.store(false, Ordering::Release);
+        self.starved = false;
+    }
+
+    fn clear_subtitles(&mut self) {
+        if !self.active_subtitles.is_empty() {
+            self.active_subtitles.clear();
+            (self.event_callback)(MediaEvents::Subtitles(vec![]));
+        }
+    }
+
+    /// Shows the next frame while paused when a step was asked for, returns false when there
+    /// was nothing to show
+    fn step(&mut self) -> bool {
+        self.system_clock.pause();
+        self.clock_smoother.reset();
+
+        if self.state.pending_steps.load(Ordering::Acquire) == 0 {
+            return false;
+        }
+        let Some(frame) = self.video_consumer.pop() else {
+            return false;
+        };
+
+        self.state.pending_steps.fetch_sub(1, Ordering::AcqRel);
+        self.state.audio_clock.store(frame.pts, Ordering::Release);
+        self.state.displayed_pts.store(frame.pts, Ordering::Release);
+        self.enter_pending_item(frame.pts);
+        let pts = frame.pts - self.state.item_offset();
+        (self.event_callback)(MediaEvents::Stepped {
+            pts,
+            frame_number: self.state.frame_rate.lock().unwrap().map(|rate| unsafe {
+                av_rescale_q(
+                    pts,
+                    av_make_q(1, ONE_NANOSECOND as i32),
+                    av_make_q(rate.den, rate.num),
+                )
+            }),
+        });
+        (self.new_frame_callback)(frame);
+
+        true
+    }
+
+    /// Shows the oldest frame once the clock gets to it, returns false when there was nothing
+    /// to show
+    fn present(&mut self) -> bool {
+        let has_audio = self.state.has_audio.load(Ordering::Acquire);
+        let clock = self.tick_clock(has_audio);
+        if clock != 0 {
+            self.enter_pending_item(clock);
+        }
+        self.report_position(clock);
+
+        if clock != 0
+            && update_subtitles(
+                &mut self.subtitle_consumer,
+                &mut self.active_subtitles,
+                clock,
+            )
+        {
+            (self.event_callback)(MediaEvents::Subtitles(self.active_subtitles.clone()));
+        }
+
+        if self.is_starved(clock) {
+            return false;
+        }
+        if has_audio && clock == 0 {
+            log::debug!("No audio clock..");
+            return false;
+        }
+
+        self.drop_late_frames(clock);
+
+        let Some(oldest_frame_in_queue) = self.video_consumer.iter().next().map(|frame| frame.pts)
+        else {
+            return false;
+        };
+
+        if oldest_frame_in_queue > clock && !self.free_running {
+            // the clock runs in media time, which passes faster or slower than real time
+            let sleep_time = Duration::from_nanos(
+                ((oldest_frame_in_queue - clock) as f64 / self.state.rate() as f64) as u64,
+            );
+
+            log::debug!("sleeping for {:?}", sleep_time);
+            spin_sleep::sleep(sleep_time);
+        }
+
+        if let Some(frame) = self.video_consumer.pop() {
+            self.show(frame, clock);
+        }
+
+        true
+    }
+
+    /// Current time on the master clock, a system clock stands in for the audio output when the
+    /// item has no audio. Zero until the clock started after a seek
+    fn tick_clock(&mut self, has_audio: bool) -> i64 {
+        if !has_audio && self.free_running {
+            // the clock jumps to every frame instead of waiting for it
+            if let Some(next) = self.video_consumer.iter().next().map(|frame| frame.pts) {
+                self.state.audio_clock.fetch_max(next, Ordering::AcqRel);
+            }
+        } else if !has_audio {
+            let current = self.state.audio_clock.load(Ordering::Acquire);
+            // after a seek the clock starts at the first frame that comes in
+            let start = if current == 0 {
+                self.video_consumer.iter().next().map(|frame| frame.pts)
+            } else {
+                Some(current)
+            };
+            if let Some(start) = start {
+                self.state.audio_clock.store(
+                    self.system_clock.tick(start, self.state.rate()),
+                    Ordering::Release,
+                );
+            }
+        }
+
+        let clock = self.state.audio_clock.load(Ordering::Acquire);
+        // the audio output only moves the clock once per buffer
+        if has_audio && !self.free_running && clock != 0 {
+            return self.clock_smoother.tick(
+                clock,
+                self.state.audio_clock_updated.load(Ordering::Acquire),
+                self.state.now(),
+                self.state.rate(),
+            );
+        }
+
+        clock
+    }
+
+    fn report_position(&mut self, clock: i64) {
+        if clock != self.last_position
+            && self.last_position_event.elapsed() >= POSITION_EVENT_INTERVAL
+        {
+            (self.event_callback)(MediaEvents::Position(clock - self.state.item_offset()));
+            (self.event_callback)(MediaEvents::Sync(self.state.sync.stats()));
+            self.last_position = clock;
+            self.last_position_event = Instant::now();
+        }
+    }
+
+    /// Whether there is nothing to play, the video queue or the audio queue for items without
+    /// real video. `Buffering` or `EndOfStream` is sent when it runs dry and `Playing` once it
+    /// filled up again
+    fn is_starved(&mut self, clock: i64) -> bool {
+        let continuous_video = self.state.continuous_video.load(Ordering::Acquire);
+        let queue_empty = if continuous_video {
+            self.video_consumer.is_empty()
+        } else {
+            self.state.audio_starved.load(Ordering::Acquire)
+        };
+
+        if !queue_empty {
+            if self.starved {
+                self.starved = false;
+                (self.event_callback)(MediaEvents::Playing);
+            }
+            return false;
+        }
+
+        log::debug!("Frame queue is empty");
+        // the frame on screen stays up for every interval its successor is missing
+        if let Some(last_shown) = self.last_shown.filter(|_| continuous_video) {
+            if self.frame_duration > 0 && clock != 0 {
+                let held = ((clock - last_shown) / self.frame_duration - 1).max(0) as u64;
+                if held > self.held_frames {
+                    self.state.sync.frames_duplicated(held - self.held_frames);
+                    self.held_frames = held;
+                }
+            }
+        }
+        if !self.starved {
+            self.starved = true;
+            if self.state.end_of_stream.load(Ordering::Acquire) {
+                (self.event_callback)(MediaEvents::EndOfStream);
+            } else {
+                (self.event_callback)(MediaEvents::Buffering);
+            }
+        }
+
+        true
+    }
+
+    /// A late frame is dropped when the one after it is due as well, showing it would only put
+    /// video further behind
+    fn drop_late_frames(&mut self, clock: i64) {
+        while !self.free_running
+            && self
+                .video_consumer
+                .iter()
+                .next()
+                .is_some_and(|frame| clock - frame.pts > LATE_FRAME_THRESHOLD)
+            && self
+                .video_consumer
+                .iter()
+                .nth(1)
+                .is_some_and(|next| next.pts <= clock)
+        {
+            let Some(frame) = self.video_consumer.pop() else {
+                break;
+            };
+            log::debug!(
+                "Dropped frame {} which is {} ms late",
+                frame.pts,
+                (clock - frame.pts) / 1_000_000
+            );
+            self.enter_pending_item(frame.pts);
+            self.state.sync.frame_dropped();
+        }
+    }
+
+    fn show(&mut self, frame: DecodedFrame, clock: i64) {
+        // the renderer has to know about a new size before the first frame of the item
+        self.enter_pending_item(frame.pts);
+        self.state.displayed_pts.store(frame.pts, Ordering::Release);
+        if !self.free_running {
+            self.state.sync.set_av_offset((clock - frame.pts).max(0));
+        }
+        if let Some(last_shown) = self.last_shown.filter(|last| *last < frame.pts) {
+            self.frame_duration = frame.pts - last_shown;
+        }
+        self.last_shown = Some(frame.pts);
+        self.held_frames = 0;
+        (self.new_frame_callback)(frame);
+    }
+
+    /// Playback got to the playlist item the decoder moved on to once it reached `pts`
+    fn enter_pending_item(&self, pts: i64) {
+        if let Some(item) = take_pending_item(&self.state, pts) {
+            enter_item(&self.state, &*self.event_callback, item);
+        }
+    }
+}
+
+/// Takes the playlist item the decoder moved on to once playback got to `pts`
+fn take_pending_item(state: &MediaState, pts: i64) -> Option<PendingItem> {
+    let mut pending_item = state.pending_item.lock().unwrap();
+    if pending_item.as_ref().is_some_and(|item| item.start <= pts) {
+        pending_item.take()
+    } else {
+        None
+    }
+}
+
+/// Moves subtitles that are due to the active list and drops the ones that ended, returns whether
+/// anything changed
+fn update_subtitles(
+    queue: &mut HeapConsumer<Subtitle>,
+    active: &mut Vec<Subtitle>,
+    clock: i64,
+) -> bool {
+    let mut changed = false;
+    while queue.iter().next().is_some_and(|next| next.start <= clock) {
+        let Some(subtitle) = queue.pop() else {
+            break;
+        };
+        // a bitmap subtitle, or an empty one, replaces the bitmaps on screen
+        let replaces_bitmaps = subtitle.text.is_empty();
+        active
+            .retain(|active| active.end.is_some() && !(replaces_bitmaps && active.text.is_empty()));
+        if !subtitle.text.is_empty() || !subtitle.bitmaps.is_empty() {
+            active.push(subtitle);
+        }
+        changed = true;
+    }
+
+    let len = active.len();
+    active.retain(|subtitle| subtitle.end.map_or(true, |end| end > clock));
+
+    changed || len != active.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32};
+    use std::sync::Mutex;
+
+    use ringbuf::{HeapProducer, HeapRb};
+
+    use super::*;
+    use crate::av_sync::SyncCounters;
+    use crate::decoder::{ColorInfo, PixelFormat};
+    use crate::frame_pool::FramePool;
+
+    const MILLISECOND: i64 = 1_000_000;
+
+    struct Harness {
+        presenter: Presenter<fn(DecodedFrame)>,
+        frames: HeapProducer<DecodedFrame>,
+        events: Arc<Mutex<Vec<MediaEvents>>>,
+    }
+
+    /// A presenter for an item with real video that is playing
+    fn harness() -> Harness {
+        let state = Arc::new(MediaState {
+            paused: AtomicBool::new(false),
+            audio_clock: AtomicI64::new(0),
+            audio_clock_updated: AtomicI64::new(0),
+            epoch: Instant::now(),
+            end_of_stream: AtomicBool::new(false),
+            audio_starved: AtomicBool::new(false),
+            gain: AtomicU32::new(1.0f32.to_bits()),
+            replay_gain: AtomicU32::new(1.0f32.to_bits()),
+            rate: AtomicU32::new(1.0f32.to_bits()),
+            displayed_pts: AtomicI64::new(0),
+            pending_steps: AtomicU32::new(0),
+            stepped: AtomicBool::new(false),
+            flush_subtitles: AtomicBool::new(false),
+            frame_rate: Mutex::new(None),
+            has_audio: AtomicBool::new(true),
+            continuous_video: AtomicBool::new(true),
+            item_offset: AtomicI64::new(0),
+            pending_item: Mutex::new(None),
+            closed: AtomicBool::new(false),
+            flush_video: AtomicBool::new(false),
+            flush_audio: AtomicBool::new(false),
+            sync: SyncCounters::default(),
+        });
+        let (frames, video_consumer) = HeapRb::<DecodedFrame>::new(8).split();
+        let (_, subtitle_consumer) = HeapRb::<Subtitle>::new(1).split();
+        let events = Arc::new(Mutex::new(vec![]));
+
+        Harness {
+            presenter: Presenter::new(
+                state,
+                video_consumer,
+                subtitle_consumer,
+                (|_| ()) as fn(DecodedFrame),
+                {
+                    let events = events.clone();
+                    Arc::new(move |event: MediaEvents| events.lock().unwrap().push(event))
+                },
+                Pacing::Realtime,
+            ),
+            frames,
+            events,
+        }
+    }
+
+    fn frame(pts: i64) -> DecodedFrame {
+        DecodedFrame {
+            width: 2,
+            height: 2,
+            format: PixelFormat {
+                format: AVPixelFormat::AV_PIX_FMT_YUV420P,
+                planes: 3,
+                chroma_shift: (1, 1),
+                bit_depth: 8,
+                rgb: false,
+            },
+            color: ColorInfo {
+                space: AVColorSpace::AVCOL_SPC_BT709,
+                range: AVColorRange::AVCOL_RANGE_MPEG,
+                primaries: AVColorPrimaries::AVCOL_PRI_BT709,
+                transfer: AVColorTransferCharacteristic::AVCOL_TRC_BT709,
+                chroma_location: AVChromaLocation::AVCHROMA_LOC_LEFT,
+            },
+            hdr_metadata: None,
+            data: FramePool::new().get(0),
+            linesizes: vec![2, 1, 1],
+            pts,
+        }
+    }
+
+    fn queue(harness: &mut Harness, pts: &[i64]) {
+        for pts in pts {
+            assert!(harness.frames.push(frame(pts * MILLISECOND)).is_ok());
+        }
+    }
+
+    fn next_pts(harness: &Harness) -> Option<i64> {
+        harness
+            .presenter
+            .video_consumer
+            .iter()
+            .next()
+            .map(|frame| frame.pts / MILLISECOND)
+    }
+
+    #[test]
+    fn drops_late_frames_once_the_next_one_is_due() {
+        let mut harness = harness();
+        queue(&mut harness, &[0, 40, 80]);
+
+        harness.presenter.drop_late_frames(45 * MILLISECOND);
+        assert_eq!(next_pts(&harness), Some(40));
+        assert_eq!(harness.presenter.state.sync.stats().dropped, 1);
+    }
+
+    #[test]
+    fn keeps_a_late_frame_until_the_next_one_is_due() {
+        let mut harness = harness();
+        queue(&mut harness, &[0, 100]);
+
+        harness.presenter.drop_late_frames(50 * MILLISECOND);
+        assert_eq!(next_pts(&harness), Some(0));
+        assert_eq!(harness.presenter.state.sync.stats().dropped, 0);
+    }
+
+    #[test]
+    fn drops_frames_only_past_the_late_threshold() {
+        let mut harness = harness();
+        queue(&mut harness, &[0, 10]);
+
+        harness.presenter.drop_late_frames(LATE_FRAME_THRESHOLD);
+        assert_eq!(next_pts(&harness), Some(0));
+        harness.presenter.drop_late_frames(LATE_FRAME_THRESHOLD + 1);
+        assert_eq!(next_pts(&harness), Some(10));
+    }
+
+    #[test]
+    fn reports_buffering_once_until_frames_come_in() {
+        let mut harness = harness();
+
+        assert!(harness.presenter.is_starved(0));
+        assert!(harness.presenter.is_starved(0));
+        assert!(matches!(
+            harness.events.lock().unwrap()[..],
+            [MediaEvents::Buffering]
+        ));
+
+        queue(&mut harness, &[0]);
+        assert!(!harness.presenter.is_starved(0));
+        assert!(matches!(
+            harness.events.lock().unwrap()[..],
+            [MediaEvents::Buffering, MediaEvents::Playing]
+        ));
+    }
+
+    #[test]
+    fn reports_the_end_of_the_stream_when_starved_after_it() {
+        let mut harness = harness();
+        harness
+            .presenter
+            .state
+            .end_of_stream
+            .store(true, Ordering::Release);
+
+        assert!(harness.presenter.is_starved(0));
+        assert!(matches!(
+            harness.events.lock().unwrap()[..],
+            [MediaEvents::EndOfStream]
+        ));
+    }
+
+    #[test]
+    fn counts_the_intervals_the_last_frame_is_held() {
+        let mut harness = harness();
+        harness.presenter.last_shown = Some(0);
+        harness.presenter.frame_duration = 40 * MILLISECOND;
+
+        // the frame was due to be replaced at 40 and 80 ms
+        assert!(harness.presenter.is_starved(130 * MILLISECOND));
+        assert_eq!(harness.presenter.state.sync.stats().duplicated, 2);
+        assert!(harness.presenter.is_starved(150 * MILLISECOND));
+        assert_eq!(harness.presenter.state.sync.stats().duplicated, 2);
+    }
+
+    #[test]
+    fn follows_the_audio_queue_without_real_video() {
+        let mut harness = harness();
+        let state = &harness.presenter.state;
+        state.continuous_video.store(false, Ordering::Release);
+        state.audio_starved.store(true, Ordering::Release);
+        queue(&mut harness, &[0]);
+
+        assert!(harness.presenter.is_starved(0));
+    }
+}
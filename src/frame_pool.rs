@@ -0,0 +1,158 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Free buffers kept around, a seek empties the whole frame queue at once and most of those
+/// aren't needed again
+const MAX_FREE_BUFFERS: usize = 16;
+
+/// Buffers for decoded frames that go back to the pool when the frame is dropped, so playback
+/// doesn't allocate every frame anew
+#[derive(Clone, Default)]
+pub struct FramePool {
+    inner: Arc<PoolInner>,
+}
+
+#[derive(Default)]
+struct PoolInner {
+    free: Mutex<Vec<Vec<u8>>>,
+    allocated: AtomicUsize,
+    in_use: AtomicUsize,
+    reused: AtomicU64,
+}
+
+/// How the pool is used, for finding out how many buffers playback needs
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FramePoolStats {
+    /// Buffers allocated since the pool was created
+    pub allocated: usize,
+    /// Buffers held by frames
+    pub in_use: usize,
+    /// Buffers waiting in the pool
+    pub free: usize,
+    /// Frames that got a recycled buffer
+    pub reused: u64,
+}
+
+impl FramePool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An empty buffer that fits at least `capacity` bytes without growing
+    pub fn get(&self, capacity: usize) -> FrameBuffer {
+        let recycled = self.inner.free.lock().unwrap().pop();
+        let data = match recycled {
+            Some(mut data) => {
+                self.inner.reused.fetch_add(1, Ordering::Relaxed);
+                data.clear();
+                data.reserve(capacity);
+                data
+            }
+            None => {
+                let allocated = self.inner.allocated.fetch_add(1, Ordering::Relaxed) + 1;
+                log::debug!("Frame pool allocated buffer {}", allocated);
+                Vec::with_capacity(capacity)
+            }
+        };
+        self.inner.in_use.fetch_add(1, Ordering::Relaxed);
+
+        FrameBuffer {
+            data,
+            pool: self.inner.clone(),
+        }
+    }
+
+    pub fn stats(&self) -> FramePoolStats {
+        FramePoolStats {
+            allocated: self.inner.allocated.load(Ordering::Relaxed),
+            in_use: self.inner.in_use.load(Ordering::Relaxed),
+            free: self.inner.free.lock().unwrap().len(),
+            reused: self.inner.reused.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The bytes of a frame, handed back to the pool on drop
+pub struct FrameBuffer {
+    data: Vec<u8>,
+    pool: Arc<PoolInner>,
+}
+
+impl Deref for FrameBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.data
+    }
+}
+
+impl DerefMut for FrameBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.data
+    }
+}
+
+impl std::fmt::Debug for FrameBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FrameBuffer")
+            .field("len", &self.data.len())
+            .finish()
+    }
+}
+
+impl Drop for FrameBuffer {
+    fn drop(&mut self) {
+        self.pool.in_use.fetch_sub(1, Ordering::Relaxed);
+
+        let mut free = self.pool.free.lock().unwrap();
+        // the rest is freed, the pool shrinks back to what playback needs
+        if free.len() < MAX_FREE_BUFFERS {
+            free.push(std::mem::take(&mut self.data));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_at_most_max_free_buffers() {
+        let pool = FramePool::new();
+        let buffers: Vec<FrameBuffer> = (0..MAX_FREE_BUFFERS + 8).map(|_| pool.get(64)).collect();
+        assert_eq!(pool.stats().in_use, MAX_FREE_BUFFERS + 8);
+
+        drop(buffers);
+
+        let stats = pool.stats();
+        assert_eq!(stats.in_use, 0);
+        assert_eq!(stats.free, MAX_FREE_BUFFERS);
+        assert_eq!(stats.allocated, MAX_FREE_BUFFERS + 8);
+        assert_eq!(stats.reused, 0);
+    }
+
+    #[test]
+    fn reuses_returned_buffers() {
+        let pool = FramePool::new();
+        let buffers: Vec<FrameBuffer> = (0..MAX_FREE_BUFFERS + 8).map(|_| pool.get(64)).collect();
+        drop(buffers);
+
+        // only the buffers the pool kept are reused, the rest is allocated again
+        let mut buffer = pool.get(128);
+        assert!(buffer.is_empty());
+        assert!(buffer.capacity() >= 128);
+        buffer.extend_from_slice(&[1, 2, 3]);
+        let buffers: Vec<FrameBuffer> = (0..MAX_FREE_BUFFERS).map(|_| pool.get(64)).collect();
+
+        let stats = pool.stats();
+        assert_eq!(stats.reused, MAX_FREE_BUFFERS as u64);
+        assert_eq!(stats.allocated, MAX_FREE_BUFFERS + 8 + 1);
+        assert_eq!(stats.free, 0);
+        assert_eq!(stats.in_use, MAX_FREE_BUFFERS + 1);
+
+        drop(buffer);
+        drop(buffers);
+        assert_eq!(pool.stats().free, MAX_FREE_BUFFERS);
+    }
+}
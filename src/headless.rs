@@ -0,0 +1,485 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use stainless_ffmpeg::prelude::{AVChromaLocation, AVMediaType, AVPixelFormat};
+
+use crate::audio_sink::AudioOutput;
+use crate::decoder::{
+    DecodedFrame, DownmixMode, MediaCommands, MediaDecoder, MediaDecoderError, MediaDecoderOptions,
+    MediaEvents, Pacing, PixelFormat, ReplayGainMode, StreamInfo,
+};
+use crate::playlist::Playlist;
+
+/// Where the frames of a headless run go
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum FrameOutput {
+    /// Frames are decoded and thrown away
+    #[default]
+    Null,
+    /// A YUV4MPEG2 stream, which ffmpeg and most players can read
+    Y4m(PathBuf),
+    /// The planes of every frame back to back, without the row padding
+    Raw(PathBuf),
+    /// A directory with a PNG for every frame
+    Png(PathBuf),
+}
+
+impl FrameOutput {
+    /// `.y4m` and `.yuv` files get the frames as they are, anything else is a directory for PNGs
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+
+        match extension.as_deref() {
+            Some("y4m") => FrameOutput::Y4m(path.to_path_buf()),
+            Some("yuv" | "raw") => FrameOutput::Raw(path.to_path_buf()),
+            _ => FrameOutput::Png(path.to_path_buf()),
+        }
+    }
+}
+
+pub struct HeadlessOptions {
+    pub frame_output: FrameOutput,
+    pub audio_output: AudioOutput,
+    pub pacing: Pacing,
+    pub subtitle_file: Option<PathBuf>,
+    pub downmix: DownmixMode,
+}
+
+/// Takes the decoded frames in the order they're shown
+pub trait FrameSink: Send {
+    fn write_frame(&mut self, frame: &DecodedFrame) -> std::io::Result<()>;
+
+    /// Called once after the last frame
+    fn finish(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Plays the playlist to the end without a window or GPU, returns how many frames were written
+pub fn run(playlist: Playlist, options: HeadlessOptions) -> Result<u64, MediaDecoderError> {
+    // the Y4M header needs the frame rate and pixel shape of the video track, which only the
+    // events tell
+    let frame_rate = Arc::new(Mutex::new(None));
+    let sample_aspect_ratio = Arc::new(Mutex::new(None));
+    let frame_sink = Arc::new(Mutex::new(
+        create_frame_sink(
+            &options.frame_output,
+            frame_rate.clone(),
+            sample_aspect_ratio.clone(),
+        )
+        .map_err(|err| MediaDecoderError::FrameOutput(err.to_string()))?,
+    ));
+    let frame_count = Arc::new(AtomicU64::new(0));
+    let frame_error = Arc::new(Mutex::new(None));
+    // the run ends once the playlist finished or a frame couldn't be written
+    let (finished_tx, finished_rx) = crossbeam_channel::bounded::<()>(1);
+
+    let mut media_decoder = MediaDecoder::new(
+        playlist,
+        MediaDecoderOptions {
+            use_hw_accel: false,
+            replay_gain: ReplayGainMode::Track,
+            subtitle_file: options.subtitle_file,
+            volume: 1.0,
+            muted: false,
+            audio_output: options.audio_output,
+            pacing: options.pacing,
+            downmix: options.downmix,
+        },
+        {
+            let frame_sink = frame_sink.clone();
+            let frame_count = frame_count.clone();
+            let frame_error = frame_error.clone();
+            let finished_tx = finished_tx.clone();
+            move |frame| {
+                if frame_error.lock().unwrap().is_some() {
+                    return;
+                }
+                match frame_sink.lock().unwrap().write_frame(&frame) {
+                    Ok(()) => {
+                        frame_count.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(err) => {
+                        log::error!("Failed to write frame: {}", err);
+                        *frame_error.lock().unwrap() =
+                            Some(MediaDecoderError::FrameOutput(err.to_string()));
+                        finished_tx.try_send(()).ok();
+                    }
+                }
+            }
+        },
+        {
+            let streams: Mutex<Vec<StreamInfo>> = Mutex::new(vec![]);
+            move |event| match event {
+                MediaEvents::Loaded {
+                    streams: loaded, ..
+                } => *streams.lock().unwrap() = loaded,
+                MediaEvents::TrackSelected {
+                    media_type: AVMediaType::AVMEDIA_TYPE_VIDEO,
+                    index,
+                } => {
+                    *frame_rate.lock().unwrap() = streams
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .find(|stream| stream.index == index)
+                        .and_then(|stream| stream.frame_rate);
+                }
+                MediaEvents::VideoSize {
+                    sample_aspect_ratio: ratio,
+                    ..
+                } => *sample_aspect_ratio.lock().unwrap() = Some(ratio),
+                MediaEvents::Error(err) => log::error!("{}", err),
+                MediaEvents::Finished => {
+                    finished_tx.try_send(()).ok();
+                }
+                _ => (),
+            }
+        },
+    )?;
+
+    let (done_tx, done_rx) = crossbeam_channel::bounded::<()>(1);
+    std::thread::spawn({
+        let command_sender = media_decoder.command_sender.clone();
+        move || {
+            crossbeam_channel::select! {
+                recv(finished_rx) -> _ => {
+                    command_sender.send(MediaCommands::Close).ok();
+                }
+                recv(done_rx) -> _ => (),
+            }
+        }
+    });
+
+    media_decoder
+        .command_sender
+        .send(MediaCommands::Play)
+        .unwrap();
+    let result = media_decoder.start();
+    drop(done_tx);
+    let stats = media_decoder.frame_pool().stats();
+    log::info!(
+        "Frame pool allocated {} buffers, {} frames reused one",
+        stats.allocated,
+        stats.reused
+    );
+    let sync = media_decoder.sync_stats();
+    log::info!(
+        "Dropped {} late frames, held {} frame intervals, last frame {} ms late",
+        sync.dropped,
+        sync.duplicated,
+        sync.av_offset / 1_000_000
+    );
+    // finishes the WAV file
+    drop(media_decoder);
+    result?;
+
+    // the presentation thread might still be on its way out, later frames go nowhere
+    let mut sink = frame_sink.lock().unwrap();
+    sink.finish()
+        .map_err(|err| MediaDecoderError::FrameOutput(err.to_string()))?;
+    *sink = Box::new(NullFrameSink);
+
+    if let Some(err) = frame_error.lock().unwrap().take() {
+        return Err(err);
+    }
+    Ok(frame_count.load(Ordering::Relaxed))
+}
+
+fn create_frame_sink(
+    output: &FrameOutput,
+    frame_rate: Arc<Mutex<Option<(i32, i32)>>>,
+    sample_aspect_ratio: Arc<Mutex<Option<(i32, i32)>>>,
+) -> std::io::Result<Box<dyn FrameSink>> {
+    Ok(match output {
+        FrameOutput::Null => Box::new(NullFrameSink),
+        FrameOutput::Y4m(path) => Box::new(Y4mWriter {
+            file: BufWriter::new(File::create(path)?),
+            frame_rate,
+            sample_aspect_ratio,
+            header: None,
+        }),
+        FrameOutput::Raw(path) => Box::new(RawWriter {
+            file: BufWriter::new(File::create(path)?),
+        }),
+        FrameOutput::Png(directory) => {
+            std::fs::create_dir_all(directory)?;
+            Box::new(PngSequence {
+                directory: directory.clone(),
+                frame_number: 0,
+            })
+        }
+    })
+}
+
+struct NullFrameSink;
+
+impl FrameSink for NullFrameSink {
+    fn write_frame(&mut self, _: &DecodedFrame) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes YUV frames with their own subsampling and bit depth, the chroma of semi-planar frames
+/// is split into its own planes
+struct Y4mWriter {
+    file: BufWriter<File>,
+    frame_rate: Arc<Mutex<Option<(i32, i32)>>>,
+    sample_aspect_ratio: Arc<Mutex<Option<(i32, i32)>>>,
+    /// Size and colorspace from the header, which every frame of the file has to share
+    header: Option<(u32, u32, String)>,
+}
+
+impl FrameSink for Y4mWriter {
+    fn write_frame(&mut self, frame: &DecodedFrame) -> std::io::Result<()> {
+        let layout = FrameLayout::new(frame);
+        let format = &frame.format;
+        let subsampling = match format.chroma_shift {
+            (1, 1) => "420",
+            (1, 0) => "422",
+            (0, 0) => "444",
+            _ => "",
+        };
+        if format.rgb || subsampling.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} frames can't be written to Y4M", format.name()),
+            ));
+        }
+
+        // Y4M only has chroma siting tags for 8 bit 4:2:0
+        let colorspace = match format.bit_depth {
+            8 if subsampling == "420" => match frame.color.chroma_location {
+                AVChromaLocation::AVCHROMA_LOC_LEFT => "420mpeg2",
+                AVChromaLocation::AVCHROMA_LOC_TOPLEFT => "420paldv",
+                _ => "420jpeg",
+            }
+            .to_string(),
+            8 => subsampling.to_string(),
+            depth => format!("{subsampling}p{depth}"),
+        };
+        let stream = (frame.width, frame.height, colorspace);
+        match &self.header {
+            Some(header) if *header != stream => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "the video changed from {}x{} C{} to {}x{} C{}, a Y4M file holds a single size and format",
+                        header.0, header.1, header.2, stream.0, stream.1, stream.2
+                    ),
+                ));
+            }
+            Some(_) => (),
+            None => {
+                let (num, den) = self.frame_rate.lock().unwrap().unwrap_or((25, 1));
+                let (sar_num, sar_den) = self.sample_aspect_ratio.lock().unwrap().unwrap_or((1, 1));
+                writeln!(
+                    self.file,
+                    "YUV4MPEG2 W{} H{} F{}:{} Ip A{}:{} C{}",
+                    stream.0, stream.1, num, den, sar_num, sar_den, stream.2
+                )?;
+                self.header = Some(stream);
+            }
+        }
+
+        self.file.write_all(b"FRAME\n")?;
+        let mut row = vec![];
+        for component in 0..3 {
+            let (width, height) = layout.component_size(component);
+            for y in 0..height {
+                row.clear();
+                for x in 0..width {
+                    let sample = layout.sample(&frame.data, component, x, y);
+                    if format.bit_depth > 8 {
+                        row.extend_from_slice(&(sample >> (16 - format.bit_depth)).to_le_bytes());
+                    } else {
+                        row.push((sample >> 8) as u8);
+                    }
+                }
+                self.file.write_all(&row)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writes the planes in the layout they were decoded in, deep samples are in the high bits
+struct RawWriter {
+    file: BufWriter<File>,
+}
+
+impl FrameSink for RawWriter {
+    fn write_frame(&mut self, frame: &DecodedFrame) -> std::io::Result<()> {
+        let layout = FrameLayout::new(frame);
+        for plane in &layout.planes {
+            for y in 0..plane.rows {
+                let start = plane.offset + y * plane.linesize;
+                self.file
+                    .write_all(&frame.data[start..start + plane.row_size])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Converts every frame to 8-bit RGB with the matrix and range of its color metadata
+struct PngSequence {
+    directory: PathBuf,
+    frame_number: u64,
+}
+
+impl FrameSink for PngSequence {
+    fn write_frame(&mut self, frame: &DecodedFrame) -> std::io::Result<()> {
+        let layout = FrameLayout::new(frame);
+        let mut rgb = Vec::with_capacity(frame.width as usize * frame.height as usize * 3);
+
+        if frame.format.rgb {
+            let plane = &layout.planes[0];
+            let bgra = frame.format.format == AVPixelFormat::AV_PIX_FMT_BGRA;
+            for y in 0..plane.rows {
+                let start = plane.offset + y * plane.linesize;
+                for pixel in frame.data[start..start + plane.row_size].chunks_exact(4) {
+                    if bgra {
+                        rgb.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+                    } else {
+                        rgb.extend_from_slice(&pixel[..3]);
+                    }
+                }
+            }
+        } else {
+            let (kr, kb) = frame.color.luma_coefficients();
+            let kg = 1.0 - kr - kb;
+            let (shift_x, shift_y) = frame.format.chroma_shift;
+            // limited range puts black at 16 and white at 235, chroma is centered on 128 either way
+            let (luma_offset, luma_range, chroma_range) = if frame.color.is_full_range() {
+                (0.0, 255.0, 255.0)
+            } else {
+                (16.0, 219.0, 224.0)
+            };
+            let sample =
+                |component, x, y| (layout.sample(&frame.data, component, x, y) >> 8) as f32;
+
+            for y in 0..frame.height as usize {
+                for x in 0..frame.width as usize {
+                    let (chroma_x, chroma_y) = (x >> shift_x, y >> shift_y);
+                    let luma = (sample(0, x, y) - luma_offset) / luma_range;
+                    let cb = (sample(1, chroma_x, chroma_y) - 128.0) / chroma_range;
+                    let cr = (sample(2, chroma_x, chroma_y) - 128.0) / chroma_range;
+
+                    let r = luma + 2.0 * (1.0 - kr) * cr;
+                    let b = luma + 2.0 * (1.0 - kb) * cb;
+                    let g = (luma - kr * r - kb * b) / kg;
+                    for value in [r, g, b] {
+                        rgb.push((value * 255.0).round().clamp(0.0, 255.0) as u8);
+                    }
+                }
+            }
+        }
+
+        let path = self
+            .directory
+            .join(format!("frame-{:06}.png", self.frame_number));
+        self.frame_number += 1;
+
+        let mut encoder = png::Encoder::new(
+            BufWriter::new(File::create(path)?),
+            frame.width,
+            frame.height,
+        );
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&rgb))
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+    }
+}
+
+struct PlaneLayout {
+    /// Where the plane starts in `DecodedFrame::data`
+    offset: usize,
+    linesize: usize,
+    rows: usize,
+    /// Bytes in a row without the padding
+    row_size: usize,
+}
+
+/// Where the samples of a frame are, with a plane per component or a luma plane and an
+/// interleaved chroma plane
+struct FrameLayout {
+    planes: Vec<PlaneLayout>,
+    format: PixelFormat,
+    width: u32,
+    height: u32,
+}
+
+impl FrameLayout {
+    fn new(frame: &DecodedFrame) -> Self {
+        let format = frame.format;
+
+        let mut offset = 0;
+        let planes = frame
+            .linesizes
+            .iter()
+            .enumerate()
+            .map(|(index, linesize)| {
+                let linesize = *linesize as usize;
+                let (width, rows) = format.plane_size(index, frame.width, frame.height);
+                let plane = PlaneLayout {
+                    offset,
+                    linesize,
+                    rows: rows as usize,
+                    row_size: width as usize * format.pixel_size(index),
+                };
+                offset += linesize * rows as usize;
+                plane
+            })
+            .collect();
+
+        Self {
+            planes,
+            format,
+            width: frame.width,
+            height: frame.height,
+        }
+    }
+
+    /// Size of the Y, U or V component in samples
+    fn component_size(&self, component: usize) -> (usize, usize) {
+        let (width, height) = self.format.plane_size(component, self.width, self.height);
+        (width as usize, height as usize)
+    }
+
+    /// Sample `x`, `y` of the Y, U or V component scaled to 16 bits
+    fn sample(&self, data: &[u8], component: usize, x: usize, y: usize) -> u16 {
+        let (plane, index) = match component {
+            0 => (&self.planes[0], x),
+            _ if self.format.is_semi_planar() => (&self.planes[1], x * 2 + component - 1),
+            _ => (&self.planes[component], x),
+        };
+        let sample_size = self.format.bytes_per_sample();
+        let position = plane.offset + y * plane.linesize + index * sample_size;
+
+        if sample_size == 2 {
+            u16::from_le_bytes([data[position], data[position + 1]])
+        } else {
+            (data[position] as u16) << 8
+        }
+    }
+}
@@ -2,26 +2,47 @@ use beuk::ash::vk::PresentModeKHR;
 use beuk::ctx::RenderContextDescriptor;
 use beuk::raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle};
 
-use decoder::{DecodedFrame, MediaCommands, MediaDecoder, MediaDecoderOptions};
+use audio_sink::AudioOutput;
+use decoder::{
+    DecodedFrame, DownmixMode, MediaCommands, MediaDecoder, MediaDecoderOptions, MediaEvents,
+    Pacing, ReplayGainMode, Subtitle,
+};
 use dioxus_beuk::{DioxusApp, Redraw};
-use media_render_pass::MediaRenderPass;
+use headless::{FrameOutput, HeadlessOptions};
+use media_render_pass::{DisplayMode, MediaRenderPass, ToneMapping};
+use playlist::Playlist;
 use present_render_pass::PresentRenderPass;
+use subtitle_render_pass::SubtitleRenderPass;
 use tao::dpi::PhysicalSize;
 use tao::event::Event;
 use tao::event_loop::{ControlFlow, EventLoopBuilder};
+use tao::keyboard::ModifiersState;
 use tao::{event::WindowEvent, window::WindowBuilder};
 
-use std::sync::{Arc, RwLock};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
 
+mod audio_queue;
+mod audio_sink;
+mod av_sync;
 mod decoder;
+mod frame_pool;
+mod headless;
 mod media_render_pass;
+mod playlist;
 mod present_render_pass;
+mod subtitle_file;
+mod subtitle_render_pass;
 mod ui;
 
 #[derive(Clone)]
 pub struct CurrentVideo {
     pub width: u32,
     pub height: u32,
+    /// Width of a pixel relative to its height
+    pub sample_aspect_ratio: f32,
+    /// Clockwise degrees, 0, 90, 180 or 270
+    pub rotation: u32,
     // pub format: vk::Format,
 }
 
@@ -29,10 +50,63 @@ pub struct CurrentVideo {
 pub struct AppContext {
     window_size: PhysicalSize<u32>,
     command_sender: Option<crossbeam_channel::Sender<MediaCommands>>,
+    event_receiver: crossbeam_channel::Receiver<MediaEvents>,
+    /// Settings for the thread that draws the video
+    render_sender: crossbeam_channel::Sender<MediaRenderCommand>,
+    tone_mapping: ToneMapping,
+    display_mode: DisplayMode,
 }
 
 pub type AppContextRef = Arc<RwLock<AppContext>>;
 
+/// Work for the thread that owns the `MediaRenderPass`
+enum MediaRenderCommand {
+    Frame(DecodedFrame),
+    /// Audio-only media without cover art
+    Placeholder,
+    /// The video size changed, the buffers are recreated for the new size
+    Reset(CurrentVideo),
+    /// The subtitles on screen changed
+    Subtitles(Vec<Subtitle>),
+    /// Redraws the frame on screen with the new tone mapping
+    SetToneMapping(ToneMapping),
+    /// Redraws the frame on screen scaled the new way
+    SetDisplayMode(DisplayMode),
+}
+
+/// Subtitle files are shown on the video that is playing, anything else is opened or added to
+/// the playlist
+fn open_dropped_files(app_context: &AppContextRef, files: Vec<PathBuf>, enqueue: bool) {
+    let Some(command_sender) = app_context.read().unwrap().command_sender.clone() else {
+        return;
+    };
+
+    let (subtitle_files, media_files): (Vec<PathBuf>, Vec<PathBuf>) = files
+        .into_iter()
+        .partition(|path| subtitle_file::is_subtitle_file(path));
+    // runs on the event loop, which mustn't block on a decoder that stopped taking commands
+    let send = |command| {
+        if let Err(err) = command_sender.try_send(command) {
+            log::warn!("Could not open the dropped files: {}", err);
+        }
+    };
+    if let Some(subtitle_file) = subtitle_files.into_iter().last() {
+        send(MediaCommands::LoadSubtitleFile(subtitle_file));
+    }
+
+    if !media_files.is_empty() {
+        let paths = media_files
+            .into_iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
+        send(if enqueue {
+            MediaCommands::Enqueue(paths)
+        } else {
+            MediaCommands::Open(paths)
+        });
+    }
+}
+
 fn main() {
     #[cfg(feature = "tracing")]
     {
@@ -50,7 +124,77 @@ fn main() {
 
     std::env::set_var("RUST_LOG", "info");
     simple_logger::SimpleLogger::new().env().init().unwrap();
-    let args: Vec<String> = std::env::args().collect();
+    let mut media_paths = vec![];
+    let mut subtitle_file = None;
+    let mut audio_output = None;
+    let mut headless = false;
+    let mut frame_output = FrameOutput::Null;
+    let mut pacing = Pacing::Realtime;
+    let mut tone_mapping = ToneMapping::default();
+    let mut downmix = DownmixMode::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--subtitles" => subtitle_file = args.next().map(std::path::PathBuf::from),
+            // `null` or a path ending in `.wav` play without a sound card
+            "--audio-output" => {
+                audio_output = Some(match args.next().as_deref() {
+                    Some("null") => AudioOutput::Null,
+                    Some(path) if path.to_lowercase().ends_with(".wav") => {
+                        AudioOutput::Wav(PathBuf::from(path))
+                    }
+                    _ => AudioOutput::Cpal(None),
+                })
+            }
+            // an output device by name, one of `audio_sink::list_output_devices`
+            "--audio-device" => audio_output = Some(AudioOutput::Cpal(args.next())),
+            // plays without a window, `--frames` takes a `.y4m` or `.yuv` file or a directory
+            // for PNGs and `--fast` doesn't wait for the clock
+            "--headless" => headless = true,
+            "--frames" => {
+                if let Some(path) = args.next() {
+                    frame_output = FrameOutput::from_path(Path::new(&path));
+                }
+            }
+            "--fast" => pacing = Pacing::AsFastAsPossible,
+            // clip, reinhard, hable or bt2390 for HDR video
+            "--tone-mapping" => match args.next().as_deref().and_then(ToneMapping::from_name) {
+                Some(value) => tone_mapping = value,
+                None => log::warn!("Unknown tone mapping, using {}", tone_mapping.name()),
+            },
+            // itu, dialogue, dolby or dplii for sources with more channels than the output
+            "--downmix" => match args.next().as_deref().and_then(DownmixMode::from_name) {
+                Some(value) => downmix = value,
+                None => log::warn!("Unknown downmix, using {}", downmix.name()),
+            },
+            _ => media_paths.push(arg),
+        }
+    }
+
+    if headless {
+        let playlist = Playlist::new(media_paths);
+        if playlist.is_empty() {
+            log::info!("Please provide an url");
+            return;
+        }
+        let options = HeadlessOptions {
+            frame_output,
+            // there is no one listening anyway
+            audio_output: audio_output.unwrap_or(AudioOutput::Null),
+            pacing,
+            subtitle_file,
+            downmix,
+        };
+        match headless::run(playlist, options) {
+            Ok(frames) => log::info!("Played {} frames", frames),
+            Err(err) => {
+                log::error!("Headless playback failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let audio_output = audio_output.unwrap_or_default();
     let event_loop = EventLoopBuilder::<Redraw>::with_user_event().build();
 
     let window = WindowBuilder::new()
@@ -65,41 +209,159 @@ fn main() {
         present_mode: PresentModeKHR::default(),
     }));
 
+    let (media_event_tx, media_event_rx) = crossbeam_channel::unbounded::<MediaEvents>();
+    let (decoder_tx, decoder_rx) = crossbeam_channel::bounded::<MediaRenderCommand>(1);
     let app_context = Arc::new(RwLock::new(AppContext {
         command_sender: None,
+        event_receiver: media_event_rx,
         window_size: window.inner_size(),
+        render_sender: decoder_tx.clone(),
+        tone_mapping,
+        display_mode: DisplayMode::default(),
     }));
 
     let current_video: Arc<RwLock<Option<CurrentVideo>>> = Arc::new(RwLock::new(None));
-    let (decoder_tx, decoder_rx) = crossbeam_channel::bounded::<DecodedFrame>(1);
 
     std::thread::spawn({
-        let current_video = current_video.clone();
         let app_context = app_context.clone();
         move || {
-            let Some(arg) = args.get(1) else {
+            // commands sent while nothing is open, only opening files does something
+            let (idle_command_tx, idle_command_rx) =
+                crossbeam_channel::unbounded::<MediaCommands>();
+            // the volume and audio device carry over from one decoder to the next
+            let volume = Arc::new(Mutex::new((1.0f32, false)));
+            let audio_output = Arc::new(Mutex::new(audio_output));
+            let mut subtitle_file = subtitle_file;
+            let mut playlist = Playlist::new(media_paths);
+            if playlist.is_empty() {
                 log::info!("Please provide an url");
-                return;
-            };
-            let mut media_decoder = MediaDecoder::new(
-                arg,
-                MediaDecoderOptions { use_hw_accel: true },
-                move |frame| {
-                    decoder_tx.send(frame).unwrap();
-                },
-            );
+            }
+
+            loop {
+                if playlist.is_empty() {
+                    app_context.write().unwrap().command_sender = Some(idle_command_tx.clone());
+                    let paths = loop {
+                        match idle_command_rx.recv() {
+                            Ok(MediaCommands::Open(paths) | MediaCommands::Enqueue(paths)) => {
+                                break paths
+                            }
+                            Ok(MediaCommands::SetAudioDevice(device)) => {
+                                if let AudioOutput::Cpal(current) =
+                                    &mut *audio_output.lock().unwrap()
+                                {
+                                    *current = device;
+                                }
+                            }
+                            Ok(_) => (),
+                            Err(_) => return,
+                        }
+                    };
+                    playlist = Playlist::new(paths);
+                    continue;
+                }
 
-            let (width, height) = media_decoder.get_video_size();
-            *current_video.write().unwrap() = Some(CurrentVideo { width, height });
-            app_context.write().unwrap().command_sender =
-                Some(media_decoder.command_sender.clone());
-            media_decoder.start();
+                let (initial_volume, initial_muted) = *volume.lock().unwrap();
+                let mut media_decoder = match MediaDecoder::new(
+                    std::mem::replace(&mut playlist, Playlist::new(vec![])),
+                    MediaDecoderOptions {
+                        use_hw_accel: true,
+                        replay_gain: ReplayGainMode::Track,
+                        // only for the files given on the command line
+                        subtitle_file: subtitle_file.take(),
+                        volume: initial_volume,
+                        muted: initial_muted,
+                        audio_output: audio_output.lock().unwrap().clone(),
+                        pacing: Pacing::Realtime,
+                        downmix,
+                    },
+                    {
+                        let decoder_tx = decoder_tx.clone();
+                        // the channels close with the window, right before the decoder is dropped
+                        move |frame| {
+                            decoder_tx.send(MediaRenderCommand::Frame(frame)).ok();
+                        }
+                    },
+                    {
+                        let media_event_tx = media_event_tx.clone();
+                        let decoder_tx = decoder_tx.clone();
+                        let volume = volume.clone();
+                        let audio_output = audio_output.clone();
+                        move |event| match event {
+                            // subtitles are drawn by the render thread, the UI doesn't need them
+                            MediaEvents::Subtitles(subtitles) => {
+                                decoder_tx
+                                    .send(MediaRenderCommand::Subtitles(subtitles))
+                                    .ok();
+                            }
+                            event => {
+                                match event {
+                                    MediaEvents::VideoSize {
+                                        width,
+                                        height,
+                                        sample_aspect_ratio: (num, den),
+                                        rotation,
+                                    } => {
+                                        decoder_tx
+                                            .send(MediaRenderCommand::Reset(CurrentVideo {
+                                                width,
+                                                height,
+                                                sample_aspect_ratio: num as f32 / den as f32,
+                                                rotation,
+                                            }))
+                                            .ok();
+                                    }
+                                    MediaEvents::AudioOnly | MediaEvents::Closed => {
+                                        decoder_tx.send(MediaRenderCommand::Placeholder).ok();
+                                    }
+                                    MediaEvents::Volume {
+                                        volume: new_volume,
+                                        muted,
+                                    } => {
+                                        *volume.lock().unwrap() = (new_volume, muted);
+                                    }
+                                    MediaEvents::AudioDevice(device) => {
+                                        *audio_output.lock().unwrap() =
+                                            AudioOutput::Cpal(device.clone());
+                                    }
+                                    _ => (),
+                                }
+                                media_event_tx.send(event).ok();
+                            }
+                        }
+                    },
+                ) {
+                    Ok(media_decoder) => media_decoder,
+                    Err(err) => {
+                        log::error!("Failed to open the playlist: {}", err);
+                        media_event_tx.send(MediaEvents::Error(err)).ok();
+                        continue;
+                    }
+                };
+
+                app_context.write().unwrap().command_sender =
+                    Some(media_decoder.command_sender.clone());
+                match media_decoder.start() {
+                    Ok(Some(paths)) => playlist = Playlist::new(paths),
+                    Ok(None) => (),
+                    Err(err) => {
+                        log::error!("Decoder stopped: {}", err);
+                        media_event_tx.send(MediaEvents::Error(err)).ok();
+                    }
+                }
+                log::debug!("Frame pool: {:?}", media_decoder.frame_pool().stats());
+                log::debug!("A/V sync: {:?}", media_decoder.sync_stats());
+                // dropping the decoder stops its threads and closes the audio device
+                app_context.write().unwrap().command_sender = Some(idle_command_tx.clone());
+                drop(media_decoder);
+            }
         }
     });
 
     let mut present_node = PresentRenderPass::new(&ctx);
-    let mut media_node = MediaRenderPass::new(&ctx);
+    let mut media_node = MediaRenderPass::new(&ctx, tone_mapping);
     let media_attachment_handle = media_node.attachment.clone();
+    let mut subtitle_node = SubtitleRenderPass::new(&ctx);
+    let subtitle_attachment_handle = subtitle_node.attachment.clone();
 
     let mut application = DioxusApp::new(
         ui::app,
@@ -114,11 +376,47 @@ fn main() {
         let event_loop_proxy = event_loop.create_proxy();
         let current_video = current_video.clone();
         move || {
-            while let Ok(frame) = decoder_rx.recv() {
-                if let Some(current_video) = current_video.read().unwrap().as_ref() {
-                    media_node.setup_buffers(&ctx, current_video, &frame);
-                    media_node.draw(&ctx, &frame);
-                    event_loop_proxy.send_event(Redraw(false)).unwrap();
+            while let Ok(command) = decoder_rx.recv() {
+                match command {
+                    MediaRenderCommand::Frame(frame) => {
+                        if let Some(current_video) = current_video.read().unwrap().as_ref() {
+                            media_node.setup_buffers(&ctx, current_video, &frame);
+                            media_node.draw(&ctx, &frame);
+                            event_loop_proxy.send_event(Redraw(false)).unwrap();
+                        }
+                    }
+                    MediaRenderCommand::Placeholder => {
+                        *current_video.write().unwrap() = None;
+                        media_node.draw_placeholder(&ctx);
+                        event_loop_proxy.send_event(Redraw(false)).unwrap();
+                    }
+                    MediaRenderCommand::Reset(video) => {
+                        *current_video.write().unwrap() = Some(video);
+                        media_node.reset();
+                    }
+                    MediaRenderCommand::SetToneMapping(tone_mapping) => {
+                        media_node.set_tone_mapping(tone_mapping);
+                        if current_video.read().unwrap().is_some() {
+                            media_node.redraw(&ctx);
+                            event_loop_proxy.send_event(Redraw(false)).unwrap();
+                        }
+                    }
+                    MediaRenderCommand::SetDisplayMode(display_mode) => {
+                        media_node.set_display_mode(display_mode);
+                        if let Some(current_video) = current_video.read().unwrap().as_ref() {
+                            media_node.set_vertex_buffer(&ctx, current_video);
+                            media_node.redraw(&ctx);
+                            event_loop_proxy.send_event(Redraw(false)).unwrap();
+                        }
+                    }
+                    MediaRenderCommand::Subtitles(subtitles) => {
+                        subtitle_node.draw(
+                            &ctx,
+                            current_video.read().unwrap().as_ref(),
+                            &subtitles,
+                        );
+                        event_loop_proxy.send_event(Redraw(false)).unwrap();
+                    }
                 }
             }
         }
@@ -166,6 +464,10 @@ fn main() {
         }
     });
 
+    // every dropped file is its own event, they're opened together once the events are cleared
+    let mut dropped_files: Vec<PathBuf> = vec![];
+    let mut modifiers = ModifiersState::empty();
+
     event_loop.run(move |event, _, control_flow| {
         *control_flow = tao::event_loop::ControlFlow::Wait;
 
@@ -179,6 +481,7 @@ fn main() {
                 &ctx,
                 &ui_attachment_handle,
                 &media_attachment_handle,
+                &subtitle_attachment_handle,
                 present_index,
             );
             ctx.present_submit(present_index);
@@ -202,9 +505,20 @@ fn main() {
                     ctx.recreate_swapchain(size.width, size.height);
                     window.request_redraw();
                 }
+                WindowEvent::ModifiersChanged(state) => modifiers = state,
+                WindowEvent::DroppedFile(path) => dropped_files.push(path),
                 _ => (),
             },
 
+            // holding shift adds the files to the playlist instead of replacing it
+            tao::event::Event::MainEventsCleared if !dropped_files.is_empty() => {
+                open_dropped_files(
+                    &app_context,
+                    std::mem::take(&mut dropped_files),
+                    modifiers.shift_key(),
+                );
+            }
+
             tao::event::Event::NewEvents(tao::event::StartCause::ResumeTimeReached { .. }) => {
                 window.request_redraw();
             }
@@ -0,0 +1,275 @@
+use beuk::ash::vk;
+use stainless_ffmpeg::prelude::{
+    AVColorPrimaries, AVColorRange, AVColorSpace, AVColorTransferCharacteristic,
+};
+
+use crate::decoder::{ColorInfo, DecodedFrame, PixelFormat};
+
+/// Model and range of the YCbCr samplers beuk creates, frames with other color metadata are
+/// converted by `convert.frag`. The chroma siting of the sampler is used as it is
+const SAMPLER_CONVERSION: (vk::SamplerYcbcrModelConversion, vk::SamplerYcbcrRange) = (
+    vk::SamplerYcbcrModelConversion::YCBCR_709,
+    vk::SamplerYcbcrRange::ITU_NARROW,
+);
+
+/// The Vulkan conversion model and range for the color metadata, `None` when Vulkan doesn't
+/// have a model for the color space
+pub fn ycbcr_conversion(
+    color: &ColorInfo,
+) -> Option<(vk::SamplerYcbcrModelConversion, vk::SamplerYcbcrRange)> {
+    let model = match color.space {
+        AVColorSpace::AVCOL_SPC_BT709 => vk::SamplerYcbcrModelConversion::YCBCR_709,
+        AVColorSpace::AVCOL_SPC_BT470BG | AVColorSpace::AVCOL_SPC_SMPTE170M => {
+            vk::SamplerYcbcrModelConversion::YCBCR_601
+        }
+        AVColorSpace::AVCOL_SPC_BT2020_NCL => vk::SamplerYcbcrModelConversion::YCBCR_2020,
+        _ => return None,
+    };
+    let range = match color.range {
+        AVColorRange::AVCOL_RANGE_JPEG => vk::SamplerYcbcrRange::ITU_FULL,
+        _ => vk::SamplerYcbcrRange::ITU_NARROW,
+    };
+
+    Some((model, range))
+}
+
+/// Whether the sampler conversion gets the colors of `color` right, it can't tone map or
+/// convert between gamuts
+pub fn sampler_converts(color: &ColorInfo, output_primaries: AVColorPrimaries) -> bool {
+    ycbcr_conversion(color) == Some(SAMPLER_CONVERSION)
+        && !color.is_hdr()
+        && color.primaries == output_primaries
+}
+
+/// Format of the texture for a single plane when the shader does the conversion
+pub fn plane_format(format: &PixelFormat, plane: usize) -> vk::Format {
+    let interleaved = plane > 0 && format.is_semi_planar();
+    match (format.bytes_per_sample(), interleaved) {
+        (1, false) => vk::Format::R8_UNORM,
+        (1, true) => vk::Format::R8G8_UNORM,
+        (_, false) => vk::Format::R16_UNORM,
+        (_, true) => vk::Format::R16G16_UNORM,
+    }
+}
+
+/// How HDR video is brought down to the brightness of an SDR screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ToneMapping {
+    /// Cuts off everything brighter than SDR white
+    Clip,
+    Reinhard,
+    /// The filmic curve of Uncharted 2
+    Hable,
+    /// The EETF of ITU-R BT.2390, leaves the shadows and midtones alone and rolls off the
+    /// highlights
+    #[default]
+    Bt2390,
+}
+
+impl ToneMapping {
+    pub const ALL: [ToneMapping; 4] = [
+        ToneMapping::Clip,
+        ToneMapping::Reinhard,
+        ToneMapping::Hable,
+        ToneMapping::Bt2390,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ToneMapping::Clip => "clip",
+            ToneMapping::Reinhard => "reinhard",
+            ToneMapping::Hable => "hable",
+            ToneMapping::Bt2390 => "bt2390",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|tone_mapping| tone_mapping.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Luminance of SDR white in nits, HDR video is scaled so this ends up at the top of the screen
+const SDR_WHITE: f32 = 203.0;
+
+/// What HDR10 assumes when the stream doesn't say how bright it gets, and the peak of HLG
+const DEFAULT_PEAK: f32 = 1000.0;
+
+/// The primaries of the swapchain color space, which the video is converted to
+pub fn output_primaries(color_space: vk::ColorSpaceKHR) -> AVColorPrimaries {
+    match color_space {
+        vk::ColorSpaceKHR::DISPLAY_P3_NONLINEAR_EXT => AVColorPrimaries::AVCOL_PRI_SMPTE432,
+        _ => AVColorPrimaries::AVCOL_PRI_BT709,
+    }
+}
+
+/// xy chromaticities of red, green, blue and the white point
+fn chromaticities(primaries: AVColorPrimaries) -> [[f32; 2]; 4] {
+    const D65: [f32; 2] = [0.3127, 0.3290];
+    match primaries {
+        AVColorPrimaries::AVCOL_PRI_BT470BG => [[0.64, 0.33], [0.29, 0.60], [0.15, 0.06], D65],
+        AVColorPrimaries::AVCOL_PRI_SMPTE170M | AVColorPrimaries::AVCOL_PRI_SMPTE240M => {
+            [[0.630, 0.340], [0.310, 0.595], [0.155, 0.070], D65]
+        }
+        AVColorPrimaries::AVCOL_PRI_BT2020 => [[0.708, 0.292], [0.170, 0.797], [0.131, 0.046], D65],
+        AVColorPrimaries::AVCOL_PRI_SMPTE432 => {
+            [[0.680, 0.320], [0.265, 0.690], [0.150, 0.060], D65]
+        }
+        _ => [[0.64, 0.33], [0.30, 0.60], [0.15, 0.06], D65],
+    }
+}
+
+type Matrix = [[f32; 3]; 3];
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut result = [[0.0; 3]; 3];
+    for (row, result_row) in result.iter_mut().enumerate() {
+        for (column, value) in result_row.iter_mut().enumerate() {
+            *value = (0..3).map(|i| a[row][i] * b[i][column]).sum();
+        }
+    }
+    result
+}
+
+fn invert(m: &Matrix) -> Matrix {
+    let cofactor = |row: usize, column: usize| {
+        let (r0, r1) = ((row + 1) % 3, (row + 2) % 3);
+        let (c0, c1) = ((column + 1) % 3, (column + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let determinant = (0..3).map(|i| m[0][i] * cofactor(0, i)).sum::<f32>();
+
+    let mut result = [[0.0; 3]; 3];
+    for (row, result_row) in result.iter_mut().enumerate() {
+        for (column, value) in result_row.iter_mut().enumerate() {
+            *value = cofactor(column, row) / determinant;
+        }
+    }
+    result
+}
+
+/// Linear RGB to CIE XYZ
+fn rgb_to_xyz(primaries: AVColorPrimaries) -> Matrix {
+    let [red, green, blue, white] = chromaticities(primaries);
+    let xyz = |[x, y]: [f32; 2]| [x / y, 1.0, (1.0 - x - y) / y];
+    let (red, green, blue, white) = (xyz(red), xyz(green), xyz(blue), xyz(white));
+
+    let primaries = [
+        [red[0], green[0], blue[0]],
+        [red[1], green[1], blue[1]],
+        [red[2], green[2], blue[2]],
+    ];
+    // scales the primaries so they add up to the white point
+    let inverse = invert(&primaries);
+    let scale: Vec<f32> = (0..3)
+        .map(|row| (0..3).map(|i| inverse[row][i] * white[i]).sum())
+        .collect();
+
+    let mut result = primaries;
+    for row in result.iter_mut() {
+        for (column, value) in row.iter_mut().enumerate() {
+            *value *= scale[column];
+        }
+    }
+    result
+}
+
+/// Linear RGB with the `from` primaries to linear RGB with the `to` primaries
+fn gamut_matrix(from: AVColorPrimaries, to: AVColorPrimaries) -> Matrix {
+    multiply(&invert(&rgb_to_xyz(to)), &rgb_to_xyz(from))
+}
+
+/// Push constants of `convert.frag`
+#[repr(C)]
+#[derive(Clone, Debug, Copy, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ConversionConstants {
+    /// Columns of the matrix from the linear RGB of the video to that of the screen
+    gamut: [[f32; 4]; 3],
+    /// Turns the texture values into Y' between 0 and 1 and chroma between -0.5 and 0.5 together
+    /// with `offset`
+    scale: [f32; 4],
+    offset: [f32; 4],
+    /// Kr and Kb of the Y'CbCr matrix
+    coefficients: [f32; 2],
+    /// How far the chroma samples are from where the texture puts them, in chroma texels
+    chroma_offset: [f32; 2],
+    /// Brightest the video gets in nits
+    source_peak: f32,
+    semi_planar: i32,
+    /// 0 for SDR, 1 for PQ and 2 for HLG
+    transfer: i32,
+    tone_mapping: i32,
+}
+
+impl ConversionConstants {
+    pub fn new(
+        frame: &DecodedFrame,
+        tone_mapping: ToneMapping,
+        output_primaries: AVColorPrimaries,
+    ) -> Self {
+        let format = &frame.format;
+        let color = &frame.color;
+
+        let (kr, kb) = color.luma_coefficients();
+
+        // the texture value times this is the code value, deep samples are in the high bits
+        let depth = format.bit_depth;
+        let code_scale = if depth > 8 {
+            65535.0 / (1 << (16 - depth)) as f32
+        } else {
+            255.0
+        };
+        let (luma, chroma) = if color.is_full_range() {
+            let max = ((1u32 << depth) - 1) as f32;
+            (
+                (code_scale / max, 0.0),
+                (code_scale / max, (1u32 << (depth - 1)) as f32 / max),
+            )
+        } else {
+            // 16 to 235 and 16 to 240 at 8 bits, multiplied for deeper samples
+            let step = (1u32 << (depth - 8)) as f32;
+            (
+                (code_scale / (219.0 * step), 16.0 / 219.0),
+                (code_scale / (224.0 * step), 128.0 / 224.0),
+            )
+        };
+
+        let (position_x, position_y) = color.chroma_position();
+        let (shift_x, shift_y) = format.chroma_shift;
+        let siting =
+            |position: f32, shift: u32| (0.5 - position) * (1.0 - 0.5f32.powi(shift as i32));
+
+        let (transfer, source_peak) = match color.transfer {
+            AVColorTransferCharacteristic::AVCOL_TRC_SMPTE2084 => (
+                1,
+                frame
+                    .hdr_metadata
+                    .and_then(|metadata| metadata.peak_luminance())
+                    .filter(|peak| *peak > 0.0)
+                    .unwrap_or(DEFAULT_PEAK),
+            ),
+            AVColorTransferCharacteristic::AVCOL_TRC_ARIB_STD_B67 => (2, DEFAULT_PEAK),
+            _ => (0, SDR_WHITE),
+        };
+
+        let gamut = gamut_matrix(color.primaries, output_primaries);
+
+        Self {
+            gamut: [0, 1, 2]
+                .map(|column| [gamut[0][column], gamut[1][column], gamut[2][column], 0.0]),
+            scale: [luma.0, chroma.0, chroma.0, 0.0],
+            offset: [luma.1, chroma.1, chroma.1, 0.0],
+            coefficients: [kr, kb],
+            chroma_offset: [siting(position_x, shift_x), siting(position_y, shift_y)],
+            source_peak,
+            semi_planar: format.is_semi_planar() as i32,
+            transfer,
+            tone_mapping: tone_mapping as i32,
+        }
+    }
+
+    pub fn set_tone_mapping(&mut self, tone_mapping: ToneMapping) {
+        self.tone_mapping = tone_mapping as i32;
+    }
+}
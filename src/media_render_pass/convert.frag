@@ -0,0 +1,156 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+#extension GL_ARB_shading_language_420pack : enable
+
+// Y'CbCr to R'G'B' for color metadata the sampler conversion doesn't handle, every plane is a
+// plain texture. Semi-planar frames bind their chroma plane twice
+layout (set = 0, binding = 0) uniform sampler2D plane_0;
+layout (set = 0, binding = 1) uniform sampler2D plane_1;
+layout (set = 0, binding = 2) uniform sampler2D plane_2;
+
+layout (push_constant) uniform Conversion {
+    vec4 gamut[3];
+    vec4 scale;
+    vec4 offset;
+    vec2 coefficients;
+    vec2 chroma_offset;
+    float source_peak;
+    int semi_planar;
+    int transfer;
+    int tone_mapping;
+} conversion;
+
+layout (location = 0) in vec2 o_uv;
+layout (location = 0) out vec4 a_frag_color;
+
+const int TRANSFER_PQ = 1;
+const int TRANSFER_HLG = 2;
+
+const int TONE_MAPPING_REINHARD = 1;
+const int TONE_MAPPING_HABLE = 2;
+const int TONE_MAPPING_BT2390 = 3;
+
+// nits that end up as white on the screen
+const float SDR_WHITE = 203.0;
+
+// SMPTE ST 2084
+const float PQ_M1 = 0.1593017578125;
+const float PQ_M2 = 78.84375;
+const float PQ_C1 = 0.8359375;
+const float PQ_C2 = 18.8515625;
+const float PQ_C3 = 18.6875;
+
+vec3 pq_to_nits(vec3 value) {
+    vec3 p = pow(max(value, 0.0), vec3(1.0 / PQ_M2));
+    return 10000.0 * pow(max(p - PQ_C1, 0.0) / (PQ_C2 - PQ_C3 * p), vec3(1.0 / PQ_M1));
+}
+
+float pq_to_nits(float value) {
+    return pq_to_nits(vec3(value)).x;
+}
+
+float nits_to_pq(float nits) {
+    float y = pow(max(nits, 0.0) / 10000.0, PQ_M1);
+    return pow((PQ_C1 + PQ_C2 * y) / (1.0 + PQ_C3 * y), PQ_M2);
+}
+
+// ARIB STD-B67 back to scene light, then the OOTF of a 1000 nit display
+vec3 hlg_to_nits(vec3 value) {
+    const float a = 0.17883277;
+    const float b = 0.28466892;
+    const float c = 0.55991073;
+    value = max(value, 0.0);
+    vec3 scene = mix(value * value / 3.0, (exp((value - c) / a) + b) / 12.0, step(0.5, value));
+    float luma = dot(scene, vec3(0.2627, 0.6780, 0.0593));
+    return 1000.0 * pow(max(luma, 1e-6), 0.2) * scene;
+}
+
+float hable(float x) {
+    const float a = 0.15;
+    const float b = 0.50;
+    const float c = 0.10;
+    const float d = 0.20;
+    const float e = 0.02;
+    const float f = 0.30;
+    return (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f;
+}
+
+// ITU-R BT.2390 EETF in PQ space, the knee starts where the highlights need to be compressed
+float bt2390(float nits) {
+    float source = nits_to_pq(conversion.source_peak);
+    float e1 = min(nits_to_pq(nits) / source, 1.0);
+    float max_luminance = nits_to_pq(SDR_WHITE) / source;
+    float knee = 1.5 * max_luminance - 0.5;
+
+    float e2 = e1;
+    if (e1 > knee) {
+        float t = (e1 - knee) / (1.0 - knee);
+        float t2 = t * t;
+        float t3 = t2 * t;
+        e2 = (2.0 * t3 - 3.0 * t2 + 1.0) * knee
+            + (t3 - 2.0 * t2 + t) * (1.0 - knee)
+            + (-2.0 * t3 + 3.0 * t2) * max_luminance;
+    }
+    return pq_to_nits(e2 * source) / SDR_WHITE;
+}
+
+// `x` is relative to SDR white, the result is between 0 and 1
+float tone_map(float x) {
+    float peak = conversion.source_peak / SDR_WHITE;
+    if (peak <= 1.0) {
+        return min(x, 1.0);
+    }
+
+    switch (conversion.tone_mapping) {
+    case TONE_MAPPING_REINHARD:
+        return x * (1.0 + x / (peak * peak)) / (1.0 + x);
+    case TONE_MAPPING_HABLE:
+        return hable(x) / hable(peak);
+    case TONE_MAPPING_BT2390:
+        return bt2390(x * SDR_WHITE);
+    default:
+        return min(x, 1.0);
+    }
+}
+
+void main() {
+    vec2 chroma_uv = o_uv + conversion.chroma_offset / vec2(textureSize(plane_1, 0));
+
+    vec3 samples;
+    samples.x = texture(plane_0, o_uv).r;
+    if (conversion.semi_planar != 0) {
+        samples.yz = texture(plane_1, chroma_uv).rg;
+    } else {
+        samples.y = texture(plane_1, chroma_uv).r;
+        samples.z = texture(plane_2, chroma_uv).r;
+    }
+
+    vec3 ycbcr = samples * conversion.scale.xyz - conversion.offset.xyz;
+    float kr = conversion.coefficients.x;
+    float kb = conversion.coefficients.y;
+    float kg = 1.0 - kr - kb;
+    vec3 rgb = vec3(
+        ycbcr.x + 2.0 * (1.0 - kr) * ycbcr.z,
+        ycbcr.x - 2.0 * kb * (1.0 - kb) / kg * ycbcr.y - 2.0 * kr * (1.0 - kr) / kg * ycbcr.z,
+        ycbcr.x + 2.0 * (1.0 - kb) * ycbcr.y
+    );
+
+    // linear light, 1.0 is SDR white
+    vec3 linear;
+    if (conversion.transfer == TRANSFER_PQ || conversion.transfer == TRANSFER_HLG) {
+        linear = (conversion.transfer == TRANSFER_PQ ? pq_to_nits(rgb) : hlg_to_nits(rgb))
+            / SDR_WHITE;
+        // scaling all channels by the brightest one keeps the hue
+        float brightest = max(max(linear.r, linear.g), linear.b);
+        if (brightest > 0.0) {
+            linear *= tone_map(brightest) / brightest;
+        }
+    } else {
+        linear = pow(clamp(rgb, 0.0, 1.0), vec3(2.4));
+    }
+
+    mat3 gamut = mat3(conversion.gamut[0].xyz, conversion.gamut[1].xyz, conversion.gamut[2].xyz);
+    linear = clamp(gamut * linear, 0.0, 1.0);
+
+    a_frag_color = vec4(pow(linear, vec3(1.0 / 2.4)), 1.0);
+}
@@ -13,9 +13,16 @@ use beuk::{
     shaders::Shader,
 };
 
-use crate::decoder::DecodedFrame;
+use stainless_ffmpeg::prelude::{AVColorPrimaries, AVPixelFormat};
+
+use crate::decoder::{ColorInfo, DecodedFrame, PixelFormat};
 use crate::CurrentVideo;
 
+pub use self::conversion::ToneMapping;
+use self::conversion::{output_primaries, plane_format, sampler_converts, ConversionConstants};
+
+mod conversion;
+
 #[repr(C, align(16))]
 #[derive(Clone, Debug, Copy, Default, bytemuck::Pod, bytemuck::Zeroable)]
 struct Vertex {
@@ -34,13 +41,219 @@ pub struct MediaRenderPass {
     vertex_buffer: Option<ResourceHandle<Buffer>>,
     index_buffer: ResourceHandle<Buffer>,
     pub yuv: Option<ResourceHandle<Texture>>,
+    /// A texture for every plane when the shader converts the colors instead of the sampler
+    planes: Vec<ResourceHandle<Texture>>,
+    /// Set when the shader converts the colors, updated for every frame
+    conversion: Option<ConversionConstants>,
+    tone_mapping: ToneMapping,
+    /// Primaries of the swapchain color space
+    output_primaries: AVColorPrimaries,
     pub frame_buffer: Option<ResourceHandle<Buffer>>,
     pub attachment: ResourceHandle<Texture>,
-    pub current_aspect_ratio: f32,
+    /// Window size the vertex buffer was made for
+    pub current_screen_size: vk::Extent2D,
+    display_mode: DisplayMode,
+    /// Frames the buffers were set up for, a playlist item can decode to another format or size
+    current_layout: Option<BufferLayout>,
+}
+
+/// What the pipeline, the textures and the staging buffer are made for. The buffer holds the
+/// planes with their row padding, so a frame with other linesizes needs a new one too
+#[derive(Debug, Clone, PartialEq)]
+struct BufferLayout {
+    format: PixelFormat,
+    color: ColorInfo,
+    width: u32,
+    height: u32,
+    linesizes: Vec<i32>,
+}
+
+impl BufferLayout {
+    fn new(frame: &DecodedFrame) -> Self {
+        Self {
+            format: frame.format,
+            color: frame.color,
+            width: frame.width,
+            height: frame.height,
+            linesizes: frame.linesizes.clone(),
+        }
+    }
+}
+
+/// How the video is scaled to the window
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DisplayMode {
+    /// As big as fits in the window, with black bars
+    #[default]
+    Fit,
+    /// Fills the window and crops what doesn't fit
+    Fill,
+    /// Fills the window and ignores the aspect ratio
+    Stretch,
+    /// A video pixel for every screen pixel
+    Native,
+    /// Fits the video as if it had this aspect ratio
+    Aspect(u32, u32),
+}
+
+impl DisplayMode {
+    pub const ALL: [DisplayMode; 7] = [
+        DisplayMode::Fit,
+        DisplayMode::Fill,
+        DisplayMode::Stretch,
+        DisplayMode::Native,
+        DisplayMode::Aspect(4, 3),
+        DisplayMode::Aspect(16, 9),
+        DisplayMode::Aspect(235, 100),
+    ];
+
+    pub fn name(&self) -> String {
+        match self {
+            DisplayMode::Fit => "Fit".to_string(),
+            DisplayMode::Fill => "Fill".to_string(),
+            DisplayMode::Stretch => "Stretch".to_string(),
+            DisplayMode::Native => "1:1".to_string(),
+            DisplayMode::Aspect(width, 100) => format!("{}:1", *width as f32 / 100.0),
+            DisplayMode::Aspect(width, height) => format!("{width}:{height}"),
+        }
+    }
+}
+
+/// The two triangles that cover the part of the window the video is shown in, in clip space,
+/// with the texture coordinates turned upright for the rotation of the video
+fn get_vertices(
+    display_mode: DisplayMode,
+    current_video: &CurrentVideo,
+    screen_width: f32,
+    screen_height: f32,
+) -> [Vertex; 6] {
+    let screen_aspect = screen_width / screen_height;
+
+    // the size the video is shown at, turned on its side for 90 and 270 degrees
+    let sideways = current_video.rotation % 180 == 90;
+    let (video_width, video_height) = {
+        let width = current_video.width as f32 * current_video.sample_aspect_ratio;
+        let height = current_video.height as f32;
+        if sideways {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    };
+    let video_aspect = match display_mode {
+        DisplayMode::Aspect(width, height) => width as f32 / height as f32,
+        _ => video_width / video_height,
+    };
+
+    // half the width and height of the video in clip space, 1.0 covers the window
+    let (scale_x, scale_y) = match display_mode {
+        DisplayMode::Fit | DisplayMode::Aspect(..) => {
+            if screen_aspect > video_aspect {
+                // Pillarbox
+                (video_aspect / screen_aspect, 1.0)
+            } else {
+                // Letterbox
+                (1.0, screen_aspect / video_aspect)
+            }
+        }
+        // whatever sticks out of the window is cut off
+        DisplayMode::Fill => {
+            if screen_aspect > video_aspect {
+                (1.0, screen_aspect / video_aspect)
+            } else {
+                (video_aspect / screen_aspect, 1.0)
+            }
+        }
+        DisplayMode::Stretch => (1.0, 1.0),
+        DisplayMode::Native => (video_width / screen_width, video_height / screen_height),
+    };
+
+    let positions = [
+        [-scale_x, -scale_y],
+        [scale_x, -scale_y],
+        [scale_x, scale_y],
+        [-scale_x, -scale_y],
+        [scale_x, scale_y],
+        [-scale_x, scale_y],
+    ];
+    // the texture coordinates at each corner of the window, turned with the video
+    let uvs = [
+        [0.0, 0.0],
+        [1.0, 0.0],
+        [1.0, 1.0],
+        [0.0, 0.0],
+        [1.0, 1.0],
+        [0.0, 1.0],
+    ]
+    .map(|[u, v]| match current_video.rotation {
+        90 => [v, 1.0 - u],
+        180 => [1.0 - u, 1.0 - v],
+        270 => [1.0 - v, u],
+        _ => [u, v],
+    });
+
+    std::array::from_fn(|i| Vertex {
+        pos: positions[i],
+        uv: uvs[i],
+    })
+}
+
+fn linear_sampler() -> SamplerDesc {
+    SamplerDesc {
+        texel_filter: vk::Filter::LINEAR,
+        mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+        address_modes: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+    }
+}
+
+/// The texture format for a pixel format and the name of the sampler in the fragment shader,
+/// YCbCr samplers get the conversion for the format from the name
+fn texture_format(format: &PixelFormat) -> Option<(vk::Format, &'static str)> {
+    Some(match format.format {
+        AVPixelFormat::AV_PIX_FMT_YUV420P => (
+            vk::Format::G8_B8_R8_3PLANE_420_UNORM,
+            "textureLinearYUV420P",
+        ),
+        AVPixelFormat::AV_PIX_FMT_NV12 => (
+            vk::Format::G8_B8R8_2PLANE_420_UNORM,
+            "textureLinearYUV420SP",
+        ),
+        AVPixelFormat::AV_PIX_FMT_P010LE => (
+            vk::Format::G10X6_B10X6R10X6_2PLANE_420_UNORM_3PACK16,
+            "textureLinearYUV420SP10",
+        ),
+        AVPixelFormat::AV_PIX_FMT_P016LE => (
+            vk::Format::G16_B16R16_2PLANE_420_UNORM,
+            "textureLinearYUV420SP16",
+        ),
+        AVPixelFormat::AV_PIX_FMT_YUV420P10LE => (
+            vk::Format::G10X6_B10X6_R10X6_3PLANE_420_UNORM_3PACK16,
+            "textureLinearYUV420P10",
+        ),
+        AVPixelFormat::AV_PIX_FMT_YUV422P => (
+            vk::Format::G8_B8_R8_3PLANE_422_UNORM,
+            "textureLinearYUV422P",
+        ),
+        AVPixelFormat::AV_PIX_FMT_YUV422P10LE => (
+            vk::Format::G10X6_B10X6_R10X6_3PLANE_422_UNORM_3PACK16,
+            "textureLinearYUV422P10",
+        ),
+        AVPixelFormat::AV_PIX_FMT_YUV444P => (
+            vk::Format::G8_B8_R8_3PLANE_444_UNORM,
+            "textureLinearYUV444P",
+        ),
+        AVPixelFormat::AV_PIX_FMT_YUV444P10LE => (
+            vk::Format::G10X6_B10X6_R10X6_3PLANE_444_UNORM_3PACK16,
+            "textureLinearYUV444P10",
+        ),
+        AVPixelFormat::AV_PIX_FMT_RGBA => (vk::Format::R8G8B8A8_UNORM, "video_texture"),
+        AVPixelFormat::AV_PIX_FMT_BGRA => (vk::Format::B8G8R8A8_UNORM, "video_texture"),
+        _ => return None,
+    })
 }
 
 impl MediaRenderPass {
-    pub fn new(ctx: &RenderContext) -> Self {
+    pub fn new(ctx: &RenderContext, tone_mapping: ToneMapping) -> Self {
         let index_buffer = ctx.create_buffer_with_data(
             &BufferDescriptor {
                 debug_name: "indices",
@@ -54,6 +267,7 @@ impl MediaRenderPass {
 
         let swapchain = ctx.get_swapchain();
         let attachment_format = swapchain.surface_format.format;
+        let output_primaries = output_primaries(swapchain.surface_format.color_space);
         let attachment_handle = ctx.create_texture(
             "media",
             &ImageCreateInfo {
@@ -105,9 +319,15 @@ impl MediaRenderPass {
             vertex_buffer: None,
             index_buffer,
             yuv: None,
+            planes: vec![],
+            conversion: None,
+            tone_mapping,
+            output_primaries,
             frame_buffer: None,
             attachment: attachment_handle,
-            current_aspect_ratio: 0.0,
+            current_screen_size: vk::Extent2D::default(),
+            display_mode: DisplayMode::default(),
+            current_layout: None,
         }
     }
 
@@ -118,51 +338,47 @@ impl MediaRenderPass {
         frame: &DecodedFrame,
     ) {
         let screen_size = ctx.get_swapchain().surface_resolution;
-        let screen_aspect = screen_size.width as f32 / screen_size.height as f32;
-        if screen_aspect != self.current_aspect_ratio {
+        if screen_size != self.current_screen_size {
             self.set_vertex_buffer(ctx, current_video);
         }
         // means that we already have the buffers setup
-        if self.yuv.is_some() {
+        let layout = BufferLayout::new(frame);
+        if self.pipeline_handle.is_some() && self.current_layout.as_ref() == Some(&layout) {
             return;
         }
 
-        let is_10_bit = frame.linesizes.iter().sum::<i32>() > 15000;
+        let (video_format, sampler_name) = texture_format(&frame.format)
+            .unwrap_or_else(|| panic!("Unsupported pixel format {}", frame.format.name()));
+        // devices without a conversion for the format don't get a sampler for it
+        let conversion = (!frame.format.rgb
+            && !(sampler_converts(&frame.color, self.output_primaries)
+                && ctx
+                    .yuv_immutable_samplers
+                    .get(&(video_format, linear_sampler()))
+                    .is_some()))
+        .then(|| ConversionConstants::new(frame, self.tone_mapping, self.output_primaries));
         log::info!(
-            "is_10_bit: {} ({})",
-            is_10_bit,
-            frame.linesizes.iter().sum::<i32>()
-        );
-        let video_format = match frame.linesizes.len() {
-            // YUV420SP
-            2 => {
-                if is_10_bit {
-                    vk::Format::G10X6_B10X6R10X6_2PLANE_420_UNORM_3PACK16
-                } else {
-                    vk::Format::G8_B8R8_2PLANE_420_UNORM
-                }
-            }
-            // YUV420P
-            3 => {
-                if is_10_bit {
-                    vk::Format::G10X6_B10X6_R10X6_3PLANE_420_UNORM_3PACK16
-                } else {
-                    vk::Format::G8_B8_R8_3PLANE_420_UNORM
-                }
+            "Using format {:?} for {} with {:?}, converted by the {}",
+            video_format,
+            frame.format.name(),
+            frame.color,
+            if conversion.is_some() {
+                "shader"
+            } else {
+                "sampler"
             }
-            x => panic!("Unsupported format with {x} linesizes"),
-        };
-        log::info!("Using format {:?}", video_format);
+        );
 
-        let pipeline_handle = ctx.create_graphics_pipeline(&GraphicsPipelineDescriptor {
-            vertex_shader: Shader::from_source_text(
+        let fragment_shader = if conversion.is_some() {
+            Shader::from_source_text(
                 &ctx.device,
-                include_str!("./shader.vert"),
-                "shader.vert",
-                beuk::shaders::ShaderKind::Vertex,
+                include_str!("./convert.frag"),
+                "convert.frag",
+                beuk::shaders::ShaderKind::Fragment,
                 "main",
-            ),
-            fragment_shader: Shader::from_source_text(
+            )
+        } else {
+            Shader::from_source_text(
                 &ctx.device,
                 &r#"
                 #version 450
@@ -177,24 +393,22 @@ impl MediaRenderPass {
                     void main() {
                         a_frag_color = texture(textureLinearYUV420P, o_uv);
                     }"#
-                .replace(
-                    "textureLinearYUV420P",
-                    match video_format {
-                        vk::Format::G8_B8R8_2PLANE_420_UNORM => "textureLinearYUV420SP",
-                        vk::Format::G8_B8_R8_3PLANE_420_UNORM => "textureLinearYUV420P",
-                        vk::Format::G10X6_B10X6R10X6_2PLANE_420_UNORM_3PACK16 => {
-                            "textureLinearYUV420SP10"
-                        }
-                        vk::Format::G10X6_B10X6_R10X6_3PLANE_420_UNORM_3PACK16 => {
-                            "textureLinearYUV420P10"
-                        }
-                        _ => panic!("Unsupported format"),
-                    },
-                ),
+                .replace("textureLinearYUV420P", sampler_name),
                 "shader.frag",
                 beuk::shaders::ShaderKind::Fragment,
                 "main",
+            )
+        };
+
+        let pipeline_handle = ctx.create_graphics_pipeline(&GraphicsPipelineDescriptor {
+            vertex_shader: Shader::from_source_text(
+                &ctx.device,
+                include_str!("./shader.vert"),
+                "shader.vert",
+                beuk::shaders::ShaderKind::Vertex,
+                "main",
             ),
+            fragment_shader,
             vertex_input: PipelineVertexInputStateCreateInfo::default()
                 .vertex_attribute_descriptions(&[
                     vk::VertexInputAttributeDescription {
@@ -223,19 +437,107 @@ impl MediaRenderPass {
                 ..Default::default()
             },
             depth_stencil: Default::default(),
-            push_constant_range: None,
+            push_constant_range: conversion.map(|_| {
+                vk::PushConstantRange::default()
+                    .stage_flags(vk::ShaderStageFlags::ALL_GRAPHICS)
+                    .offset(0)
+                    .size(std::mem::size_of::<ConversionConstants>() as u32)
+            }),
             blend: Default::default(),
             multisample: Default::default(),
         });
+        let pipeline = ctx.graphics_pipelines.get(&pipeline_handle).unwrap();
+
+        self.yuv = None;
+        self.planes.clear();
+        if conversion.is_some() {
+            let sampler = *ctx.immutable_samplers.get(&linear_sampler()).unwrap();
+            for plane in 0..frame.format.planes {
+                let (width, height) = frame.format.plane_size(plane, frame.width, frame.height);
+                self.planes.push(ctx.create_texture(
+                    "video_plane",
+                    &ImageCreateInfo {
+                        image_type: vk::ImageType::TYPE_2D,
+                        format: plane_format(&frame.format, plane),
+                        extent: vk::Extent3D {
+                            width,
+                            height,
+                            depth: 1,
+                        },
+                        samples: vk::SampleCountFlags::TYPE_1,
+                        usage: vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
+                        mip_levels: 1,
+                        array_layers: 1,
+                        sharing_mode: vk::SharingMode::EXCLUSIVE,
+                        ..Default::default()
+                    },
+                    true,
+                ));
+            }
+
+            // the chroma of semi-planar frames is bound to both chroma samplers
+            let image_infos: Vec<_> = (0..3)
+                .map(|binding| {
+                    let plane = &self.planes[binding.min(self.planes.len() - 1)];
+                    vk::DescriptorImageInfo::default()
+                        .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                        .image_view(*ctx.get_texture_view(plane).unwrap())
+                        .sampler(sampler)
+                })
+                .collect();
+            let writes: Vec<_> = image_infos
+                .iter()
+                .enumerate()
+                .map(|(binding, image_info)| {
+                    vk::WriteDescriptorSet::default()
+                        .dst_set(pipeline.descriptor_sets[0])
+                        .dst_binding(binding as u32)
+                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                        .image_info(std::slice::from_ref(image_info))
+                })
+                .collect();
+            unsafe {
+                ctx.device.update_descriptor_sets(&writes, &[]);
+            }
+        } else {
+            self.yuv = Some(Self::create_video_texture(
+                ctx,
+                &pipeline,
+                frame,
+                video_format,
+            ));
+        }
+
+        log::debug!("Creating frame buffer of size {}", frame.data.len());
+
+        let frame_buffer = ctx.create_buffer(&BufferDescriptor {
+            debug_name: "frame",
+            size: frame.data.len() as DeviceSize,
+            location: MemoryLocation::CpuToGpu,
+            usage: BufferUsageFlags::TRANSFER_SRC | BufferUsageFlags::TRANSFER_DST,
+        });
 
+        self.frame_buffer = Some(frame_buffer);
+        self.pipeline_handle = Some(pipeline_handle);
+        self.conversion = conversion;
+        self.current_layout = Some(layout);
+    }
+
+    /// A single texture that the sampler reads as RGB, through its YCbCr conversion for YUV
+    fn create_video_texture(
+        ctx: &RenderContext,
+        pipeline: &GraphicsPipeline,
+        frame: &DecodedFrame,
+        video_format: vk::Format,
+    ) -> ResourceHandle<Texture> {
         let yuv = ctx.create_texture(
             "yuv420",
             &ImageCreateInfo {
                 image_type: vk::ImageType::TYPE_2D,
                 format: video_format,
                 extent: vk::Extent3D {
-                    width: current_video.width,
-                    height: current_video.height,
+                    width: frame.width,
+                    height: frame.height,
                     depth: 1,
                 },
                 samples: vk::SampleCountFlags::TYPE_1,
@@ -248,19 +550,28 @@ impl MediaRenderPass {
             true,
         );
 
-        {
+        if frame.format.rgb {
+            unsafe {
+                ctx.device.update_descriptor_sets(
+                    &[vk::WriteDescriptorSet::default()
+                        .dst_set(pipeline.descriptor_sets[0])
+                        .dst_binding(0)
+                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                        .image_info(std::slice::from_ref(
+                            &vk::DescriptorImageInfo::default()
+                                .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                                .image_view(*ctx.get_texture_view(&yuv).unwrap())
+                                .sampler(*ctx.immutable_samplers.get(&linear_sampler()).unwrap()),
+                        ))],
+                    &[],
+                );
+            };
+        } else {
             let texture = ctx.texture_manager.get(&yuv).unwrap();
 
             let (sampler_conversion, _) = ctx
                 .yuv_immutable_samplers
-                .get(&(
-                    video_format,
-                    SamplerDesc {
-                        texel_filter: vk::Filter::LINEAR,
-                        mipmap_mode: vk::SamplerMipmapMode::LINEAR,
-                        address_modes: vk::SamplerAddressMode::CLAMP_TO_EDGE,
-                    },
-                ))
+                .get(&(video_format, linear_sampler()))
                 .unwrap();
 
             let view = unsafe {
@@ -291,7 +602,6 @@ impl MediaRenderPass {
                 )
             }
             .unwrap();
-            let pipeline = ctx.graphics_pipelines.get(&pipeline_handle).unwrap();
             unsafe {
                 ctx.device.update_descriptor_sets(
                     &[vk::WriteDescriptorSet::default()
@@ -308,50 +618,36 @@ impl MediaRenderPass {
             };
         }
 
-        log::debug!("Creating frame buffer of size {}", frame.data.len());
+        yuv
+    }
 
-        let frame_buffer = ctx.create_buffer(&BufferDescriptor {
-            debug_name: "frame",
-            size: frame.data.len() as DeviceSize,
-            location: MemoryLocation::CpuToGpu,
-            usage: BufferUsageFlags::TRANSFER_SRC | BufferUsageFlags::TRANSFER_DST,
-        });
+    /// Drops the video buffers so the next frame sets them up again
+    pub fn reset(&mut self) {
+        self.yuv = None;
+        self.planes.clear();
+        self.conversion = None;
+        self.frame_buffer = None;
+        self.pipeline_handle = None;
+        self.current_screen_size = vk::Extent2D::default();
+        self.current_layout = None;
+    }
 
-        self.yuv = Some(yuv);
-        self.frame_buffer = Some(frame_buffer);
-        self.pipeline_handle = Some(pipeline_handle);
+    pub fn set_display_mode(&mut self, display_mode: DisplayMode) {
+        self.display_mode = display_mode;
+        // the vertex buffer is made again for the next frame
+        self.current_screen_size = vk::Extent2D::default();
     }
 
     pub fn set_vertex_buffer(&mut self, ctx: &RenderContext, current_video: &CurrentVideo) {
         let screen_size = ctx.get_swapchain().surface_resolution;
-        let screen_aspect = screen_size.width as f32 / screen_size.height as f32;
-        let video_aspect = current_video.width as f32 / current_video.height as f32;
-
-        let positions = if screen_aspect > video_aspect {
-            // Pillarbox
-            let scale_x = video_aspect / screen_aspect;
-            [
-                [-scale_x, -1.0],
-                [scale_x, -1.0],
-                [scale_x, 1.0],
-                [-scale_x, -1.0],
-                [scale_x, 1.0],
-                [-scale_x, 1.0],
-            ]
-        } else {
-            // Letterbox
-            let scale_y = screen_aspect / video_aspect;
-            [
-                [-1.0, -scale_y],
-                [1.0, -scale_y],
-                [1.0, scale_y],
-                [-1.0, -scale_y],
-                [1.0, scale_y],
-                [-1.0, scale_y],
-            ]
-        };
+        let vertices = get_vertices(
+            self.display_mode,
+            current_video,
+            screen_size.width as f32,
+            screen_size.height as f32,
+        );
 
-        self.current_aspect_ratio = screen_aspect;
+        self.current_screen_size = screen_size;
         self.vertex_buffer = Some(ctx.create_buffer_with_data(
             &BufferDescriptor {
                 debug_name: "vertices",
@@ -359,32 +655,7 @@ impl MediaRenderPass {
                 usage: BufferUsageFlags::VERTEX_BUFFER,
                 location: MemoryLocation::GpuOnly,
             },
-            bytemuck::cast_slice(&[
-                Vertex {
-                    pos: positions[0],
-                    uv: [0.0, 0.0],
-                },
-                Vertex {
-                    pos: positions[1],
-                    uv: [1.0, 0.0],
-                },
-                Vertex {
-                    pos: positions[2],
-                    uv: [1.0, 1.0],
-                },
-                Vertex {
-                    pos: positions[3],
-                    uv: [0.0, 0.0],
-                },
-                Vertex {
-                    pos: positions[4],
-                    uv: [1.0, 1.0],
-                },
-                Vertex {
-                    pos: positions[5],
-                    uv: [0.0, 1.0],
-                },
-            ]),
+            bytemuck::cast_slice(&vertices),
             0,
         ));
     }
@@ -395,166 +666,109 @@ impl MediaRenderPass {
         ctx: &RenderContext,
         frame: &DecodedFrame,
     ) {
-        let Some(yuv) = self.yuv.as_ref() else {
-            return;
-        };
-
         let Some(frame_buffer) = self.frame_buffer.as_ref() else {
             return;
         };
 
-        let buffer = ctx
-            .buffer_manager
-            .get_mut(self.frame_buffer.as_ref().unwrap())
-            .unwrap();
-        buffer.copy_from_slice(&frame.data, 0);
+        let format = &frame.format;
+        // every plane goes to its own texture, or to a plane of the single video texture
+        let targets: Vec<(&ResourceHandle<Texture>, vk::ImageAspectFlags)> =
+            if let Some(yuv) = self.yuv.as_ref() {
+                let aspects = [
+                    vk::ImageAspectFlags::PLANE_0,
+                    vk::ImageAspectFlags::PLANE_1,
+                    vk::ImageAspectFlags::PLANE_2,
+                ];
+                (0..format.planes)
+                    .map(|plane| {
+                        if format.rgb {
+                            (yuv, vk::ImageAspectFlags::COLOR)
+                        } else {
+                            (yuv, aspects[plane])
+                        }
+                    })
+                    .collect()
+            } else {
+                self.planes
+                    .iter()
+                    .map(|plane| (plane, vk::ImageAspectFlags::COLOR))
+                    .collect()
+            };
+        if targets.len() != format.planes {
+            return;
+        }
 
-        let texture = ctx.texture_manager.get_mut(yuv).unwrap();
+        let buffer = ctx.buffer_manager.get_mut(frame_buffer).unwrap();
+        buffer.copy_from_slice(frame.data.as_slice(), 0);
         let frame_buffer = ctx.buffer_manager.get(frame_buffer).unwrap();
 
-        texture.transition(
-            &ctx.device,
-            command_buffer,
-            &TransitionDesc {
-                new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                new_access_mask: vk::AccessFlags::TRANSFER_WRITE,
-                new_stage_mask: vk::PipelineStageFlags::TRANSFER,
-            },
-        );
+        let textures = self.yuv.iter().chain(self.planes.iter());
+        for handle in textures.clone() {
+            ctx.texture_manager.get_mut(handle).unwrap().transition(
+                &ctx.device,
+                command_buffer,
+                &TransitionDesc {
+                    new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    new_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                    new_stage_mask: vk::PipelineStageFlags::TRANSFER,
+                },
+            );
+        }
 
-        let y_plane_size = frame.linesizes[0] as usize * texture.extent.height as usize;
-        let u_plane_size = frame.linesizes[1] as usize * texture.extent.height as usize / 2;
-
-        match texture.format {
-            vk::Format::G8_B8R8_2PLANE_420_UNORM
-            | vk::Format::G10X6_B10X6R10X6_2PLANE_420_UNORM_3PACK16 => {
-                log::debug!("copying PLANE_0 with offset {:?}", 0);
-                ctx.device.cmd_copy_buffer_to_image(
-                    command_buffer,
-                    frame_buffer.buffer,
-                    texture.image,
-                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                    &[vk::BufferImageCopy::default()
-                        .buffer_offset(0)
-                        .buffer_row_length(0)
-                        .buffer_image_height(0)
-                        .image_subresource(vk::ImageSubresourceLayers {
-                            aspect_mask: vk::ImageAspectFlags::PLANE_0,
-                            layer_count: 1,
-                            ..Default::default()
-                        })
-                        .image_extent(texture.extent)],
-                );
+        let mut offset = 0;
+        for (plane, (handle, aspect_mask)) in targets.into_iter().enumerate() {
+            let texture = ctx.texture_manager.get(handle).unwrap();
+            let (width, height) = format.plane_size(plane, frame.width, frame.height);
 
-                log::debug!("copying PLANE_1 with offset {:?}", y_plane_size);
-                ctx.device.cmd_copy_buffer_to_image(
-                    command_buffer,
-                    frame_buffer.buffer,
-                    texture.image,
-                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                    &[vk::BufferImageCopy::default()
-                        .buffer_offset(y_plane_size as DeviceSize)
-                        .buffer_row_length(0)
-                        .buffer_image_height(0)
-                        .image_subresource(vk::ImageSubresourceLayers {
-                            aspect_mask: vk::ImageAspectFlags::PLANE_1,
-                            layer_count: 1,
-                            ..Default::default()
-                        })
-                        .image_extent(vk::Extent3D {
-                            width: texture.extent.width / 2,
-                            height: texture.extent.height / 2,
-                            depth: 1,
-                        })],
-                );
-            }
-            vk::Format::G8_B8_R8_3PLANE_420_UNORM
-            | vk::Format::G10X6_B10X6_R10X6_3PLANE_420_UNORM_3PACK16 => {
-                log::debug!("copying PLANE_0 with offset {:?}", 0);
-                ctx.device.cmd_copy_buffer_to_image(
-                    command_buffer,
-                    frame_buffer.buffer,
-                    texture.image,
-                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                    &[vk::BufferImageCopy::default()
-                        .buffer_offset(0)
-                        .buffer_row_length(0)
-                        .buffer_image_height(0)
-                        .image_subresource(vk::ImageSubresourceLayers {
-                            aspect_mask: vk::ImageAspectFlags::PLANE_0,
-                            layer_count: 1,
-                            ..Default::default()
-                        })
-                        .image_extent(texture.extent)],
-                );
-
-                log::debug!("copying PLANE_1 with offset {:?}", y_plane_size);
-                ctx.device.cmd_copy_buffer_to_image(
-                    command_buffer,
-                    frame_buffer.buffer,
-                    texture.image,
-                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                    &[vk::BufferImageCopy::default()
-                        .buffer_offset(y_plane_size as DeviceSize)
-                        .buffer_row_length(0)
-                        .buffer_image_height(0)
-                        .image_subresource(vk::ImageSubresourceLayers {
-                            aspect_mask: vk::ImageAspectFlags::PLANE_1,
-                            layer_count: 1,
-                            ..Default::default()
-                        })
-                        .image_extent(vk::Extent3D {
-                            width: texture.extent.width / 2,
-                            height: texture.extent.height / 2,
-                            depth: 1,
-                        })],
-                );
+            log::debug!(
+                "copying plane {} to {:?} with offset {:?}",
+                plane,
+                aspect_mask,
+                offset
+            );
+            ctx.device.cmd_copy_buffer_to_image(
+                command_buffer,
+                frame_buffer.buffer,
+                texture.image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[vk::BufferImageCopy::default()
+                    .buffer_offset(offset as DeviceSize)
+                    // in pixels, the rows keep the padding ffmpeg added
+                    .buffer_row_length(
+                        frame.linesizes[plane] as u32 / format.pixel_size(plane) as u32,
+                    )
+                    .buffer_image_height(0)
+                    .image_subresource(vk::ImageSubresourceLayers {
+                        aspect_mask,
+                        layer_count: 1,
+                        ..Default::default()
+                    })
+                    .image_extent(vk::Extent3D {
+                        width,
+                        height,
+                        depth: 1,
+                    })],
+            );
 
-                log::debug!(
-                    "copying PLANE_2 with offset {:?}",
-                    y_plane_size + u_plane_size
-                );
-                ctx.device.cmd_copy_buffer_to_image(
-                    command_buffer,
-                    frame_buffer.buffer,
-                    texture.image,
-                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                    &[vk::BufferImageCopy::default()
-                        .buffer_offset((y_plane_size + u_plane_size) as DeviceSize)
-                        .buffer_row_length(0)
-                        .buffer_image_height(0)
-                        .image_subresource(vk::ImageSubresourceLayers {
-                            aspect_mask: vk::ImageAspectFlags::PLANE_2,
-                            layer_count: 1,
-                            ..Default::default()
-                        })
-                        .image_extent(vk::Extent3D {
-                            width: texture.extent.width / 2,
-                            height: texture.extent.height / 2,
-                            depth: 1,
-                        })],
-                );
-            }
-            _ => panic!("Unsupported format"),
+            offset += frame.linesizes[plane] as usize * height as usize;
         }
 
-        texture.transition(
-            &ctx.device,
-            command_buffer,
-            &TransitionDesc {
-                new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-                new_access_mask: vk::AccessFlags::TRANSFER_WRITE,
-                new_stage_mask: vk::PipelineStageFlags::TRANSFER,
-            },
-        );
+        for handle in textures {
+            ctx.texture_manager.get_mut(handle).unwrap().transition(
+                &ctx.device,
+                command_buffer,
+                &TransitionDesc {
+                    new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    new_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                    new_stage_mask: vk::PipelineStageFlags::TRANSFER,
+                },
+            );
+        }
     }
 
-    #[tracing::instrument(name = "MediaRenderPass::draw", skip_all)]
-    pub fn draw(&self, ctx: &RenderContext, frame: &DecodedFrame) {
-        log::debug!("Copying frame to gpu");
-
+    /// Fills the attachment with a neutral color for media that has nothing to show
+    pub fn draw_placeholder(&self, ctx: &RenderContext) {
         ctx.record_submit(|ctx, command_buffer| unsafe {
-            self.copy_yuv420_frame_to_gpu(command_buffer, ctx, frame);
             let color_attachments = &[vk::RenderingAttachmentInfo::default()
                 .image_view(*ctx.get_texture_view(&self.attachment).unwrap())
                 .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
@@ -562,36 +776,171 @@ impl MediaRenderPass {
                 .store_op(vk::AttachmentStoreOp::STORE)
                 .clear_value(vk::ClearValue {
                     color: vk::ClearColorValue {
-                        float32: [0.0, 0.0, 0.0, 1.0],
+                        // slate-900
+                        float32: [0.059, 0.09, 0.165, 1.0],
                     },
                 })];
 
             ctx.begin_rendering(command_buffer, color_attachments, None);
+            ctx.end_rendering(command_buffer);
+        });
+    }
+
+    pub fn set_tone_mapping(&mut self, tone_mapping: ToneMapping) {
+        self.tone_mapping = tone_mapping;
+        if let Some(conversion) = self.conversion.as_mut() {
+            conversion.set_tone_mapping(tone_mapping);
+        }
+    }
+
+    #[tracing::instrument(name = "MediaRenderPass::draw", skip_all)]
+    pub fn draw(&mut self, ctx: &RenderContext, frame: &DecodedFrame) {
+        log::debug!("Copying frame to gpu");
+        // the HDR metadata can change from frame to frame
+        if self.conversion.is_some() {
+            self.conversion = Some(ConversionConstants::new(
+                frame,
+                self.tone_mapping,
+                self.output_primaries,
+            ));
+        }
+
+        ctx.record_submit(|ctx, command_buffer| unsafe {
+            self.copy_yuv420_frame_to_gpu(command_buffer, ctx, frame);
+            self.render(ctx, command_buffer);
+        });
+        log::debug!("Frame copied to gpu");
+    }
 
-            ctx.graphics_pipelines
-                .get(self.pipeline_handle.as_ref().unwrap())
-                .unwrap()
-                .bind(&ctx.device, command_buffer);
-            ctx.device.cmd_bind_vertex_buffers(
+    /// Draws the last frame again, after a setting that changes how it looks
+    pub fn redraw(&self, ctx: &RenderContext) {
+        if self.pipeline_handle.is_none() {
+            return;
+        }
+
+        ctx.record_submit(|ctx, command_buffer| unsafe {
+            self.render(ctx, command_buffer);
+        });
+    }
+
+    unsafe fn render(&self, ctx: &RenderContext, command_buffer: vk::CommandBuffer) {
+        let color_attachments = &[vk::RenderingAttachmentInfo::default()
+            .image_view(*ctx.get_texture_view(&self.attachment).unwrap())
+            .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .load_op(vk::AttachmentLoadOp::CLEAR)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .clear_value(vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: [0.0, 0.0, 0.0, 1.0],
+                },
+            })];
+
+        ctx.begin_rendering(command_buffer, color_attachments, None);
+
+        let pipeline = ctx
+            .graphics_pipelines
+            .get(self.pipeline_handle.as_ref().unwrap())
+            .unwrap();
+        pipeline.bind(&ctx.device, command_buffer);
+        if let Some(conversion) = self.conversion.as_ref() {
+            ctx.device.cmd_push_constants(
                 command_buffer,
+                pipeline.layout,
+                vk::ShaderStageFlags::ALL_GRAPHICS,
                 0,
-                std::slice::from_ref(
-                    &ctx.buffer_manager
-                        .get(self.vertex_buffer.as_ref().unwrap())
-                        .unwrap()
-                        .buffer,
-                ),
-                &[0],
+                bytemuck::bytes_of(conversion),
             );
-            ctx.device.cmd_bind_index_buffer(
-                command_buffer,
-                ctx.buffer_manager.get(&self.index_buffer).unwrap().buffer,
-                0,
-                vk::IndexType::UINT32,
+        }
+        ctx.device.cmd_bind_vertex_buffers(
+            command_buffer,
+            0,
+            std::slice::from_ref(
+                &ctx.buffer_manager
+                    .get(self.vertex_buffer.as_ref().unwrap())
+                    .unwrap()
+                    .buffer,
+            ),
+            &[0],
+        );
+        ctx.device.cmd_bind_index_buffer(
+            command_buffer,
+            ctx.buffer_manager.get(&self.index_buffer).unwrap().buffer,
+            0,
+            vk::IndexType::UINT32,
+        );
+        ctx.device.cmd_draw_indexed(command_buffer, 6, 1, 0, 0, 1);
+        ctx.end_rendering(command_buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(width: u32, height: u32, sample_aspect_ratio: f32, rotation: u32) -> CurrentVideo {
+        CurrentVideo {
+            width,
+            height,
+            sample_aspect_ratio,
+            rotation,
+        }
+    }
+
+    /// Half the width and height of the video in clip space, from its top right corner
+    fn scale(display_mode: DisplayMode, video: &CurrentVideo) -> (f32, f32) {
+        let [x, y] = get_vertices(display_mode, video, 1600.0, 900.0)[2].pos;
+        ((x * 1000.0).round() / 1000.0, (y * 1000.0).round() / 1000.0)
+    }
+
+    #[test]
+    fn scales_a_4_3_video_in_a_16_9_window() {
+        let video = video(1440, 1080, 1.0, 0);
+        assert_eq!(scale(DisplayMode::Fit, &video), (0.75, 1.0));
+        assert_eq!(scale(DisplayMode::Fill, &video), (1.0, 1.333));
+        assert_eq!(scale(DisplayMode::Stretch, &video), (1.0, 1.0));
+        assert_eq!(scale(DisplayMode::Native, &video), (0.9, 1.2));
+        assert_eq!(scale(DisplayMode::Aspect(16, 9), &video), (1.0, 1.0));
+    }
+
+    #[test]
+    fn scales_by_the_sample_aspect_ratio() {
+        // anamorphic 4:3 pixels that make up a 16:9 picture
+        let video = video(1440, 1080, 4.0 / 3.0, 0);
+        assert_eq!(scale(DisplayMode::Fit, &video), (1.0, 1.0));
+        assert_eq!(scale(DisplayMode::Native, &video), (1.2, 1.2));
+    }
+
+    #[test]
+    fn fits_a_sideways_video_by_its_turned_size() {
+        for rotation in [90, 270] {
+            let video = video(1920, 1080, 1.0, rotation);
+            assert_eq!(scale(DisplayMode::Fit, &video), (0.316, 1.0));
+            assert_eq!(scale(DisplayMode::Fill, &video), (1.0, 3.16));
+        }
+        assert_eq!(
+            scale(DisplayMode::Fit, &video(1920, 1080, 1.0, 180)),
+            (1.0, 1.0)
+        );
+    }
+
+    #[test]
+    fn turns_the_texture_coordinates_with_the_video() {
+        // the texture coordinates at the bottom left and bottom right corner of the window
+        let cases = [
+            (0, [0.0, 0.0], [1.0, 0.0]),
+            (90, [0.0, 1.0], [0.0, 0.0]),
+            (180, [1.0, 1.0], [0.0, 1.0]),
+            (270, [1.0, 0.0], [1.0, 1.0]),
+        ];
+        for (rotation, first, second) in cases {
+            let vertices = get_vertices(
+                DisplayMode::Fit,
+                &video(1920, 1080, 1.0, rotation),
+                1600.0,
+                900.0,
             );
-            ctx.device.cmd_draw_indexed(command_buffer, 6, 1, 0, 0, 1);
-            ctx.end_rendering(command_buffer);
-        });
-        log::debug!("Frame copied to gpu");
+            assert_eq!(vertices[0].uv, first, "{rotation} degrees");
+            assert_eq!(vertices[1].uv, second, "{rotation} degrees");
+        }
     }
 }
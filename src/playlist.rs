@@ -0,0 +1,391 @@
+use std::path::Path;
+
+/// What happens when an item ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RepeatMode {
+    /// Stop after the last item
+    #[default]
+    Off,
+    /// Play the current item again
+    One,
+    /// Start over at the first item after the last one
+    All,
+}
+
+/// Paths and URLs in the order they're played
+pub struct Playlist {
+    items: Vec<String>,
+    /// Indices into `items` in play order, shuffled or not
+    order: Vec<usize>,
+    /// Position of the current item in `order`
+    position: usize,
+    shuffle: bool,
+    repeat: RepeatMode,
+}
+
+impl Playlist {
+    /// M3U, M3U8 and PLS files are replaced by their entries, anything else is played as it is
+    pub fn new(paths: impl IntoIterator<Item = String>) -> Self {
+        let items: Vec<String> = paths
+            .into_iter()
+            .flat_map(|path| expand_playlist_file(&path))
+            .collect();
+
+        Self {
+            order: (0..items.len()).collect(),
+            items,
+            position: 0,
+            shuffle: false,
+            repeat: RepeatMode::Off,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// All items in the order they were added
+    pub fn items(&self) -> &[String] {
+        &self.items
+    }
+
+    pub fn shuffle(&self) -> bool {
+        self.shuffle
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat
+    }
+
+    /// Position of the current item in the play order
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn set_position(&mut self, position: usize) {
+        self.position = position.min(self.order.len().saturating_sub(1));
+    }
+
+    /// Item at `position` in the play order
+    pub fn get(&self, position: usize) -> Option<&str> {
+        self.order
+            .get(position)
+            .map(|index| self.items[*index].as_str())
+    }
+
+    pub fn current(&self) -> Option<&str> {
+        self.get(self.position)
+    }
+
+    /// Index of the current item in `items`
+    pub fn current_index(&self) -> Option<usize> {
+        self.order.get(self.position).copied()
+    }
+
+    /// Position that plays once the item at `position` ended, `None` at the end of the playlist
+    pub fn following(&self, position: usize) -> Option<usize> {
+        if self.is_empty() {
+            return None;
+        }
+
+        match self.repeat {
+            RepeatMode::Off => (position + 1 < self.len()).then_some(position + 1),
+            RepeatMode::One => Some(position),
+            RepeatMode::All => Some((position + 1) % self.len()),
+        }
+    }
+
+    /// Position the next button goes to, unlike `following` this never repeats the current item
+    pub fn next_position(&self) -> Option<usize> {
+        match self.repeat {
+            RepeatMode::Off => (self.position + 1 < self.len()).then_some(self.position + 1),
+            RepeatMode::One | RepeatMode::All => {
+                (!self.is_empty()).then(|| (self.position + 1) % self.len())
+            }
+        }
+    }
+
+    pub fn previous_position(&self) -> Option<usize> {
+        match (self.position, self.repeat) {
+            (0, RepeatMode::Off) => None,
+            (0, _) => self.len().checked_sub(1),
+            (position, _) => Some(position - 1),
+        }
+    }
+
+    /// Shuffling keeps the current item playing and puts the others in a random order after it
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        self.shuffle = shuffle;
+        let Some(current) = self.current_index() else {
+            return;
+        };
+
+        if shuffle {
+            let mut rest: Vec<usize> = (0..self.len()).filter(|index| *index != current).collect();
+            fastrand::shuffle(&mut rest);
+            self.order = std::iter::once(current).chain(rest).collect();
+            self.position = 0;
+        } else {
+            self.order = (0..self.len()).collect();
+            self.position = current;
+        }
+    }
+
+    pub fn set_repeat(&mut self, repeat: RepeatMode) {
+        self.repeat = repeat;
+    }
+
+    /// Adds items after the last one, playlist files are expanded like in `new`
+    pub fn append(&mut self, paths: impl IntoIterator<Item = String>) {
+        for path in paths
+            .into_iter()
+            .flat_map(|path| expand_playlist_file(&path))
+        {
+            self.order.push(self.items.len());
+            self.items.push(path);
+        }
+    }
+}
+
+/// Entries of a playlist file, or the path itself when it isn't one
+fn expand_playlist_file(path: &str) -> Vec<String> {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+    // remote playlists are left to ffmpeg, which plays HLS
+    if !matches!(extension.as_deref(), Some("m3u" | "m3u8" | "pls")) || is_url(path) {
+        return vec![path.to_string()];
+    }
+
+    let contents = match std::fs::read(path) {
+        Ok(bytes) => String::from_utf8_lossy(&bytes)
+            .trim_start_matches('\u{feff}')
+            .to_string(),
+        Err(err) => {
+            log::error!("Could not read playlist {}: {}", path, err);
+            return vec![];
+        }
+    };
+    // an HLS media playlist on disk is a single stream
+    if contents.contains("#EXT-X-") {
+        return vec![path.to_string()];
+    }
+
+    let entries = if extension.as_deref() == Some("pls") {
+        parse_pls(&contents)
+    } else {
+        parse_m3u(&contents)
+    };
+    log::info!("Loaded {} items from {}", entries.len(), path);
+
+    // entries are relative to the playlist file
+    let directory = Path::new(path).parent().unwrap_or(Path::new(""));
+    entries
+        .into_iter()
+        .map(|entry| {
+            if is_url(&entry) || Path::new(&entry).is_absolute() {
+                entry
+            } else {
+                directory.join(entry).to_string_lossy().into_owned()
+            }
+        })
+        .collect()
+}
+
+/// Every line that isn't empty or a `#` comment or directive is an entry
+fn parse_m3u(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Takes the `FileN=` lines in the order of their number
+fn parse_pls(contents: &str) -> Vec<String> {
+    let mut entries: Vec<(usize, String)> = contents
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.trim().split_once('=')?;
+            let number = key.trim().strip_prefix("File")?.parse::<usize>().ok()?;
+            Some((number, value.trim().to_string()))
+        })
+        .collect();
+    entries.sort_by_key(|(number, _)| *number);
+
+    entries.into_iter().map(|(_, entry)| entry).collect()
+}
+
+fn is_url(path: &str) -> bool {
+    path.contains("://")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::LoopMode;
+
+    fn playlist(items: &[&str]) -> Playlist {
+        Playlist::new(items.iter().map(|item| item.to_string()))
+    }
+
+    /// Writes `contents` to a playlist file of its own, so tests can run in parallel
+    fn write_playlist(name: &str, contents: &str) -> std::path::PathBuf {
+        let directory = std::env::temp_dir().join(format!("playlist-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn skips_m3u_comments() {
+        let entries = parse_m3u(
+            "#EXTM3U\n#EXTINF:123,Artist - Title\nfirst.mp3\n\n  # a comment\n\
+             http://example.com/stream\n",
+        );
+
+        assert_eq!(entries, vec!["first.mp3", "http://example.com/stream"]);
+    }
+
+    #[test]
+    fn orders_pls_entries_by_number() {
+        let entries = parse_pls(
+            "[playlist]\nFile2=second.mp3\nTitle2=Second\nFile1=first.mp3\n\
+             NumberOfEntries=3\nFile10=tenth.mp3\nVersion=2\n",
+        );
+
+        assert_eq!(entries, vec!["first.mp3", "second.mp3", "tenth.mp3"]);
+    }
+
+    #[test]
+    fn resolves_entries_relative_to_the_playlist() {
+        let absolute = if cfg!(windows) {
+            "C:\\music\\absolute.mp3"
+        } else {
+            "/music/absolute.mp3"
+        };
+        let path = write_playlist(
+            "relative.m3u",
+            &format!("#EXTM3U\nrelative.mp3\n{absolute}\nhttp://example.com/stream\n"),
+        );
+        let directory = path.parent().unwrap();
+
+        let entries = expand_playlist_file(path.to_str().unwrap());
+
+        assert_eq!(
+            entries,
+            vec![
+                directory
+                    .join("relative.mp3")
+                    .to_string_lossy()
+                    .into_owned(),
+                absolute.to_string(),
+                "http://example.com/stream".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn plays_hls_playlists_as_one_stream() {
+        let path = write_playlist(
+            "stream.m3u8",
+            "#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXTINF:10,\nsegment0.ts\n",
+        );
+        let path = path.to_str().unwrap();
+
+        assert_eq!(expand_playlist_file(path), vec![path.to_string()]);
+    }
+
+    #[test]
+    fn stops_at_the_ends_without_repeat() {
+        let playlist = playlist(&["a", "b", "c"]);
+
+        assert_eq!(playlist.following(2), None);
+        assert_eq!(playlist.previous_position(), None);
+    }
+
+    #[test]
+    fn repeats_one_item() {
+        let mut playlist = playlist(&["a", "b", "c"]);
+        playlist.set_repeat(RepeatMode::One);
+        playlist.set_position(2);
+
+        assert_eq!(playlist.following(2), Some(2));
+        // the buttons still move on
+        assert_eq!(playlist.next_position(), Some(0));
+        assert_eq!(playlist.previous_position(), Some(1));
+    }
+
+    #[test]
+    fn wraps_around_when_repeating_all() {
+        let mut playlist = playlist(&["a", "b", "c"]);
+        playlist.set_repeat(RepeatMode::All);
+
+        assert_eq!(playlist.following(2), Some(0));
+        assert_eq!(playlist.previous_position(), Some(2));
+        playlist.set_position(2);
+        assert_eq!(playlist.next_position(), Some(0));
+    }
+
+    #[test]
+    fn shuffle_plays_every_item_once() {
+        let items: Vec<String> = (0..20).map(|index| index.to_string()).collect();
+        let mut playlist = Playlist::new(items.clone());
+        playlist.set_position(5);
+        playlist.set_shuffle(true);
+
+        // the current item keeps playing
+        assert_eq!(playlist.current(), Some("5"));
+        let mut played: Vec<String> = (0..playlist.len())
+            .map(|position| playlist.get(position).unwrap().to_string())
+            .collect();
+        played.sort();
+        let mut expected = items;
+        expected.sort();
+        assert_eq!(played, expected);
+
+        playlist.set_shuffle(false);
+        assert_eq!(playlist.current(), Some("5"));
+        assert_eq!(playlist.position(), 5);
+    }
+
+    #[test]
+    fn loops_the_whole_item_or_nothing_at_any_length() {
+        for mode in [LoopMode::Off, LoopMode::File] {
+            assert!(mode.is_valid(Some(0)));
+            assert!(mode.is_valid(None));
+        }
+    }
+
+    #[test]
+    fn loops_a_range_that_ends_after_it_starts() {
+        let duration = Some(10_000_000_000);
+        let range = |start, end| LoopMode::Range { start, end };
+
+        assert!(range(1_000_000_000, 2_000_000_000).is_valid(duration));
+        assert!(!range(2_000_000_000, 2_000_000_000).is_valid(duration));
+        assert!(!range(2_000_000_000, 1_000_000_000).is_valid(duration));
+        assert!(!range(-1_000_000_000, 1_000_000_000).is_valid(duration));
+    }
+
+    #[test]
+    fn loops_a_range_only_within_the_item() {
+        let duration = Some(10_000_000_000);
+        let range = |end| LoopMode::Range {
+            start: 1_000_000_000,
+            end,
+        };
+
+        assert!(range(10_000_000_000).is_valid(duration));
+        assert!(!range(10_000_000_001).is_valid(duration));
+        // streams without a known length take any end
+        assert!(range(60_000_000_000).is_valid(None));
+    }
+}
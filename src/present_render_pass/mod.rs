@@ -134,6 +134,7 @@ impl PresentRenderPass {
         ctx: &RenderContext,
         ui_attachment: &ResourceHandle<Texture>,
         media_attachment: &ResourceHandle<Texture>,
+        subtitle_attachment: &ResourceHandle<Texture>,
         present_index: u32,
     ) {
         let pipeline = ctx.graphics_pipelines.get(&self.pipeline_handle).unwrap();
@@ -176,6 +177,24 @@ impl PresentRenderPass {
                                         .unwrap(),
                                 ),
                         )),
+                    vk::WriteDescriptorSet::default()
+                        .dst_set(pipeline.descriptor_sets[0])
+                        .dst_binding(2)
+                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                        .image_info(std::slice::from_ref(
+                            &vk::DescriptorImageInfo::default()
+                                .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                                .image_view(*ctx.get_texture_view(subtitle_attachment).unwrap())
+                                .sampler(
+                                    *ctx.immutable_samplers
+                                        .get(&SamplerDesc {
+                                            address_modes: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+                                            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+                                            texel_filter: vk::Filter::LINEAR,
+                                        })
+                                        .unwrap(),
+                                ),
+                        )),
                 ],
                 &[],
             );
@@ -4,6 +4,7 @@
 
 layout (set = 0, binding = 0) uniform sampler2D ui_texture;
 layout (set = 0, binding = 1) uniform sampler2D media_texture;
+layout (set = 0, binding = 2) uniform sampler2D subtitle_texture;
 
 layout (location = 0) in vec2 o_uv;
 layout (location = 0) out vec4 a_frag_color;
@@ -11,6 +12,8 @@ layout (location = 0) out vec4 a_frag_color;
 void main() {
     vec4 ui_data = texture(ui_texture, o_uv);
     vec4 media_data = texture(media_texture, o_uv);
-    
-    a_frag_color = mix(media_data, ui_data, ui_data.a);
+    vec4 subtitle_data = texture(subtitle_texture, o_uv);
+
+    vec4 video_data = mix(media_data, subtitle_data, subtitle_data.a);
+    a_frag_color = mix(video_data, ui_data, ui_data.a);
 }
\ No newline at end of file
@@ -0,0 +1,298 @@
+use std::path::{Path, PathBuf};
+
+use crate::decoder::Subtitle;
+
+/// Sidecar formats we can parse, in the order they're looked for
+const SUBTITLE_EXTENSIONS: [&str; 4] = ["srt", "ass", "ssa", "vtt"];
+
+/// Finds a subtitle file with the same name as the media, `movie.srt` for `movie.mkv`
+pub fn find_sidecar_file(media_path: &Path) -> Option<PathBuf> {
+    SUBTITLE_EXTENSIONS
+        .iter()
+        .map(|extension| media_path.with_extension(extension))
+        .find(|path| path.is_file())
+}
+
+/// Whether the file is one we can parse, going by its extension
+pub fn is_subtitle_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase())
+        .is_some_and(|extension| SUBTITLE_EXTENSIONS.contains(&extension.as_str()))
+}
+
+/// Reads an SRT, ASS or WebVTT file into cues sorted by start time
+pub fn parse_subtitle_file(path: &Path) -> Result<Vec<Subtitle>, String> {
+    let bytes = std::fs::read(path).map_err(|err| err.to_string())?;
+    let contents = String::from_utf8_lossy(&bytes)
+        .trim_start_matches('\u{feff}')
+        .replace("\r\n", "\n");
+
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+    let mut cues = match extension.as_deref() {
+        Some("srt") => parse_srt(&contents),
+        Some("vtt") => parse_vtt(&contents),
+        Some("ass") | Some("ssa") => parse_ass(&contents),
+        _ => return Err(format!("unsupported subtitle file {}", path.display())),
+    };
+    cues.sort_by_key(|cue| cue.start);
+
+    Ok(cues)
+}
+
+fn parse_srt(contents: &str) -> Vec<Subtitle> {
+    contents
+        .split("\n\n")
+        .filter_map(|block| {
+            let mut lines = block.lines().skip_while(|line| line.trim().is_empty());
+            let mut timing = lines.next()?;
+            // the cue number is optional in practice
+            if !timing.contains("-->") {
+                timing = lines.next()?;
+            }
+
+            let (start, end) = parse_timing(timing)?;
+            Some(create_cue(start, end, lines.map(strip_html_tags)))
+        })
+        .collect()
+}
+
+fn parse_vtt(contents: &str) -> Vec<Subtitle> {
+    contents
+        .split("\n\n")
+        .filter_map(|block| {
+            let mut lines = block.lines().skip_while(|line| line.trim().is_empty());
+            // the header, NOTE, STYLE and REGION blocks have no timing line
+            let mut timing = lines.next()?;
+            if !timing.contains("-->") {
+                timing = lines.next().filter(|line| line.contains("-->"))?;
+            }
+
+            let (start, end) = parse_timing(timing)?;
+            Some(create_cue(start, end, lines.map(strip_html_tags)))
+        })
+        .collect()
+}
+
+fn parse_ass(contents: &str) -> Vec<Subtitle> {
+    let mut in_events = false;
+    // the default field order of the [Events] section
+    let mut fields = vec![
+        "layer", "start", "end", "style", "name", "marginl", "marginr", "marginv", "effect", "text",
+    ];
+    let mut cues = vec![];
+
+    for line in contents.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_events = line.eq_ignore_ascii_case("[events]");
+            continue;
+        }
+        if !in_events {
+            continue;
+        }
+
+        if let Some(format) = line.strip_prefix("Format:") {
+            fields = format.split(',').map(|field| field.trim()).collect();
+            continue;
+        }
+        let Some(dialogue) = line.strip_prefix("Dialogue:") else {
+            continue;
+        };
+
+        // the text is always the last field and may contain commas itself
+        let values: Vec<&str> = dialogue.splitn(fields.len(), ',').collect();
+        let get_value = |name: &str| {
+            fields
+                .iter()
+                .position(|field| field.eq_ignore_ascii_case(name))
+                .and_then(|index| values.get(index))
+                .map(|value| value.trim())
+        };
+
+        let (Some(start), Some(end), Some(text)) = (
+            get_value("start").and_then(parse_timestamp),
+            get_value("end").and_then(parse_timestamp),
+            get_value("text"),
+        ) else {
+            continue;
+        };
+        cues.push(create_cue(
+            start,
+            end,
+            strip_ass_tags(text).lines().map(str::to_string),
+        ));
+    }
+
+    cues
+}
+
+fn create_cue(start: i64, end: i64, lines: impl Iterator<Item = String>) -> Subtitle {
+    Subtitle {
+        start,
+        end: Some(end),
+        text: lines.filter(|line| !line.trim().is_empty()).collect(),
+        bitmaps: vec![],
+        canvas: (0, 0),
+    }
+}
+
+/// Parses `00:00:01,000 --> 00:00:04,000`, anything after the end time are cue settings
+fn parse_timing(line: &str) -> Option<(i64, i64)> {
+    let (start, end) = line.split_once("-->")?;
+    let end = end.split_whitespace().next()?;
+
+    Some((parse_timestamp(start.trim())?, parse_timestamp(end)?))
+}
+
+/// Parses `hh:mm:ss,mmm`, `mm:ss.mmm` and the `h:mm:ss.cc` of ASS into nanoseconds
+fn parse_timestamp(timestamp: &str) -> Option<i64> {
+    let (clock, fraction) = timestamp.split_once([',', '.']).unwrap_or((timestamp, ""));
+
+    let mut seconds = 0;
+    for part in clock.split(':') {
+        seconds = seconds * 60 + part.trim().parse::<i64>().ok()?;
+    }
+
+    // the fraction can be in centiseconds or milliseconds
+    let fraction = fraction.trim();
+    if !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let nanoseconds = if fraction.is_empty() {
+        0
+    } else {
+        let digits = &fraction[..fraction.len().min(9)];
+        digits.parse::<i64>().ok()? * 10i64.pow(9 - digits.len() as u32)
+    };
+
+    Some(seconds * 1_000_000_000 + nanoseconds)
+}
+
+/// Drops the `<i>`, `<font>` and WebVTT `<c>` or `<v>` tags
+fn strip_html_tags(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut in_tag = false;
+    for c in line.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => stripped.push(c),
+            _ => (),
+        }
+    }
+
+    stripped
+        .replace("&amp;", "&")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+}
+
+/// Drops the override tags like `{\i1}` from ASS text and turns its line breaks into newlines
+pub fn strip_ass_tags(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '{' => in_tag = true,
+            '}' => in_tag = false,
+            c if !in_tag => stripped.push(c),
+            _ => (),
+        }
+    }
+
+    stripped
+        .replace("\\N", "\n")
+        .replace("\\n", "\n")
+        .replace("\\h", " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("00:00:01,000"), Some(1_000_000_000));
+        assert_eq!(parse_timestamp("01:02:03.450"), Some(3_723_450_000_000));
+        assert_eq!(parse_timestamp("02:03.5"), Some(123_500_000_000));
+        // ASS uses centiseconds and a single digit for the hours
+        assert_eq!(parse_timestamp("0:00:01.25"), Some(1_250_000_000));
+        assert_eq!(parse_timestamp("0:00:01"), Some(1_000_000_000));
+    }
+
+    #[test]
+    fn rejects_broken_timestamps() {
+        assert_eq!(parse_timestamp("00:00:xx,000"), None);
+        assert_eq!(parse_timestamp("00:00:01,5é0"), None);
+        assert_eq!(parse_timestamp("00:00:01,-50"), None);
+        assert_eq!(parse_timestamp("00:00:01,+50"), None);
+        assert_eq!(parse_timing("00:00:01,000 -> 00:00:02,000"), None);
+    }
+
+    #[test]
+    fn parses_cue_settings() {
+        assert_eq!(
+            parse_timing("00:00:01.000 --> 00:00:02.500 align:start line:0%"),
+            Some((1_000_000_000, 2_500_000_000))
+        );
+    }
+
+    #[test]
+    fn strips_html_tags() {
+        assert_eq!(
+            strip_html_tags("<i>Hello</i> <font color=\"red\">world</font>"),
+            "Hello world"
+        );
+        assert_eq!(strip_html_tags("<v Roger>Tom &amp; Jerry"), "Tom & Jerry");
+        assert_eq!(strip_html_tags("1 &lt; 2"), "1 < 2");
+    }
+
+    #[test]
+    fn strips_ass_tags() {
+        assert_eq!(strip_ass_tags("{\\i1}Hello{\\i0}\\Nworld"), "Hello\nworld");
+        assert_eq!(strip_ass_tags("non\\hbreaking"), "non breaking");
+    }
+
+    #[test]
+    fn parses_multi_line_srt_cues() {
+        let cues = parse_srt(
+            "1\n00:00:01,000 --> 00:00:02,000\nFirst line\n<b>Second line</b>\n\n\
+             2\n00:00:03,000 --> 00:00:04,000\nNext\n",
+        );
+
+        assert_eq!(cues.len(), 2);
+        assert_eq!(cues[0].text, vec!["First line", "Second line"]);
+        assert_eq!(cues[0].start, 1_000_000_000);
+        assert_eq!(cues[0].end, Some(2_000_000_000));
+        assert_eq!(cues[1].text, vec!["Next"]);
+    }
+
+    #[test]
+    fn parses_vtt_skipping_header_and_notes() {
+        let cues = parse_vtt("WEBVTT\n\nNOTE a comment\n\nintro\n00:01.000 --> 00:02.000\nHi\n");
+
+        assert_eq!(cues.len(), 1);
+        assert_eq!(cues[0].start, 1_000_000_000);
+        assert_eq!(cues[0].text, vec!["Hi"]);
+    }
+
+    #[test]
+    fn keeps_overlapping_ass_cues() {
+        let cues = parse_ass(
+            "[Script Info]\nTitle: test\n\n[Events]\n\
+             Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n\
+             Dialogue: 0,0:00:01.00,0:00:05.00,Default,,0,0,0,,Top, with a comma\n\
+             Dialogue: 0,0:00:02.00,0:00:03.00,Default,,0,0,0,,{\\an8}Bottom\n",
+        );
+
+        assert_eq!(cues.len(), 2);
+        assert_eq!(cues[0].text, vec!["Top, with a comma"]);
+        assert_eq!(cues[1].start, 2_000_000_000);
+        assert_eq!(cues[1].end, Some(3_000_000_000));
+        assert_eq!(cues[1].text, vec!["Bottom"]);
+    }
+}
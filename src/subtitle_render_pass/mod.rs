@@ -0,0 +1,295 @@
+use beuk::ash::vk::{self, BufferUsageFlags, DeviceSize, ImageCreateInfo};
+use beuk::buffer::{Buffer, BufferDescriptor, MemoryLocation};
+use beuk::ctx::RenderContext;
+use beuk::memory::ResourceHandle;
+use beuk::texture::{Texture, TransitionDesc};
+use epaint::text::{FontDefinitions, Fonts};
+use epaint::{Color32, FontId};
+
+use crate::decoder::{Subtitle, SubtitleBitmap};
+use crate::CurrentVideo;
+
+/// Subtitle text height relative to the video height
+const FONT_SCALE: f32 = 0.05;
+/// Space between the text and the bottom of the video, relative to the video height
+const BOTTOM_MARGIN: f32 = 0.06;
+const OUTLINE_WIDTH: f32 = 2.0;
+
+/// Subtitles are rasterized on the cpu into their own layer, which is composited between the
+/// video and the UI
+pub struct SubtitleRenderPass {
+    pub attachment: ResourceHandle<Texture>,
+    staging_buffer: ResourceHandle<Buffer>,
+    fonts: Fonts,
+    width: u32,
+    height: u32,
+}
+
+impl SubtitleRenderPass {
+    pub fn new(ctx: &RenderContext) -> Self {
+        let swapchain = ctx.get_swapchain();
+        let (width, height) = (
+            swapchain.surface_resolution.width,
+            swapchain.surface_resolution.height,
+        );
+
+        let attachment = ctx.create_texture(
+            "subtitles",
+            &ImageCreateInfo {
+                image_type: vk::ImageType::TYPE_2D,
+                format: vk::Format::R8G8B8A8_UNORM,
+                extent: vk::Extent3D {
+                    width,
+                    height,
+                    depth: 1,
+                },
+                usage: vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
+                samples: vk::SampleCountFlags::TYPE_1,
+                mip_levels: 1,
+                array_layers: 1,
+                sharing_mode: vk::SharingMode::EXCLUSIVE,
+                tiling: vk::ImageTiling::OPTIMAL,
+                ..Default::default()
+            },
+            true,
+        );
+
+        let staging_buffer = ctx.create_buffer(&BufferDescriptor {
+            debug_name: "subtitles",
+            size: (width * height * 4) as DeviceSize,
+            location: MemoryLocation::CpuToGpu,
+            usage: BufferUsageFlags::TRANSFER_SRC | BufferUsageFlags::TRANSFER_DST,
+        });
+
+        let pass = Self {
+            attachment,
+            staging_buffer,
+            fonts: Fonts::new(1.0, 2 * 1024, FontDefinitions::default()),
+            width,
+            height,
+        };
+        // start out fully transparent
+        pass.upload(ctx, &vec![0; (width * height * 4) as usize]);
+
+        pass
+    }
+
+    /// Redraws the layer with the given subtitles, positioned over the letterboxed video
+    pub fn draw(
+        &mut self,
+        ctx: &RenderContext,
+        current_video: Option<&CurrentVideo>,
+        subtitles: &[Subtitle],
+    ) {
+        let mut canvas = vec![0u8; (self.width * self.height * 4) as usize];
+        let video_rect = self.get_video_rect(current_video);
+
+        for subtitle in subtitles {
+            for bitmap in subtitle.bitmaps.iter() {
+                self.draw_bitmap(&mut canvas, video_rect, subtitle.canvas, bitmap);
+            }
+        }
+
+        let lines: Vec<&str> = subtitles
+            .iter()
+            .flat_map(|subtitle| subtitle.text.iter())
+            .map(|line| line.as_str())
+            .collect();
+        if !lines.is_empty() {
+            self.draw_text(&mut canvas, video_rect, &lines);
+        }
+
+        self.upload(ctx, &canvas);
+    }
+
+    /// Area the video covers in the layer as `(x, y, width, height)`, the same fit as the media pass
+    fn get_video_rect(&self, current_video: Option<&CurrentVideo>) -> (f32, f32, f32, f32) {
+        let (width, height) = (self.width as f32, self.height as f32);
+        let Some(video) = current_video else {
+            return (0.0, 0.0, width, height);
+        };
+
+        let screen_aspect = width / height;
+        let video_aspect = video.width as f32 / video.height as f32;
+        if screen_aspect > video_aspect {
+            // Pillarbox
+            let video_width = height * video_aspect;
+            ((width - video_width) / 2.0, 0.0, video_width, height)
+        } else {
+            // Letterbox
+            let video_height = width / video_aspect;
+            (0.0, (height - video_height) / 2.0, width, video_height)
+        }
+    }
+
+    fn draw_bitmap(
+        &self,
+        canvas: &mut [u8],
+        video_rect: (f32, f32, f32, f32),
+        subtitle_canvas: (u32, u32),
+        bitmap: &SubtitleBitmap,
+    ) {
+        let (video_x, video_y, video_width, video_height) = video_rect;
+        if subtitle_canvas.0 == 0 || subtitle_canvas.1 == 0 {
+            return;
+        }
+        let scale_x = video_width / subtitle_canvas.0 as f32;
+        let scale_y = video_height / subtitle_canvas.1 as f32;
+
+        let left = (video_x + bitmap.x as f32 * scale_x) as i32;
+        let top = (video_y + bitmap.y as f32 * scale_y) as i32;
+        let width = (bitmap.width as f32 * scale_x) as i32;
+        let height = (bitmap.height as f32 * scale_y) as i32;
+
+        // nearest neighbour is good enough for the flat colors of bitmap subtitles
+        for y in 0..height {
+            let source_y = ((y as f32 / scale_y) as u32).min(bitmap.height - 1);
+            for x in 0..width {
+                let source_x = ((x as f32 / scale_x) as u32).min(bitmap.width - 1);
+                let offset = ((source_y * bitmap.width + source_x) * 4) as usize;
+                let color = &bitmap.data[offset..offset + 4];
+                self.blend_pixel(
+                    canvas,
+                    left + x,
+                    top + y,
+                    [color[0], color[1], color[2]],
+                    color[3] as f32 / 255.0,
+                );
+            }
+        }
+    }
+
+    /// Centered white lines with a black outline at the bottom of the video
+    fn draw_text(&mut self, canvas: &mut [u8], video_rect: (f32, f32, f32, f32), lines: &[&str]) {
+        let (video_x, video_y, video_width, video_height) = video_rect;
+        let font_id = FontId::proportional((video_height * FONT_SCALE).max(12.0));
+
+        // the font atlas only grows, but the layout cache is cleared every frame
+        self.fonts.begin_frame(1.0, 2 * 1024);
+        let galleys: Vec<_> = lines
+            .iter()
+            .map(|line| {
+                self.fonts.layout(
+                    line.to_string(),
+                    font_id.clone(),
+                    Color32::WHITE,
+                    video_width * 0.9,
+                )
+            })
+            .collect();
+        let atlas = self.fonts.image();
+
+        let mut top = video_y + video_height * (1.0 - BOTTOM_MARGIN)
+            - galleys.iter().map(|galley| galley.size().y).sum::<f32>();
+        for galley in galleys {
+            let left = video_x + (video_width - galley.size().x) / 2.0;
+
+            let outline = [
+                (-OUTLINE_WIDTH, -OUTLINE_WIDTH),
+                (0.0, -OUTLINE_WIDTH),
+                (OUTLINE_WIDTH, -OUTLINE_WIDTH),
+                (-OUTLINE_WIDTH, 0.0),
+                (OUTLINE_WIDTH, 0.0),
+                (-OUTLINE_WIDTH, OUTLINE_WIDTH),
+                (0.0, OUTLINE_WIDTH),
+                (OUTLINE_WIDTH, OUTLINE_WIDTH),
+            ];
+            let passes = outline
+                .iter()
+                .map(|(x, y)| (*x, *y, [0, 0, 0]))
+                .chain(std::iter::once((0.0, 0.0, [255, 255, 255])));
+
+            for (offset_x, offset_y, color) in passes {
+                for glyph in galley.rows.iter().flat_map(|row| row.glyphs.iter()) {
+                    let uv_rect = glyph.uv_rect;
+                    if uv_rect.is_nothing() {
+                        continue;
+                    }
+
+                    let glyph_left = (left + offset_x + glyph.pos.x + uv_rect.offset.x) as i32;
+                    let glyph_top = (top + offset_y + glyph.pos.y + uv_rect.offset.y) as i32;
+                    for texel_y in uv_rect.min[1]..uv_rect.max[1] {
+                        for texel_x in uv_rect.min[0]..uv_rect.max[0] {
+                            let coverage =
+                                atlas.pixels[texel_y as usize * atlas.size[0] + texel_x as usize];
+                            self.blend_pixel(
+                                canvas,
+                                glyph_left + (texel_x - uv_rect.min[0]) as i32,
+                                glyph_top + (texel_y - uv_rect.min[1]) as i32,
+                                color,
+                                coverage,
+                            );
+                        }
+                    }
+                }
+            }
+
+            top += galley.size().y;
+        }
+    }
+
+    /// Draws `color` over the pixel, the layer is not premultiplied
+    fn blend_pixel(&self, canvas: &mut [u8], x: i32, y: i32, color: [u8; 3], alpha: f32) {
+        if alpha <= 0.0 || x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            return;
+        }
+
+        let offset = ((y as u32 * self.width + x as u32) * 4) as usize;
+        let pixel = &mut canvas[offset..offset + 4];
+        let dst_alpha = pixel[3] as f32 / 255.0;
+        let out_alpha = alpha + dst_alpha * (1.0 - alpha);
+        for channel in 0..3 {
+            let value = (color[channel] as f32 * alpha
+                + pixel[channel] as f32 * dst_alpha * (1.0 - alpha))
+                / out_alpha;
+            pixel[channel] = value as u8;
+        }
+        pixel[3] = (out_alpha * 255.0) as u8;
+    }
+
+    fn upload(&self, ctx: &RenderContext, canvas: &[u8]) {
+        ctx.buffer_manager
+            .get_mut(&self.staging_buffer)
+            .unwrap()
+            .copy_from_slice(canvas, 0);
+
+        ctx.record_submit(|ctx, command_buffer| unsafe {
+            let texture = ctx.texture_manager.get_mut(&self.attachment).unwrap();
+            let staging_buffer = ctx.buffer_manager.get(&self.staging_buffer).unwrap();
+
+            texture.transition(
+                &ctx.device,
+                command_buffer,
+                &TransitionDesc {
+                    new_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    new_access_mask: vk::AccessFlags::TRANSFER_WRITE,
+                    new_stage_mask: vk::PipelineStageFlags::TRANSFER,
+                },
+            );
+
+            ctx.device.cmd_copy_buffer_to_image(
+                command_buffer,
+                staging_buffer.buffer,
+                texture.image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[vk::BufferImageCopy::default()
+                    .image_subresource(vk::ImageSubresourceLayers {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        layer_count: 1,
+                        ..Default::default()
+                    })
+                    .image_extent(texture.extent)],
+            );
+
+            texture.transition(
+                &ctx.device,
+                command_buffer,
+                &TransitionDesc {
+                    new_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    new_access_mask: vk::AccessFlags::SHADER_READ,
+                    new_stage_mask: vk::PipelineStageFlags::FRAGMENT_SHADER,
+                },
+            );
+        });
+    }
+}
@@ -1,27 +1,150 @@
 use dioxus::prelude::*;
-use dioxus_beuk::hooks::{animation::Animation, use_animation};
 
-use crate::{decoder::MediaCommands, AppContextRef};
+use stainless_ffmpeg::prelude::AVMediaType;
 
-pub fn app(cx: Scope) -> Element {
-    let ctx = use_context::<AppContextRef>(cx).unwrap();
-    let animation = use_animation(cx, 0.0);
-    let progress = animation.value();
+use crate::{
+    audio_sink::list_output_devices,
+    decoder::{LoopMode, MediaCommands, SeekMode, StreamInfo},
+    media_render_pass::{DisplayMode, ToneMapping},
+    playlist::RepeatMode,
+    AppContextRef, MediaRenderCommand,
+};
 
-    use_effect(cx, (&progress,), move |(val,)| {
-        if val == 100.0 {
-            animation.start(Animation::new_linear(100.0..=0.0, 1000));
-        }
+mod use_player_state;
+use use_player_state::{format_time, use_player_state};
+
+/// Speeds the rate button cycles through
+const RATES: [f32; 9] = [0.25, 0.5, 0.75, 1.0, 1.25, 1.5, 2.0, 3.0, 4.0];
+
+fn track_label(stream: &StreamInfo) -> String {
+    match (&stream.title, &stream.language) {
+        (Some(title), _) => title.clone(),
+        (None, Some(language)) => format!("{} ({})", language, stream.codec),
+        (None, None) => format!("#{} ({})", stream.index, stream.codec),
+    }
+}
+
+/// The decoder is gone while nothing is open and after it stopped on an error
+fn send_command(ctx: &AppContextRef, command: MediaCommands) {
+    let Some(command_sender) = ctx.read().unwrap().command_sender.clone() else {
+        log::warn!("Nothing is playing, ignoring {:?}", command);
+        return;
+    };
+    if let Err(err) = command_sender.send(command) {
+        log::warn!("The decoder stopped, ignoring {:?}", err.into_inner());
+    }
+}
 
-        if val == 0.0 {
-            animation.start(Animation::new_linear(0.0..=100.0, 1000));
+pub fn app(cx: Scope) -> Element {
+    let ctx = use_context::<AppContextRef>(cx).unwrap();
+    let player = use_player_state(cx);
+    // highlights the window while files are dragged over it, main.rs opens them once dropped
+    let drop_target = use_state(cx, || false);
+    let drop_target_class = if *drop_target.get() {
+        "border-4 border-sky-500"
+    } else {
+        ""
+    };
+    let progress = player.progress();
+    let position = format_time(player.position);
+    let duration = player.duration.map(format_time).unwrap_or_default();
+    let volume = player.volume;
+    let muted = player.muted;
+    let mute_label = if muted {
+        "Unmute".to_string()
+    } else {
+        format!("Mute ({}%)", (volume * 100.0).round())
+    };
+    let audio_tracks = player.tracks(AVMediaType::AVMEDIA_TYPE_AUDIO);
+    let video_tracks = player.tracks(AVMediaType::AVMEDIA_TYPE_VIDEO);
+    let subtitle_tracks = player.tracks(AVMediaType::AVMEDIA_TYPE_SUBTITLE);
+    let subtitle_file = player
+        .subtitle_file
+        .as_ref()
+        .and_then(|path| path.file_name())
+        .map(|name| name.to_string_lossy().into_owned());
+    let subtitles_off_class = if player.subtitle_track.is_none() && subtitle_file.is_none() {
+        "bg-white/70"
+    } else {
+        "bg-white/40"
+    };
+    let rate = player.rate;
+    let dropped_frames = player.sync.dropped;
+    let next_rate = RATES
+        .iter()
+        .copied()
+        .find(|r| *r > rate)
+        .unwrap_or(RATES[0]);
+    let loaded = !player.playlist.is_empty();
+    let playlist_len = player.playlist.len();
+    let playlist_number = player.playlist_index + 1;
+    let playlist_item = player
+        .playlist
+        .get(player.playlist_index)
+        .map(|item| {
+            std::path::Path::new(item)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| item.clone())
+        })
+        .unwrap_or_default();
+    let shuffle = player.shuffle;
+    let shuffle_label = if shuffle { "On" } else { "Off" };
+    let (repeat_label, next_repeat) = match player.repeat {
+        RepeatMode::Off => ("Off", RepeatMode::All),
+        RepeatMode::All => ("All", RepeatMode::One),
+        RepeatMode::One => ("One", RepeatMode::Off),
+    };
+    // only HDR video is tone mapped, the setting stays for the next file
+    let hdr = player
+        .streams
+        .iter()
+        .any(|stream| Some(stream.index) == player.video_track && stream.hdr);
+    let tone_mapping = use_state(cx, || ctx.read().unwrap().tone_mapping);
+    let tone_mapping_label = tone_mapping.get().name();
+    let next_tone_mapping = ToneMapping::ALL
+        .iter()
+        .copied()
+        .cycle()
+        .skip_while(|value| value != tone_mapping.get())
+        .nth(1)
+        .unwrap();
+    let has_video = player.video_track.is_some();
+    let has_audio = player.audio_track.is_some();
+    // A is kept here until B is set, the decoder only hears about whole loops
+    let loop_start = use_state(cx, || None::<i64>);
+    let current_position = player.position;
+    let loop_mode = player.loop_mode;
+    let (file_loop_label, next_file_loop) = if loop_mode == LoopMode::File {
+        ("On", LoopMode::Off)
+    } else {
+        ("Off", LoopMode::File)
+    };
+    let range_loop_label = match (loop_mode, *loop_start.get()) {
+        (LoopMode::Range { start, end }, _) => {
+            format!("A-B {} - {}", format_time(start), format_time(end))
         }
-        async move {}
-    });
+        (_, Some(start)) => format!("Set B (A {})", format_time(start)),
+        _ => "Set A".to_string(),
+    };
+    let audio_device = player.audio_device.clone();
+    let audio_device_label = audio_device.as_deref().unwrap_or("Default").to_string();
+    let display_mode = use_state(cx, || ctx.read().unwrap().display_mode);
+    let display_mode_label = display_mode.get().name();
+    let next_display_mode = DisplayMode::ALL
+        .iter()
+        .copied()
+        .cycle()
+        .skip_while(|value| value != display_mode.get())
+        .nth(1)
+        .unwrap();
 
     cx.render(rsx! {
       div {
-        class: "w-full h-full bg-transparent flex flex-col justify-end",
+        class: "w-full h-full bg-transparent flex flex-col justify-end {drop_target_class}",
+        ondragenter: move |_| drop_target.set(true),
+        ondragleave: move |_| drop_target.set(false),
+        ondrop: move |_| drop_target.set(false),
 
           // div {
           //   onclick: move |_| count.modify(|v| {v.add(1)}),
@@ -45,13 +168,41 @@ pub fn app(cx: Scope) -> Element {
               }
             }
 
+            div {
+              class: "justify-between px-10 pt-5 text-sky-900",
+
+              span {
+                "{position} / {duration}"
+              }
+
+              if let Some(frame_number) = player.frame_number {
+                rsx! { span { "Frame {frame_number}" } }
+              }
+
+              if player.buffering {
+                rsx! { span { "Buffering..." } }
+              }
+
+              if dropped_frames > 0 {
+                rsx! { span { "{dropped_frames} frames dropped" } }
+              }
+
+              if player.ended {
+                rsx! { span { "Ended" } }
+              }
+
+              if let Some(error) = &player.error {
+                rsx! { span { class: "text-red-700", "{error}" } }
+              }
+            }
+
             div {
               class: "justify-center pt-10 gap-x-10",
 
               div {
                 class: "bg-white/50 h-64 px-20 flex items-center justify-center text-sky-900 rounded-5 hover:bg-white/60",
                 onclick: move |_| {
-                  ctx.read().unwrap().command_sender.as_ref().unwrap().send(MediaCommands::Play).unwrap();
+                  send_command(ctx, MediaCommands::Play);
                 },
 
                 span {
@@ -62,7 +213,7 @@ pub fn app(cx: Scope) -> Element {
               div {
                 class: "bg-white/50 h-64 px-20 flex items-center justify-center text-sky-900 rounded-5 hover:bg-white/60",
                 onclick: move |_| {
-                  ctx.read().unwrap().command_sender.as_ref().unwrap().send(MediaCommands::Pause).unwrap();
+                  send_command(ctx, MediaCommands::Pause);
                 },
 
                 span {
@@ -76,7 +227,7 @@ pub fn app(cx: Scope) -> Element {
               div {
                 class: "bg-white/50 h-64 px-20 flex items-center justify-center text-sky-900 rounded-5 hover:bg-white/60",
                 onclick: move |_| {
-                  ctx.read().unwrap().command_sender.as_ref().unwrap().send(MediaCommands::Pause).unwrap();
+                  send_command(ctx, MediaCommands::SeekRelative(10 * 1_000_000_000, SeekMode::Keyframe));
                 },
 
                 span {
@@ -86,8 +237,318 @@ pub fn app(cx: Scope) -> Element {
                 //   class: "h-6 w-6 bg-red-300",
                 // }
               }
+
+              div {
+                class: "bg-white/50 h-64 px-20 flex items-center justify-center text-sky-900 rounded-5 hover:bg-white/60",
+                onclick: move |_| {
+                  send_command(ctx, MediaCommands::StepBackward);
+                },
+
+                span {
+                  "Frame -"
+                }
+              }
+
+              div {
+                class: "bg-white/50 h-64 px-20 flex items-center justify-center text-sky-900 rounded-5 hover:bg-white/60",
+                onclick: move |_| {
+                  send_command(ctx, MediaCommands::StepForward);
+                },
+
+                span {
+                  "Frame +"
+                }
+              }
+
+              div {
+                class: "bg-white/50 h-64 px-20 flex items-center justify-center text-sky-900 rounded-5 hover:bg-white/60",
+                onclick: move |_| {
+                  send_command(ctx, MediaCommands::SetVolume(volume - 0.1));
+                },
+
+                span {
+                  "Volume -"
+                }
+              }
+
+              div {
+                class: "bg-white/50 h-64 px-20 flex items-center justify-center text-sky-900 rounded-5 hover:bg-white/60",
+                onclick: move |_| {
+                  send_command(ctx, MediaCommands::SetVolume(volume + 0.1));
+                },
+
+                span {
+                  "Volume +"
+                }
+              }
+
+              div {
+                class: "bg-white/50 h-64 px-20 flex items-center justify-center text-sky-900 rounded-5 hover:bg-white/60",
+                onclick: move |_| {
+                  send_command(ctx, MediaCommands::SetMuted(!muted));
+                },
+
+                span {
+                  "{mute_label}"
+                }
+              }
+
+              div {
+                class: "bg-white/50 h-64 px-20 flex items-center justify-center text-sky-900 rounded-5 hover:bg-white/60",
+                onclick: move |_| {
+                  send_command(ctx, MediaCommands::SetRate(next_rate));
+                },
+
+                span {
+                  "Speed {rate}x"
+                }
+              }
+
+              if loaded {
+                rsx! {
+                  div {
+                    class: "bg-white/50 h-64 px-20 flex items-center justify-center text-sky-900 rounded-5 hover:bg-white/60",
+                    onclick: move |_| {
+                      send_command(ctx, MediaCommands::Close);
+                    },
+
+                    span {
+                      "Close"
+                    }
+                  }
+                }
+              }
+
+              if has_video {
+                rsx! {
+                  div {
+                    class: "bg-white/50 h-64 px-20 flex items-center justify-center text-sky-900 rounded-5 hover:bg-white/60",
+                    onclick: move |_| {
+                      let mut app_context = ctx.write().unwrap();
+                      app_context.display_mode = next_display_mode;
+                      app_context.render_sender.send(MediaRenderCommand::SetDisplayMode(next_display_mode)).unwrap();
+                      display_mode.set(next_display_mode);
+                    },
+
+                    span {
+                      "Display: {display_mode_label}"
+                    }
+                  }
+                }
+              }
+
+              if has_audio {
+                rsx! {
+                  div {
+                    class: "bg-white/50 h-64 px-20 flex items-center justify-center text-sky-900 rounded-5 hover:bg-white/60",
+                    onclick: move |_| {
+                      // the default device comes first, when the current one went away we start over
+                      let devices: Vec<Option<String>> = std::iter::once(None)
+                        .chain(list_output_devices().into_iter().map(Some))
+                        .collect();
+                      let next = devices
+                        .iter()
+                        .position(|device| *device == audio_device)
+                        .map_or(0, |index| (index + 1) % devices.len());
+                      send_command(ctx, MediaCommands::SetAudioDevice(devices[next].clone()));
+                    },
+
+                    span {
+                      "Audio: {audio_device_label}"
+                    }
+                  }
+                }
+              }
+
+              if loaded {
+                rsx! {
+                  div {
+                    class: "bg-white/50 h-64 px-20 flex items-center justify-center text-sky-900 rounded-5 hover:bg-white/60",
+                    onclick: move |_| {
+                      send_command(ctx, MediaCommands::SetLoop(next_file_loop));
+                    },
+
+                    span {
+                      "Loop file: {file_loop_label}"
+                    }
+                  }
+
+                  div {
+                    class: "bg-white/50 h-64 px-20 flex items-center justify-center text-sky-900 rounded-5 hover:bg-white/60",
+                    onclick: move |_| match (loop_mode, *loop_start.get()) {
+                      (LoopMode::Range { .. }, _) => {
+                        send_command(ctx, MediaCommands::SetLoop(LoopMode::Off));
+                      }
+                      // B before A starts over
+                      (_, Some(start)) => {
+                        loop_start.set(None);
+                        if current_position > start {
+                          send_command(ctx, MediaCommands::SetLoop(LoopMode::Range { start, end: current_position }));
+                        }
+                      }
+                      (_, None) => loop_start.set(Some(current_position)),
+                    },
+
+                    span {
+                      "{range_loop_label}"
+                    }
+                  }
+                }
+              }
+
+              if hdr {
+                rsx! {
+                  div {
+                    class: "bg-white/50 h-64 px-20 flex items-center justify-center text-sky-900 rounded-5 hover:bg-white/60",
+                    onclick: move |_| {
+                      let mut app_context = ctx.write().unwrap();
+                      app_context.tone_mapping = next_tone_mapping;
+                      app_context.render_sender.send(MediaRenderCommand::SetToneMapping(next_tone_mapping)).unwrap();
+                      tone_mapping.set(next_tone_mapping);
+                    },
+
+                    span {
+                      "HDR: {tone_mapping_label}"
+                    }
+                  }
+                }
+              }
+            }
+
+
+            if playlist_len > 1 {
+              rsx! {
+                div {
+                  class: "justify-center items-center pt-5 gap-x-5 text-sky-900",
+
+                  div {
+                    class: "bg-white/40 h-32 px-10 flex items-center rounded-5 hover:bg-white/60",
+                    onclick: move |_| {
+                      send_command(ctx, MediaCommands::Previous);
+                    },
+
+                    span { "Previous" }
+                  }
+
+                  span {
+                    "{playlist_number} / {playlist_len}: {playlist_item}"
+                  }
+
+                  div {
+                    class: "bg-white/40 h-32 px-10 flex items-center rounded-5 hover:bg-white/60",
+                    onclick: move |_| {
+                      send_command(ctx, MediaCommands::Next);
+                    },
+
+                    span { "Next" }
+                  }
+
+                  div {
+                    class: "bg-white/40 h-32 px-10 flex items-center rounded-5 hover:bg-white/60",
+                    onclick: move |_| {
+                      send_command(ctx, MediaCommands::SetShuffle(!shuffle));
+                    },
+
+                    span { "Shuffle: {shuffle_label}" }
+                  }
+
+                  div {
+                    class: "bg-white/40 h-32 px-10 flex items-center rounded-5 hover:bg-white/60",
+                    onclick: move |_| {
+                      send_command(ctx, MediaCommands::SetRepeat(next_repeat));
+                    },
+
+                    span { "Repeat: {repeat_label}" }
+                  }
+                }
+              }
             }
 
+            if audio_tracks.len() > 1
+              || video_tracks.len() > 1
+              || !subtitle_tracks.is_empty()
+              || subtitle_file.is_some()
+            {
+              rsx! {
+                div {
+                  class: "justify-center pt-5 gap-x-5 text-sky-900",
+
+                  audio_tracks.iter().filter(|_| audio_tracks.len() > 1).map(|stream| {
+                    let index = stream.index;
+                    let label = track_label(stream);
+                    let class = if player.audio_track == Some(index) { "bg-white/70" } else { "bg-white/40" };
+                    rsx! {
+                      div {
+                        key: "audio-{index}",
+                        class: "{class} h-32 px-10 flex items-center rounded-5 hover:bg-white/60",
+                        onclick: move |_| {
+                          send_command(ctx, MediaCommands::SelectAudioTrack(index));
+                        },
+
+                        span { "Audio: {label}" }
+                      }
+                    }
+                  })
+
+                  video_tracks.iter().filter(|_| video_tracks.len() > 1).map(|stream| {
+                    let index = stream.index;
+                    let label = track_label(stream);
+                    let class = if player.video_track == Some(index) { "bg-white/70" } else { "bg-white/40" };
+                    rsx! {
+                      div {
+                        key: "video-{index}",
+                        class: "{class} h-32 px-10 flex items-center rounded-5 hover:bg-white/60",
+                        onclick: move |_| {
+                          send_command(ctx, MediaCommands::SelectVideoTrack(index));
+                        },
+
+                        span { "Video: {label}" }
+                      }
+                    }
+                  })
+
+                  if !subtitle_tracks.is_empty() || subtitle_file.is_some() {
+                    rsx! {
+                      div {
+                        class: "{subtitles_off_class} h-32 px-10 flex items-center rounded-5 hover:bg-white/60",
+                        onclick: move |_| {
+                          send_command(ctx, MediaCommands::SelectSubtitleTrack(None));
+                        },
+
+                        span { "Subtitles: Off" }
+                      }
+                    }
+                  }
+
+                  subtitle_tracks.iter().map(|stream| {
+                    let index = stream.index;
+                    let label = track_label(stream);
+                    let class = if player.subtitle_track == Some(index) { "bg-white/70" } else { "bg-white/40" };
+                    rsx! {
+                      div {
+                        key: "subtitle-{index}",
+                        class: "{class} h-32 px-10 flex items-center rounded-5 hover:bg-white/60",
+                        onclick: move |_| {
+                          send_command(ctx, MediaCommands::SelectSubtitleTrack(Some(index)));
+                        },
+
+                        span { "Subtitles: {label}" }
+                      }
+                    }
+                  })
+
+                  if let Some(subtitle_file) = &subtitle_file {
+                    rsx! {
+                      div {
+                        class: "bg-white/70 h-32 px-10 flex items-center rounded-5",
+
+                        span { "Subtitles: {subtitle_file}" }
+                      }
+                    }
+                  }
+                }
+              }
+            }
 
             // (0..5).map(|_| {
             //   rsx! {
@@ -0,0 +1,184 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use dioxus::prelude::*;
+use stainless_ffmpeg::prelude::AVMediaType;
+
+use crate::{
+    av_sync::SyncStats,
+    decoder::{LoopMode, MediaDecoderError, MediaEvents, StreamInfo},
+    playlist::RepeatMode,
+    AppContextRef,
+};
+
+/// Snapshot of the player built from the events sent by the decoder
+#[derive(Debug, Clone, Default)]
+pub struct PlayerState {
+    /// Position in nanoseconds
+    pub position: i64,
+    /// Duration in nanoseconds
+    pub duration: Option<i64>,
+    pub streams: Vec<StreamInfo>,
+    /// Stream index of the audio track that is playing
+    pub audio_track: Option<usize>,
+    /// Stream index of the video track that is playing
+    pub video_track: Option<usize>,
+    /// Stream index of the subtitles that are shown
+    pub subtitle_track: Option<usize>,
+    /// Subtitle file that is shown instead of an embedded track
+    pub subtitle_file: Option<PathBuf>,
+    pub playing: bool,
+    pub buffering: bool,
+    pub ended: bool,
+    pub volume: f32,
+    pub muted: bool,
+    pub rate: f32,
+    /// Set while stepping through frames
+    pub frame_number: Option<i64>,
+    /// Paths and URLs in the order they were added
+    pub playlist: Vec<String>,
+    /// Index in `playlist` of the item that is playing
+    pub playlist_index: usize,
+    pub shuffle: bool,
+    pub repeat: RepeatMode,
+    /// Output device the audio plays through, `None` is the default device
+    pub audio_device: Option<String>,
+    pub loop_mode: LoopMode,
+    /// Frames dropped and repeated since the file was opened, and how late video is
+    pub sync: SyncStats,
+    pub error: Option<MediaDecoderError>,
+}
+
+impl PlayerState {
+    fn apply(&mut self, event: MediaEvents) {
+        match event {
+            MediaEvents::Loaded { duration, streams } => {
+                self.duration = duration;
+                self.streams = streams;
+                self.audio_track = None;
+                self.video_track = None;
+                self.ended = false;
+                self.error = None;
+            }
+            MediaEvents::Position(position) => {
+                self.position = position;
+                self.ended = false;
+                self.frame_number = None;
+            }
+            MediaEvents::Paused => self.playing = false,
+            MediaEvents::Playing => {
+                self.playing = true;
+                self.buffering = false;
+            }
+            MediaEvents::Buffering => self.buffering = true,
+            MediaEvents::EndOfStream | MediaEvents::Finished => {
+                self.ended = true;
+                self.buffering = false;
+            }
+            MediaEvents::Volume { volume, muted } => {
+                self.volume = volume;
+                self.muted = muted;
+            }
+            MediaEvents::Rate(rate) => self.rate = rate,
+            MediaEvents::TrackSelected { media_type, index } => match media_type {
+                AVMediaType::AVMEDIA_TYPE_AUDIO => self.audio_track = Some(index),
+                AVMediaType::AVMEDIA_TYPE_VIDEO => self.video_track = Some(index),
+                _ => (),
+            },
+            MediaEvents::SubtitleTrackSelected(index) => {
+                self.subtitle_track = index;
+                self.subtitle_file = None;
+            }
+            MediaEvents::SubtitleFileLoaded(path) => self.subtitle_file = Some(path),
+            MediaEvents::Playlist {
+                items,
+                current,
+                shuffle,
+                repeat,
+            } => {
+                self.playlist = items;
+                self.playlist_index = current;
+                self.shuffle = shuffle;
+                self.repeat = repeat;
+            }
+            MediaEvents::AudioDevice(device) => self.audio_device = device,
+            MediaEvents::Loop(mode) => self.loop_mode = mode,
+            MediaEvents::Sync(sync) => self.sync = sync,
+            // the volume and device are kept for the next file
+            MediaEvents::Closed => {
+                *self = Self {
+                    volume: self.volume,
+                    muted: self.muted,
+                    audio_device: self.audio_device.take(),
+                    ..Self::default()
+                }
+            }
+            MediaEvents::VideoSize { .. } | MediaEvents::AudioOnly => (),
+            MediaEvents::Subtitles(_) => (),
+            MediaEvents::Stepped { pts, frame_number } => {
+                self.position = pts;
+                self.frame_number = frame_number;
+            }
+            MediaEvents::Error(error) => self.error = Some(error),
+        }
+    }
+
+    /// Streams of `media_type` that can be selected, cover art excluded
+    pub fn tracks(&self, media_type: AVMediaType) -> Vec<&StreamInfo> {
+        self.streams
+            .iter()
+            .filter(|stream| stream.media_type == media_type && !stream.attached_picture)
+            .collect()
+    }
+
+    /// Playback progress between 0 and 100
+    pub fn progress(&self) -> f32 {
+        match self.duration {
+            Some(duration) if duration > 0 => {
+                (self.position as f64 / duration as f64 * 100.0).clamp(0.0, 100.0) as f32
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+/// Listens to the decoder events and re-renders the component whenever the player state changes
+pub fn use_player_state(cx: &ScopeState) -> PlayerState {
+    let state = cx.use_hook(|| {
+        let state = Arc::new(RwLock::new(PlayerState::default()));
+        let event_receiver = cx
+            .consume_context::<AppContextRef>()
+            .unwrap()
+            .read()
+            .unwrap()
+            .event_receiver
+            .clone();
+        let update = cx.schedule_update();
+
+        std::thread::spawn({
+            let state = state.clone();
+            move || {
+                while let Ok(event) = event_receiver.recv() {
+                    state.write().unwrap().apply(event);
+                    update();
+                }
+            }
+        });
+
+        state
+    });
+
+    state.read().unwrap().clone()
+}
+
+/// Formats nanoseconds as `mm:ss` or `hh:mm:ss`
+pub fn format_time(nanoseconds: i64) -> String {
+    let seconds = nanoseconds.max(0) / 1_000_000_000;
+    let (hours, minutes, seconds) = (seconds / 3600, (seconds / 60) % 60, seconds % 60);
+
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes:02}:{seconds:02}")
+    }
+}